@@ -6,6 +6,12 @@ use std::{
 };
 
 fn main() -> Result<(), Error> {
+    // Host builds (`cargo th`, `rhost`, `rsim`) don't link against memory.x.
+    if !env::var("TARGET")?.starts_with("thumb") {
+        println!("cargo:rerun-if-changed=build.rs");
+        return Ok(());
+    }
+
     let target = Target::read();
 
     copy_memory_config(target)?;
@@ -79,6 +85,8 @@ Please refer to the documentation for more details."
     }
 }
 
+// Only read through Debug, when main returns it.
+#[allow(dead_code)]
 #[derive(Debug)]
 enum Error {
     Env(env::VarError),
@@ -0,0 +1,168 @@
+#![deny(warnings)]
+// CHAdeMO vehicle simulator for Linux SocketCAN.
+// Plays the vehicle side against the charger (real hardware, or can-dc-fc-host) on a CAN
+// interface and reports what the charger did. To try it without hardware:
+//   sudo ip link add dev vcan0 type vcan && sudo ip link set up vcan0
+//   cargo rsim --interface vcan0 --voltage 380 --steps 0:10,20000:25
+// --discharge offers V2H in 0x200, for a charger session started with 'd'.
+use can_dc_fc::host::{SocketCan, SystemClock};
+use can_dc_fc::interfaces::{CanBus, Clock};
+use can_dc_fc::vehicle::*;
+use std::process::exit;
+use std::thread::sleep;
+use std::time::Duration;
+
+const HUNDRED_MS: u32 = 100;
+
+fn usage() -> ! {
+    eprintln!(
+        "Usage: ev-sim [--interface vcan0] [--voltage V] [--max-voltage V] [--current A]
+              [--steps ms:A,ms:A,...] [--soc %] [--capacity 0.1kWh] [--charge-time s]
+              [--max-time min] [--contactor-delay ms] [--protocol n] [--response-timeout ms]
+              [--discharge A] [--min-soc %] [--battery-voltage V]"
+    );
+    exit(2);
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| usage())
+}
+
+// "0:10,20000:30" -> current request steps, at most four.
+fn parse_steps(value: &str, config: &mut VehicleConfig) {
+    let mut count = 0;
+    for step in value.split(',') {
+        if count == config.current_steps.len() {
+            usage();
+        }
+        let mut parts = step.splitn(2, ':');
+        let start = parse(parts.next().unwrap_or_else(|| usage()));
+        let amps = parse(parts.next().unwrap_or_else(|| usage()));
+        config.current_steps[count] = (start, amps);
+        count += 1;
+    }
+    if count == 0 {
+        usage();
+    }
+    // Unused slots repeat the last step.
+    for i in count..config.current_steps.len() {
+        config.current_steps[i] = config.current_steps[count - 1];
+    }
+}
+
+fn main() {
+    let mut interface = String::from("vcan0");
+    let mut config = VehicleConfig::new();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() % 2 != 0 {
+        usage();
+    }
+    for pair in args.chunks(2) {
+        let value = pair[1].as_str();
+        match pair[0].as_str() {
+            "--interface" => interface = value.to_string(),
+            "--voltage" => config.target_voltage = parse(value),
+            "--max-voltage" => config.max_battery_voltage = parse(value),
+            "--current" => config.current_steps = [(0, parse(value)); 4],
+            "--steps" => parse_steps(value, &mut config),
+            "--soc" => config.state_of_charge = parse(value),
+            "--capacity" => config.battery_capacity = parse(value),
+            "--charge-time" => config.charge_time = parse::<u32>(value) * 1000,
+            "--max-time" => config.max_charge_time = parse(value),
+            "--contactor-delay" => config.contactor_delay = parse(value),
+            "--protocol" => config.protocol_number = parse(value),
+            "--response-timeout" => config.evse_response_timeout = parse(value),
+            "--discharge" => config.max_discharge_current = parse(value),
+            "--min-soc" => config.min_discharge_soc = parse(value),
+            "--battery-voltage" => config.battery_voltage = parse(value),
+            _ => usage(),
+        }
+    }
+    if config.max_battery_voltage < config.target_voltage {
+        config.max_battery_voltage = config.target_voltage;
+    }
+
+    let mut fc_can = SocketCan::open(&interface).unwrap_or_else(|e| {
+        eprintln!("Unable to open {}: {}", interface, e);
+        exit(1);
+    });
+    let clock = SystemClock::new();
+    let mut vehicle = VehicleState::new(config);
+    let mut previous_100_ms_ts = 0;
+    let mut previous_state = VehicleStateEnum::Idle;
+    let mut previous_status = None;
+
+    println!(
+        "Vehicle on {}: target {} V",
+        interface, config.target_voltage
+    );
+    // No d2 line on SocketCAN, so it's taken as set. Only the vehicle firmware and the tests check
+    // that the charger sets it before the contactors close.
+    vehicle.d2 = true;
+    start(&mut vehicle, clock.elapsed_ms());
+
+    loop {
+        let elapsed = clock.elapsed_ms();
+
+        while let Some(frame) = fc_can.receive_frame() {
+            init(elapsed, &mut vehicle, frame.id, frame.data());
+        }
+
+        if elapsed.wrapping_sub(previous_100_ms_ts) >= HUNDRED_MS {
+            previous_100_ms_ts = elapsed;
+            hundred_ms_loop(elapsed, &mut vehicle, &mut fc_can);
+        }
+
+        while let Some(entry) = vehicle.activity_list.pop_front() {
+            println!("{}", entry);
+        }
+
+        // Report what the charger is doing whenever its status changes.
+        if vehicle.evse_status != previous_status {
+            previous_status = vehicle.evse_status;
+            if let Some(status) = vehicle.evse_status {
+                println!(
+                    "{} - EVSE: {} V {} A, flags {:#04x} ({:?})",
+                    elapsed,
+                    status.present_voltage,
+                    status.present_current,
+                    status.status.to_byte(),
+                    status.status
+                );
+            }
+        }
+        if vehicle.state != previous_state {
+            previous_state = vehicle.state;
+            println!("{} - Vehicle state: {}", elapsed, vehicle.state);
+        }
+
+        if vehicle.state == VehicleStateEnum::Stopped || vehicle.state == VehicleStateEnum::Fault {
+            break;
+        }
+        sleep(Duration::from_millis(1));
+    }
+
+    println!("Session summary:");
+    if let Some(params) = vehicle.evse_params {
+        println!(
+            "  EVSE advertised {} V {} A, threshold {} V, weld detection {}",
+            params.available_voltage,
+            params.available_current,
+            params.threshold_voltage,
+            params.welding_detection
+        );
+    }
+    println!("  Max voltage seen: {} V", vehicle.max_voltage_seen);
+    println!("  Max current seen: {} A", vehicle.max_current_seen);
+    if let Some(discharge) = vehicle.evse_discharge {
+        println!(
+            "  EVSE V2H: {} A available, last {} A",
+            discharge.available_input_current, discharge.present_discharge_current
+        );
+    }
+    println!("  Final state: {}", vehicle.state);
+    if vehicle.state == VehicleStateEnum::Fault {
+        exit(1);
+    }
+}
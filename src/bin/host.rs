@@ -0,0 +1,98 @@
+#![deny(warnings)]
+// The charger firmware logic as a Linux process.
+// SocketCAN stands in for FCCAN, stdin/stdout for the USART console, d1 / d2 are logged to
+// stderr, the output stage, insulation monitor and connector lock are simulated, j and proximity
+// are always on and the system clock replaces TIM2. Pair it with ev-sim on vcan0:
+//   cargo rhost --interface vcan0
+// --protocol gbt runs GB/T instead of CHAdeMO, the vcan bit rate doesn't matter.
+use can_dc_fc::host::{LoggedRelay, SocketCan, StdoutSink, SystemClock};
+use can_dc_fc::interfaces::Clock;
+use can_dc_fc::main_loop::{MainLoop, Peripherals};
+use can_dc_fc::mock::{MockLock, MockSequenceInputs, SimulatedInsulation, SimulatedSupply};
+use can_dc_fc::types::ProtocolEnum;
+use std::io::Read;
+use std::process::exit;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread::{sleep, spawn};
+use std::time::Duration;
+
+fn usage() -> ! {
+    eprintln!("Usage: can-dc-fc-host [--interface vcan0] [--protocol chademo|gbt]");
+    exit(2);
+}
+
+// The terminal is line buffered, so commands arrive after Enter. Line endings are dropped,
+// process_serial would report them as invalid selections.
+fn stdin_reader() -> Receiver<u8> {
+    let (sender, receiver) = channel();
+    spawn(move || {
+        for byte in std::io::stdin().lock().bytes() {
+            match byte {
+                Ok(b'\n') | Ok(b'\r') => {}
+                Ok(byte) => {
+                    if sender.send(byte).is_err() {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+    });
+    receiver
+}
+
+fn main() {
+    let mut interface = String::from("vcan0");
+    let mut protocol = ProtocolEnum::Chademo;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    for pair in args.chunks(2) {
+        match pair {
+            [flag, value] if flag == "--interface" => interface = value.clone(),
+            [flag, value] if flag == "--protocol" => {
+                protocol = match value.as_str() {
+                    "chademo" => ProtocolEnum::Chademo,
+                    "gbt" => ProtocolEnum::Gbt,
+                    _ => usage(),
+                }
+            }
+            _ => usage(),
+        }
+    }
+
+    let mut fc_can = SocketCan::open(&interface).unwrap_or_else(|e| {
+        eprintln!("Unable to open {}: {}", interface, e);
+        exit(1);
+    });
+    let clock = SystemClock::new();
+    let serial_input = stdin_reader();
+
+    let mut main_loop = MainLoop::new();
+    main_loop.cd_state.charger_config.protocol = protocol;
+    let mut io = Peripherals {
+        connector_lock: MockLock::new(),
+        // Nothing to measure on the host, a healthy 10 MOhm.
+        insulation_monitor: SimulatedInsulation::new(10_000_000),
+        power_stage: SimulatedSupply::default(),
+        d1: LoggedRelay::new("d1"),
+        d2: LoggedRelay::new("d2"),
+        sequence_inputs: MockSequenceInputs::new(),
+    };
+    let mut tx = StdoutSink;
+
+    loop {
+        let elapsed = clock.elapsed_ms();
+        // The simulated battery is connected while the vehicle says its contactors are closed.
+        io.power_stage.battery_connected = !main_loop.car_state.contactor_open;
+        io.power_stage.update(elapsed);
+        // No fault line on the host, it always reads OK (high).
+        main_loop.poll(
+            &mut io,
+            &mut fc_can,
+            &mut tx,
+            elapsed,
+            true,
+            serial_input.try_recv().ok(),
+        );
+        sleep(Duration::from_millis(1));
+    }
+}
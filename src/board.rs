@@ -0,0 +1,258 @@
+#![deny(warnings)]
+#[cfg(feature = "nucleof767zi")]
+extern crate stm32f7xx_hal as hal;
+
+#[cfg(feature = "nucleof446re")]
+extern crate stm32f4xx_hal as hal;
+
+use crate::can_rx_queue::RxQueueWriter;
+use crate::interfaces::{
+    CanBus, CanMessage, ConnectorLock, InsulationMonitor, PowerStage, Relay, SequenceInputs,
+};
+use hal::prelude::*;
+
+// Generic type abstractions
+// Why? Remove reference to hal, so that it does not need to be included in many spots with
+// conditional code around it.
+pub type BaseID = hal::can::BaseID;
+pub type CanFrame = hal::can::CanFrame;
+pub type DataFrame = hal::can::DataFrame;
+pub type ExtendedID = hal::can::ExtendedID;
+pub type ID = hal::can::ID;
+pub type Rtc = hal::rtc::Rtc;
+pub type RxFifo = hal::can::RxFifo;
+
+// HW specific type abstractions
+#[cfg(feature = "nucleof767zi")]
+mod abstractions {
+    extern crate stm32f7xx_hal as hal;
+    use hal::can::Can;
+    use hal::gpio::gpiod::{PD0, PD1, PD2};
+    use hal::gpio::gpioe::{PE0, PE1, PE2};
+    use hal::gpio::gpiog::{PG0, PG1, PG2, PG3};
+    use hal::gpio::AF9;
+    use hal::gpio::{Alternate, Floating, Input, Output, PullDown, PullUp, PushPull};
+    use hal::pac::CAN1;
+    pub type FCCAN = Can<CAN1, (PD1<Alternate<AF9>>, PD0<Alternate<AF9>>)>;
+    pub type ConsoleUsart = hal::pac::USART3;
+    pub type SerialConsoleInput = hal::serial::Rx<hal::pac::USART3>;
+    pub type SerialConsoleOutput = hal::serial::Tx<hal::pac::USART3>;
+    pub type FaultLinePin = PG2<Input<Floating>>;
+    pub type RelayOnePin = PG3<Output<PushPull>>;
+    pub type RelayTwoPin = PD2<Output<PushPull>>;
+    pub type LockDrivePin = PG0<Output<PushPull>>;
+    pub type LockFeedbackPin = PG1<Input<PullDown>>;
+    pub type ChargePermissionPin = PE0<Input<PullUp>>;
+    pub type ProximityPin = PE1<Input<PullUp>>;
+    pub type D2InputPin = PE2<Input<PullUp>>;
+}
+
+#[cfg(feature = "nucleof446re")]
+mod abstractions {
+    extern crate stm32f4xx_hal as hal;
+    use hal::can::Can;
+    use hal::gpio::gpioa::{PA0, PA1, PA4};
+    use hal::gpio::gpiob::{PB3, PB5, PB6, PB8, PB9};
+    use hal::gpio::gpioc::{PC0, PC1};
+    use hal::gpio::AF9;
+    use hal::gpio::{Alternate, Floating, Input, Output, PullDown, PullUp, PushPull};
+    use hal::pac::CAN1;
+    pub type FCCAN = Can<CAN1, (PB9<Alternate<AF9>>, PB8<Alternate<AF9>>)>;
+    pub type ConsoleUsart = hal::pac::USART2;
+    pub type SerialConsoleInput = hal::serial::Rx<hal::pac::USART2>;
+    pub type SerialConsoleOutput = hal::serial::Tx<hal::pac::USART2>;
+    pub type FaultLinePin = PB3<Input<Floating>>;
+    pub type RelayOnePin = PB5<Output<PushPull>>;
+    pub type RelayTwoPin = PB6<Output<PushPull>>; // FIXME: Not actual pin.
+    pub type LockDrivePin = PC0<Output<PushPull>>;
+    pub type LockFeedbackPin = PC1<Input<PullDown>>;
+    pub type ChargePermissionPin = PA0<Input<PullUp>>;
+    pub type ProximityPin = PA1<Input<PullUp>>;
+    pub type D2InputPin = PA4<Input<PullUp>>;
+}
+
+pub type FCCAN = abstractions::FCCAN;
+pub type ConsoleUsart = abstractions::ConsoleUsart;
+pub type SerialConsoleInput = abstractions::SerialConsoleInput;
+pub type SerialConsoleOutput = abstractions::SerialConsoleOutput;
+pub type FaultLinePin = abstractions::FaultLinePin;
+pub type RelayOnePin = abstractions::RelayOnePin;
+pub type RelayTwoPin = abstractions::RelayTwoPin;
+pub type LockDrivePin = abstractions::LockDrivePin;
+pub type LockFeedbackPin = abstractions::LockFeedbackPin;
+pub type ChargePermissionPin = abstractions::ChargePermissionPin;
+pub type ProximityPin = abstractions::ProximityPin;
+pub type D2InputPin = abstractions::D2InputPin;
+
+impl CanBus for FCCAN {
+    fn send_frame(&mut self, message: &CanMessage) -> bool {
+        let id = if message.extended {
+            ID::ExtendedID(ExtendedID::new(message.id))
+        } else {
+            ID::BaseID(BaseID::new(message.id as u16))
+        };
+        let mut frame = DataFrame::new(id);
+        frame.set_data_length(message.length);
+        frame.data_as_mut().copy_from_slice(message.data());
+        self.transmit(&frame.into()).is_ok()
+    }
+
+    fn receive_frame(&mut self) -> Option<CanMessage> {
+        for fifo in &[RxFifo::Fifo0, RxFifo::Fifo1] {
+            if let Ok(CanFrame::DataFrame(frame)) = self.receive(fifo) {
+                return Some(can_message(&frame));
+            }
+        }
+        None
+    }
+}
+
+fn can_message(frame: &DataFrame) -> CanMessage {
+    match frame.id() {
+        ID::ExtendedID(_) => CanMessage::new_extended(frame.id().into(), frame.data()),
+        ID::BaseID(_) => CanMessage::new(frame.id().into(), frame.data()),
+    }
+}
+
+// Receive interrupt for one FIFO: everything in it goes to the queue, stamped with the time of
+// the interrupt, and an overrun is counted.
+pub fn receive_fifo(fc_can: &mut FCCAN, fifo: &RxFifo, timestamp: u32, writer: &mut RxQueueWriter) {
+    while let Ok(frame) = fc_can.receive(fifo) {
+        if let CanFrame::DataFrame(frame) = frame {
+            writer.push(can_message(&frame), timestamp);
+        }
+    }
+    if clear_overrun(fifo) {
+        writer.overrun();
+    }
+}
+
+// The HAL doesn't expose the FIFO overrun flags. They clear by writing 1.
+fn clear_overrun(fifo: &RxFifo) -> bool {
+    let can1 = unsafe { &*hal::pac::CAN1::ptr() };
+    match fifo {
+        RxFifo::Fifo0 => {
+            let overrun = can1.rf0r.read().fovr0().bit_is_set();
+            if overrun {
+                can1.rf0r.write(|w| w.fovr0().set_bit());
+            }
+            overrun
+        }
+        RxFifo::Fifo1 => {
+            let overrun = can1.rf1r.read().fovr1().bit_is_set();
+            if overrun {
+                can1.rf1r.write(|w| w.fovr1().set_bit());
+            }
+            overrun
+        }
+    }
+}
+
+// The console's transmit register empty interrupt, on while there is output queued. The HAL's Tx
+// can't switch it.
+pub fn listen_console_tx(listen: bool) {
+    let usart = unsafe { &*ConsoleUsart::ptr() };
+    usart.cr1.modify(|_, w| w.txeie().bit(listen));
+}
+
+// Relay drivers are active low. Relay one switches d1, relay two d2.
+impl Relay for RelayOnePin {
+    fn set_closed(&mut self, closed: bool) {
+        if closed {
+            self.set_low().ok();
+        } else {
+            self.set_high().ok();
+        }
+    }
+}
+
+impl Relay for RelayTwoPin {
+    fn set_closed(&mut self, closed: bool) {
+        if closed {
+            self.set_low().ok();
+        } else {
+            self.set_high().ok();
+        }
+    }
+}
+
+// Lock solenoid driver (active high) and the lock's position switch, which pulls the feedback
+// pin high once engaged. Without a lock fitted, a jumper from the drive pin to the feedback pin
+// stands in for it.
+pub struct LockActuator {
+    pub drive: LockDrivePin,
+    pub feedback: LockFeedbackPin,
+}
+
+impl ConnectorLock for LockActuator {
+    fn set_locked(&mut self, locked: bool) {
+        if locked {
+            self.drive.set_high().ok();
+        } else {
+            self.drive.set_low().ok();
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.feedback.is_high().unwrap_or(false)
+    }
+}
+
+// The vehicle's j line and the connector proximity switch both pull their pin to ground.
+pub struct SequenceLineInputs {
+    pub charge_permission: ChargePermissionPin,
+    pub proximity: ProximityPin,
+}
+
+impl SequenceInputs for SequenceLineInputs {
+    fn charge_permission(&self) -> bool {
+        self.charge_permission.is_low().unwrap_or(false)
+    }
+
+    fn connector_detected(&self) -> bool {
+        self.proximity.is_low().unwrap_or(false)
+    }
+}
+
+// The charger's d2, for the vehicle firmware. The charger's relay two pulls the pin to ground.
+pub struct D2Input {
+    pub pin: D2InputPin,
+}
+
+impl D2Input {
+    pub fn is_on(&self) -> bool {
+        self.pin.is_low().unwrap_or(false)
+    }
+}
+
+// The Nucleo boards have no power stage. Nothing is commanded and the output reads 0 V / 0 A, so
+// 0x109 never reports an output that isn't there.
+pub struct NoPowerStage;
+
+impl PowerStage for NoPowerStage {
+    fn set_output(&mut self, _enabled: bool, _voltage: u16, _current: u16) {}
+    fn set_reverse_output(&mut self, _enabled: bool, _current: u8) {}
+    fn set_discharge(&mut self, _on: bool) {}
+
+    fn measured_voltage(&self) -> u16 {
+        0
+    }
+
+    fn measured_current(&self) -> u16 {
+        0
+    }
+
+    fn measured_reverse_current(&self) -> u8 {
+        0
+    }
+}
+
+// Nor an insulation monitor. Nothing measured reads 0 Ohm, which fails the insulation test, so
+// main.rs turns the test off in the charger config.
+pub struct NoInsulationMonitor;
+
+impl InsulationMonitor for NoInsulationMonitor {
+    fn resistance_ohms(&mut self) -> u32 {
+        0
+    }
+}
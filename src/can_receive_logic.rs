@@ -1,27 +1,37 @@
 #![deny(warnings)]
-use crate::process_cd::init as process_cd;
+use crate::add_to_activity_list;
+use crate::interfaces::CanBus;
+use crate::session::receive;
 use crate::types::*;
 
-pub fn init(
-    can_frame: &CanFrame,
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// Everything received since the last pass. The protocol engine for the charger's protocol
+// decodes each frame, the session acts on it. A frame that arrived after this pass read the
+// clock counts as arriving now.
+pub fn init<C: CanBus>(
+    fc_can: &mut C,
     elapsed: u32,
-    mut cd_state: &mut CDState,
-    mut car_state: &mut CarState,
+    cd_state: &mut CDState,
+    car_state: &mut CarState,
 ) {
-    if let CanFrame::DataFrame(ref frame) = can_frame {
-        let id: u32 = frame.id().into();
-        let data = frame.data();
+    while let Some(frame) = fc_can.receive_timed(elapsed) {
+        let timestamp = frame.timestamp.min(elapsed);
+        receive(timestamp, &frame.message, cd_state, car_state);
+    }
 
-        // Can only say you've gotten a frame, not
-        // that you _haven't_ gotten a frame.
-        // Timeout needs to be set somewhere else.
-        match id {
-            0x100 | 0x101 | 0x102 => {
-                cd_state.previous_can_ts = elapsed;
-                cd_state.comm_timeout = false;
-                process_cd(elapsed, &mut cd_state, &mut car_state, id, data);
-            }
-            _ => {}
-        }
+    let counters = fc_can.rx_counters();
+    if counters != cd_state.can_rx {
+        add_to_activity_list!(
+            cd_state,
+            "{} - CAN rx lost, ovr {} drop {}",
+            elapsed,
+            counters.overruns,
+            counters.dropped
+        );
+        cd_state.can_rx = counters;
     }
 }
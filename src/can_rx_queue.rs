@@ -0,0 +1,114 @@
+#![deny(warnings)]
+// Interrupt driven CAN reception. The receive interrupts move frames out of the controller's
+// FIFOs into a queue, stamped with the time they arrived, and the main loop takes them from
+// there. One producer (the interrupts) and one consumer (the main loop), so no lock is needed.
+use crate::interfaces::{CanBus, CanMessage, CanRxCounters, TimedFrame};
+use core::sync::atomic::{AtomicU32, Ordering};
+use heapless::consts::U32;
+use heapless::spsc::{Consumer, Producer, Queue};
+
+// A CHAdeMO or GB/T vehicle sends a handful of frames per 100 ms, this covers a slow main loop
+// pass with room to spare.
+pub type RxQueueLength = U32;
+pub type RxQueue = Queue<TimedFrame, RxQueueLength>;
+
+// Counted on the interrupt side, read from the main loop.
+pub struct RxCounters {
+    overruns: AtomicU32,
+    dropped: AtomicU32,
+}
+
+impl Default for RxCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RxCounters {
+    pub const fn new() -> Self {
+        Self {
+            overruns: AtomicU32::new(0),
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    pub fn get(&self) -> CanRxCounters {
+        CanRxCounters {
+            overruns: self.overruns.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// Interrupt side.
+pub struct RxQueueWriter {
+    producer: Producer<'static, TimedFrame, RxQueueLength>,
+    counters: &'static RxCounters,
+}
+
+impl RxQueueWriter {
+    pub fn push(&mut self, message: CanMessage, timestamp: u32) {
+        if self
+            .producer
+            .enqueue(TimedFrame { message, timestamp })
+            .is_err()
+        {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn overrun(&self) {
+        self.counters.overruns.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// Main loop side.
+pub struct RxQueueReader {
+    consumer: Consumer<'static, TimedFrame, RxQueueLength>,
+    counters: &'static RxCounters,
+}
+
+impl RxQueueReader {
+    // The bus the charger logic sees: frames go out through transmit, whose own receive isn't
+    // used, and come in from the queue.
+    pub fn bus<T: CanBus>(&mut self, transmit: T) -> QueuedCan<'_, T> {
+        QueuedCan {
+            transmit,
+            reader: self,
+        }
+    }
+}
+
+pub struct QueuedCan<'a, T> {
+    pub transmit: T,
+    reader: &'a mut RxQueueReader,
+}
+
+impl<'a, T: CanBus> CanBus for QueuedCan<'a, T> {
+    fn send_frame(&mut self, message: &CanMessage) -> bool {
+        self.transmit.send_frame(message)
+    }
+
+    fn receive_frame(&mut self) -> Option<CanMessage> {
+        self.reader.consumer.dequeue().map(|frame| frame.message)
+    }
+
+    fn receive_timed(&mut self, _now: u32) -> Option<TimedFrame> {
+        self.reader.consumer.dequeue()
+    }
+
+    fn rx_counters(&self) -> CanRxCounters {
+        self.reader.counters.get()
+    }
+}
+
+pub fn split(
+    queue: &'static mut RxQueue,
+    counters: &'static RxCounters,
+) -> (RxQueueWriter, RxQueueReader) {
+    let (producer, consumer) = queue.split();
+    (
+        RxQueueWriter { producer, counters },
+        RxQueueReader { consumer, counters },
+    )
+}
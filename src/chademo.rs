@@ -0,0 +1,519 @@
+#![deny(warnings)]
+// CHAdeMO frame definitions.
+// One struct per frame on the charge bus, each with a decode from the raw CAN payload and an
+// encode back to the 8 byte payload. Multi-byte values are little endian (byte1 + 256*byte2).
+
+// Vehicle -> EVSE
+pub const VEHICLE_PARAMS_ID: u32 = 0x100;
+pub const VEHICLE_TIME_ID: u32 = 0x101;
+pub const VEHICLE_STATUS_ID: u32 = 0x102;
+pub const VEHICLE_EXTENDED_ID: u32 = 0x110; // 1.0+
+pub const VEHICLE_DISCHARGE_ID: u32 = 0x200; // V2H
+
+// EVSE -> Vehicle
+pub const EVSE_PARAMS_ID: u32 = 0x108;
+pub const EVSE_STATUS_ID: u32 = 0x109;
+pub const EVSE_EXTENDED_ID: u32 = 0x118; // 1.0+
+pub const EVSE_DISCHARGE_ID: u32 = 0x208; // V2H
+pub const EVSE_DISCHARGE_STATUS_ID: u32 = 0x209; // V2H
+
+pub const FRAME_LENGTH: usize = 8;
+
+// Protocol numbers, 0x102 / 0x109 byte 0. The session runs at the lower of the two.
+pub const PROTOCOL_0_9: u8 = 1;
+pub const PROTOCOL_1_0: u8 = 2; // Dynamic control
+pub const PROTOCOL_2_0: u8 = 3; // High current control
+
+// Short frames are treated as if the missing bytes were zero.
+fn byte(data: &[u8], index: usize) -> u8 {
+    data.get(index).copied().unwrap_or(0)
+}
+
+fn word(data: &[u8], index: usize) -> u16 {
+    (byte(data, index + 1) as u16) << 8 | byte(data, index) as u16
+}
+
+fn put_word(payload: &mut [u8; FRAME_LENGTH], index: usize, value: u16) {
+    payload[index] = (value & 0x00FF) as u8;
+    payload[index + 1] = ((value & 0xFF00) >> 8) as u8;
+}
+
+fn bit(value: u8, mask: u8) -> bool {
+    value & mask == mask
+}
+
+// V2H currents are sent as 0xFF less the value.
+fn inverted(value: u8) -> u8 {
+    0xFF - value
+}
+
+// 0x100 - Vehicle battery parameters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VehicleParams100 {
+    pub minimum_charge_current: u8,   // Byte 0, A
+    pub minimum_battery_voltage: u16, // Byte 2-3, V
+    pub maximum_battery_voltage: u16, // Byte 4-5, V
+    pub charged_rate_reference: u8,   // Byte 6, % value that represents a full battery
+}
+
+impl VehicleParams100 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            minimum_charge_current: byte(data, 0),
+            minimum_battery_voltage: word(data, 2),
+            maximum_battery_voltage: word(data, 4),
+            charged_rate_reference: byte(data, 6),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.minimum_charge_current;
+        put_word(&mut payload, 2, self.minimum_battery_voltage);
+        put_word(&mut payload, 4, self.maximum_battery_voltage);
+        payload[6] = self.charged_rate_reference;
+        payload
+    }
+}
+
+// 0x101 - Vehicle charge time limits and battery capacity.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VehicleTime101 {
+    pub max_charge_time_10s: u8, // Byte 1, 10 s units. 0xFF -> use byte 2 instead.
+    pub max_charge_time_1min: u8, // Byte 2, 1 min units
+    pub estimated_charge_time: u8, // Byte 3, 1 min units
+    pub battery_capacity: u16,   // Byte 5-6, 0.1 kWh units
+}
+
+impl VehicleTime101 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            max_charge_time_10s: byte(data, 1),
+            max_charge_time_1min: byte(data, 2),
+            estimated_charge_time: byte(data, 3),
+            battery_capacity: word(data, 5),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[1] = self.max_charge_time_10s;
+        payload[2] = self.max_charge_time_1min;
+        payload[3] = self.estimated_charge_time;
+        put_word(&mut payload, 5, self.battery_capacity);
+        payload
+    }
+
+    pub fn max_charge_time_seconds(&self) -> u32 {
+        if self.max_charge_time_10s == 0xFF {
+            self.max_charge_time_1min as u32 * 60
+        } else {
+            self.max_charge_time_10s as u32 * 10
+        }
+    }
+}
+
+// 0x102 byte 4 - Vehicle fault flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VehicleFaults {
+    pub battery_over_voltage: bool,     // Bit 0
+    pub battery_under_voltage: bool,    // Bit 1
+    pub current_deviation: bool,        // Bit 2
+    pub battery_over_temperature: bool, // Bit 3
+    pub voltage_deviation: bool,        // Bit 4
+}
+
+impl VehicleFaults {
+    pub fn from_byte(value: u8) -> Self {
+        Self {
+            battery_over_voltage: bit(value, 0x01),
+            battery_under_voltage: bit(value, 0x02),
+            current_deviation: bit(value, 0x04),
+            battery_over_temperature: bit(value, 0x08),
+            voltage_deviation: bit(value, 0x10),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        let mut value = 0;
+        if self.battery_over_voltage {
+            value |= 0x01;
+        }
+        if self.battery_under_voltage {
+            value |= 0x02;
+        }
+        if self.current_deviation {
+            value |= 0x04;
+        }
+        if self.battery_over_temperature {
+            value |= 0x08;
+        }
+        if self.voltage_deviation {
+            value |= 0x10;
+        }
+        value
+    }
+
+    pub fn any(&self) -> bool {
+        self.to_byte() != 0
+    }
+}
+
+// 0x102 byte 5 - Vehicle status flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VehicleStatusFlags {
+    pub charging_enabled: bool,   // Bit 0
+    pub not_park: bool,           // Bit 1, shift lever not in park
+    pub malfunction: bool,        // Bit 2, vehicle charging system fault
+    pub contactor_open: bool,     // Bit 3, open when 1, closed when 0
+    pub stop_before_charge: bool, // Bit 4, normal stop request before charging
+}
+
+impl Default for VehicleStatusFlags {
+    fn default() -> Self {
+        Self {
+            charging_enabled: false,
+            not_park: false,
+            malfunction: false,
+            contactor_open: true,
+            stop_before_charge: false,
+        }
+    }
+}
+
+impl VehicleStatusFlags {
+    pub fn from_byte(value: u8) -> Self {
+        Self {
+            charging_enabled: bit(value, 0x01),
+            not_park: bit(value, 0x02),
+            malfunction: bit(value, 0x04),
+            contactor_open: bit(value, 0x08),
+            stop_before_charge: bit(value, 0x10),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        let mut value = 0;
+        if self.charging_enabled {
+            value |= 0x01;
+        }
+        if self.not_park {
+            value |= 0x02;
+        }
+        if self.malfunction {
+            value |= 0x04;
+        }
+        if self.contactor_open {
+            value |= 0x08;
+        }
+        if self.stop_before_charge {
+            value |= 0x10;
+        }
+        value
+    }
+}
+
+// 0x102 - Vehicle charge request and status.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VehicleStatus102 {
+    pub protocol_number: u8, // Byte 0
+    pub target_voltage: u16, // Byte 1-2, V
+    pub current_request: u8, // Byte 3, A
+    pub faults: VehicleFaults,
+    pub status: VehicleStatusFlags,
+    pub state_of_charge: u8, // Byte 6, %
+}
+
+impl VehicleStatus102 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            protocol_number: byte(data, 0),
+            target_voltage: word(data, 1),
+            current_request: byte(data, 3),
+            faults: VehicleFaults::from_byte(byte(data, 4)),
+            status: VehicleStatusFlags::from_byte(byte(data, 5)),
+            state_of_charge: byte(data, 6),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.protocol_number;
+        put_word(&mut payload, 1, self.target_voltage);
+        payload[3] = self.current_request;
+        payload[4] = self.faults.to_byte();
+        payload[5] = self.status.to_byte();
+        payload[6] = self.state_of_charge;
+        payload
+    }
+}
+
+// 0x108 - EVSE capabilities.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvseParams108 {
+    pub welding_detection: bool, // Byte 0, EV contactor weld detection supported
+    pub available_voltage: u16,  // Byte 1-2, V
+    pub available_current: u8,   // Byte 3, A
+    pub threshold_voltage: u16,  // Byte 4-5, V
+}
+
+impl EvseParams108 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            welding_detection: byte(data, 0) != 0,
+            available_voltage: word(data, 1),
+            available_current: byte(data, 3),
+            threshold_voltage: word(data, 4),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.welding_detection as u8;
+        put_word(&mut payload, 1, self.available_voltage);
+        payload[3] = self.available_current;
+        put_word(&mut payload, 4, self.threshold_voltage);
+        payload
+    }
+}
+
+// 0x109 byte 5 - EVSE status flags.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvseStatusFlags {
+    pub charging: bool,         // Bit 0, charger is active
+    pub error: bool,            // Bit 1, something went wrong
+    pub connector_locked: bool, // Bit 2, connector is currently locked
+    pub incompatible: bool,     // Bit 3, params between vehicle and charger not compatible
+    pub battery_error: bool,    // Bit 4, something wrong with battery
+    pub stopped: bool,          // Bit 5, charger is stopped
+}
+
+impl EvseStatusFlags {
+    pub fn from_byte(value: u8) -> Self {
+        Self {
+            charging: bit(value, 0x01),
+            error: bit(value, 0x02),
+            connector_locked: bit(value, 0x04),
+            incompatible: bit(value, 0x08),
+            battery_error: bit(value, 0x10),
+            stopped: bit(value, 0x20),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        let mut value = 0;
+        if self.charging {
+            value |= 0x01;
+        }
+        if self.error {
+            value |= 0x02;
+        }
+        if self.connector_locked {
+            value |= 0x04;
+        }
+        if self.incompatible {
+            value |= 0x08;
+        }
+        if self.battery_error {
+            value |= 0x10;
+        }
+        if self.stopped {
+            value |= 0x20;
+        }
+        value
+    }
+}
+
+// 0x109 - EVSE present output and status.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvseStatus109 {
+    pub protocol_number: u8,  // Byte 0, PROTOCOL_*
+    pub present_voltage: u16, // Byte 1-2, V
+    pub present_current: u8,  // Byte 3, A
+    pub status: EvseStatusFlags,
+    pub remaining_time_10s: u8, // Byte 6, 10 s units. 0xFF -> use byte 7 instead.
+    pub remaining_time_1min: u8, // Byte 7, 1 min units
+}
+
+impl EvseStatus109 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            protocol_number: byte(data, 0),
+            present_voltage: word(data, 1),
+            present_current: byte(data, 3),
+            status: EvseStatusFlags::from_byte(byte(data, 5)),
+            remaining_time_10s: byte(data, 6),
+            remaining_time_1min: byte(data, 7),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.protocol_number;
+        put_word(&mut payload, 1, self.present_voltage);
+        payload[3] = self.present_current;
+        payload[5] = self.status.to_byte();
+        payload[6] = self.remaining_time_10s;
+        payload[7] = self.remaining_time_1min;
+        payload
+    }
+}
+
+// 0x110 / 0x118 byte 0 - Extended functions, each used only when both sides set it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ExtendedFunctions {
+    pub dynamic_control: bool, // Bit 0, available current may change while charging
+    pub high_current_control: bool, // Bit 1, currents above 255 A in the 16 bit fields
+}
+
+impl ExtendedFunctions {
+    pub fn from_byte(value: u8) -> Self {
+        Self {
+            dynamic_control: bit(value, 0x01),
+            high_current_control: bit(value, 0x02),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        let mut value = 0;
+        if self.dynamic_control {
+            value |= 0x01;
+        }
+        if self.high_current_control {
+            value |= 0x02;
+        }
+        value
+    }
+}
+
+// 0x110 - Vehicle extended functions and high current request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VehicleExtended110 {
+    pub functions: ExtendedFunctions,
+    pub current_request: u16, // Byte 1-2, A, replaces 0x102 byte 3 with high current control
+}
+
+impl VehicleExtended110 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            functions: ExtendedFunctions::from_byte(byte(data, 0)),
+            current_request: word(data, 1),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.functions.to_byte();
+        put_word(&mut payload, 1, self.current_request);
+        payload
+    }
+}
+
+// 0x118 - EVSE extended functions and high current values. 0x108 / 0x109 carry the same
+// currents, capped at 255 A.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvseExtended118 {
+    pub functions: ExtendedFunctions,
+    pub available_current: u16, // Byte 1-2, A
+    pub present_current: u16,   // Byte 3-4, A
+}
+
+impl EvseExtended118 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            functions: ExtendedFunctions::from_byte(byte(data, 0)),
+            available_current: word(data, 1),
+            present_current: word(data, 3),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.functions.to_byte();
+        put_word(&mut payload, 1, self.available_current);
+        put_word(&mut payload, 3, self.present_current);
+        payload
+    }
+}
+
+// 0x200 - Vehicle discharge (V2H) limits. Only sent by vehicles that can discharge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VehicleDischarge200 {
+    pub maximum_discharge_current: u8,  // Byte 0, A, inverted
+    pub minimum_discharge_voltage: u16, // Byte 4-5, V
+    pub minimum_discharge_soc: u8,      // Byte 6, %, discharge stops here
+    pub maximum_charge_soc: u8,         // Byte 7, %, charging stops here
+}
+
+impl VehicleDischarge200 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            maximum_discharge_current: inverted(byte(data, 0)),
+            minimum_discharge_voltage: word(data, 4),
+            minimum_discharge_soc: byte(data, 6),
+            maximum_charge_soc: byte(data, 7),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = inverted(self.maximum_discharge_current);
+        put_word(&mut payload, 4, self.minimum_discharge_voltage);
+        payload[6] = self.minimum_discharge_soc;
+        payload[7] = self.maximum_charge_soc;
+        payload
+    }
+}
+
+// 0x208 - EVSE discharge (V2H) capability and present discharge current. Only sent by chargers
+// that can discharge.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvseDischarge208 {
+    pub present_discharge_current: u8, // Byte 0, A, inverted
+    pub available_input_voltage: u16,  // Byte 1-2, V
+    pub available_input_current: u8,   // Byte 3, A, inverted
+    pub lower_threshold_voltage: u16,  // Byte 6-7, V, discharge stops below this
+}
+
+impl EvseDischarge208 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            present_discharge_current: inverted(byte(data, 0)),
+            available_input_voltage: word(data, 1),
+            available_input_current: inverted(byte(data, 3)),
+            lower_threshold_voltage: word(data, 6),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = inverted(self.present_discharge_current);
+        put_word(&mut payload, 1, self.available_input_voltage);
+        payload[3] = inverted(self.available_input_current);
+        put_word(&mut payload, 6, self.lower_threshold_voltage);
+        payload
+    }
+}
+
+// 0x209 - EVSE discharge (V2H) sequence and remaining time.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EvseDischargeStatus209 {
+    pub sequence_control_number: u8,   // Byte 0
+    pub remaining_discharge_time: u16, // Byte 1-2, min
+}
+
+impl EvseDischargeStatus209 {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            sequence_control_number: byte(data, 0),
+            remaining_discharge_time: word(data, 1),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.sequence_control_number;
+        put_word(&mut payload, 1, self.remaining_discharge_time);
+        payload
+    }
+}
@@ -0,0 +1,136 @@
+#![deny(warnings)]
+// CHAdeMO frames from the charger, sent from the 100 ms loop while a session has transmit on.
+use crate::chademo::*;
+use crate::interfaces::{CanBus, CanMessage};
+use crate::session::vehicle_fault;
+use crate::types::*;
+
+pub fn init<C: CanBus>(elapsed: u32, cd_state: &CDState, car_state: &CarState, fc_can: &mut C) {
+    if !cd_state.enable_can_transmit {
+        return;
+    }
+    params108(fc_can, cd_state);
+    status109(fc_can, elapsed, cd_state, car_state);
+    let config = &cd_state.charger_config;
+    if config.dynamic_control || config.high_current_control {
+        extended118(fc_can, cd_state);
+    }
+    if config.discharge_capable {
+        discharge208(fc_can, cd_state);
+        discharge_status209(fc_can);
+    }
+}
+
+fn transmit<C: CanBus>(fc_can: &mut C, id: u32, payload: &[u8; FRAME_LENGTH]) {
+    fc_can.send_frame(&CanMessage::new(id, payload));
+}
+
+pub fn params108<C: CanBus>(fc_can: &mut C, cd_state: &CDState) {
+    let config = &cd_state.charger_config;
+    let params108 = EvseParams108 {
+        welding_detection: config.weld_detection,
+        available_voltage: config.rated_voltage,
+        available_current: cd_state.available_current.min(0xFF) as u8,
+        threshold_voltage: config.threshold_voltage,
+    };
+    transmit(fc_can, EVSE_PARAMS_ID, &params108.encode());
+}
+
+// Flags sent in 0x109. Charging while the output is on, stopped in every other state.
+pub fn evse_status(cd_state: &CDState, car_state: &CarState) -> EvseStatusFlags {
+    let charging = matches!(
+        cd_state.charge_state,
+        ChargeStateEnum::ChargeLoop | ChargeStateEnum::DischargeLoop
+    );
+    EvseStatusFlags {
+        charging,
+        error: cd_state.fault_line
+            || cd_state.discharge_fault
+            || cd_state.lock_fault
+            || cd_state.insulation_fault
+            || cd_state.charge_state == ChargeStateEnum::TimeOut,
+        connector_locked: cd_state.connector_locked,
+        incompatible: cd_state.incompatible,
+        battery_error: car_state.malfunction || vehicle_fault(car_state).is_some(),
+        stopped: !charging,
+    }
+}
+
+// Remaining time as (10 s units, 1 min units): the vehicle's maximum charge time until charging
+// starts, less the time spent charging after that. The 10 s byte is 0xFF when the time is too
+// long for it, and both are 0xFF while the vehicle hasn't sent a maximum.
+pub fn remaining_time(elapsed: u32, cd_state: &CDState, car_state: &CarState) -> (u8, u8) {
+    let seconds = match cd_state.charge_state {
+        ChargeStateEnum::ChargeLoop => {
+            let charged = elapsed.wrapping_sub(cd_state.charge_start_ts) / 1000;
+            car_state.charge_time_max.saturating_sub(charged)
+        }
+        ChargeStateEnum::WaitForComms
+        | ChargeStateEnum::WaitChargeEnable
+        | ChargeStateEnum::InsulationTest
+        | ChargeStateEnum::WaitVehicleChargeStart => car_state.charge_time_max,
+        _ => return (0, 0),
+    };
+    if car_state.charge_time_max == 0 {
+        return (0xFF, 0xFF);
+    }
+    let minutes = (seconds / 60).min(0xFF) as u8;
+    if seconds / 10 < 0xFF {
+        ((seconds / 10) as u8, minutes)
+    } else {
+        (0xFF, minutes)
+    }
+}
+
+pub fn status109<C: CanBus>(
+    fc_can: &mut C,
+    elapsed: u32,
+    cd_state: &CDState,
+    car_state: &CarState,
+) {
+    let (remaining_time_10s, remaining_time_1min) = remaining_time(elapsed, cd_state, car_state);
+    let status109 = EvseStatus109 {
+        protocol_number: cd_state.protocol_number,
+        present_voltage: cd_state.current_voltage,
+        present_current: cd_state.present_current.min(0xFF) as u8,
+        status: evse_status(cd_state, car_state),
+        remaining_time_10s,
+        remaining_time_1min,
+    };
+    transmit(fc_can, EVSE_STATUS_ID, &status109.encode());
+}
+
+// What the charger offers of 1.0 / 2.0, and the currents in full.
+pub fn extended118<C: CanBus>(fc_can: &mut C, cd_state: &CDState) {
+    let config = &cd_state.charger_config;
+    let extended118 = EvseExtended118 {
+        functions: ExtendedFunctions {
+            dynamic_control: config.dynamic_control,
+            high_current_control: config.high_current_control,
+        },
+        available_current: cd_state.available_current,
+        present_current: cd_state.present_current,
+    };
+    transmit(fc_can, EVSE_EXTENDED_ID, &extended118.encode());
+}
+
+// V2H capability, sent by a discharge capable charger so the vehicle knows it can offer 0x200.
+pub fn discharge208<C: CanBus>(fc_can: &mut C, cd_state: &CDState) {
+    let config = &cd_state.charger_config;
+    let discharge208 = EvseDischarge208 {
+        present_discharge_current: cd_state.v2h_current,
+        available_input_voltage: config.rated_voltage,
+        available_input_current: config.rated_discharge_current,
+        lower_threshold_voltage: config.discharge_threshold_voltage,
+    };
+    transmit(fc_can, EVSE_DISCHARGE_ID, &discharge208.encode());
+}
+
+// No discharge time limit of our own, the vehicle's minimum SoC ends the session.
+pub fn discharge_status209<C: CanBus>(fc_can: &mut C) {
+    let status209 = EvseDischargeStatus209 {
+        sequence_control_number: 0x02,
+        remaining_discharge_time: 0,
+    };
+    transmit(fc_can, EVSE_DISCHARGE_STATUS_ID, &status209.encode());
+}
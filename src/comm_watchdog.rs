@@ -0,0 +1,48 @@
+#![deny(warnings)]
+use crate::add_to_activity_list;
+use crate::types::*;
+use crate::utils::stop_charge;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// Run from the periodic loop. can_receive_logic can only say a vehicle frame has arrived, this
+// is where not getting one is noticed.
+pub fn init(elapsed: u32, cd_state: &mut CDState, car_state: &mut CarState) {
+    if cd_state.charge_state == ChargeStateEnum::TimeOut {
+        // Not while discharge still has the connector locked.
+        if elapsed.wrapping_sub(cd_state.timeout_ts) >= TIMEOUT_RECOVERY_MS
+            && !cd_state.latch_enabled
+        {
+            cd_state.charge_state = ChargeStateEnum::ChargeIdle;
+            add_to_activity_list!(cd_state, "{} - TimeOut -> ChargeIdle", elapsed);
+        }
+        return;
+    }
+
+    if !cd_state.charge_state.is_active() {
+        return;
+    }
+
+    // Until the first frame arrives, previous_can_ts is the start of the session.
+    let limit = if cd_state.comm_timeout {
+        cd_state.comm_start_timeout_limit
+    } else {
+        cd_state.comm_timeout_limit
+    };
+
+    if elapsed.wrapping_sub(cd_state.previous_can_ts) > limit {
+        cd_state.comm_timeout = true;
+        cd_state.timeout_ts = elapsed;
+        cd_state.charge_state = ChargeStateEnum::TimeOut;
+        add_to_activity_list!(
+            cd_state,
+            "{} - No vehicle frame for {} ms -> TimeOut",
+            elapsed,
+            elapsed.wrapping_sub(cd_state.previous_can_ts)
+        );
+        stop_charge(cd_state, car_state, elapsed);
+    }
+}
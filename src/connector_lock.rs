@@ -0,0 +1,67 @@
+#![deny(warnings)]
+// Drives the connector lock from latch_enabled and checks the position feedback follows it.
+// The session only goes on to the insulation test once the lock is confirmed engaged, and only
+// ends once it is confirmed released.
+use crate::add_to_activity_list;
+use crate::interfaces::{ConnectorLock, PowerStage};
+use crate::types::*;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// Run every pass of the main loop, before insulation_test.
+pub fn init<L: ConnectorLock, P: PowerStage>(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    lock: &mut L,
+    power_stage: &P,
+) {
+    // Never released with voltage on the output, whatever cleared the latch.
+    let live = power_stage.measured_voltage() >= UNLOCK_VOLTAGE;
+    let command = cd_state.latch_enabled || (live && cd_state.connector_locked);
+    lock.set_locked(command);
+    let locked = lock.is_locked();
+    if locked != cd_state.connector_locked {
+        cd_state.connector_locked = locked;
+        if locked {
+            add_to_activity_list!(cd_state, "{} - Connector locked", elapsed);
+        } else {
+            add_to_activity_list!(cd_state, "{} - Connector unlocked", elapsed);
+        }
+    }
+
+    if locked == command {
+        cd_state.lock_ts = elapsed;
+    } else if !cd_state.lock_fault && elapsed.wrapping_sub(cd_state.lock_ts) > LOCK_TIMEOUT_MS {
+        cd_state.lock_fault = true;
+        add_to_activity_list!(
+            cd_state,
+            "{} - Lock fault, commanded {}",
+            elapsed,
+            command as u8
+        );
+        if cd_state.charge_state.is_active() {
+            cd_state.charge_state = ChargeStateEnum::StopCharge;
+            add_to_activity_list!(cd_state, "{} - Lock fault -> StopCharge", elapsed);
+        }
+    }
+
+    match cd_state.charge_state {
+        ChargeStateEnum::WaitChargeEnable if cd_state.latch_enabled && locked => {
+            add_to_activity_list!(cd_state, "{} - WaitChargeEnable -> InsulationTest", elapsed);
+            cd_state.insulation_phase = InsulationPhaseEnum::Rise;
+            cd_state.insulation_ts = elapsed;
+            cd_state.charge_state = ChargeStateEnum::InsulationTest;
+        }
+        // A lock that won't release has already been flagged, don't hold the session up for it.
+        ChargeStateEnum::Discharge
+            if !cd_state.latch_enabled && (!locked || cd_state.lock_fault) =>
+        {
+            cd_state.charge_state = ChargeStateEnum::ChargeIdle;
+            add_to_activity_list!(cd_state, "{} - Discharge -> ChargeIdle", elapsed);
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,67 @@
+#![deny(warnings)]
+// Output control. In ChargeLoop the power stage follows the vehicle's voltage and current
+// targets, clamped to what the charger advertises (which dynamic control can lower
+// mid-session). In DischargeLoop it draws the most the vehicle (0x200) and the charger allow.
+// insulation_test has the stage during InsulationTest, anywhere else it is off.
+use crate::interfaces::PowerStage;
+use crate::session::current_request;
+use crate::types::*;
+
+// Run every pass of the main loop, so a stop turns the output off before the relays open.
+pub fn init<P: PowerStage>(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &CarState,
+    power_stage: &mut P,
+) {
+    let delta_ms = elapsed.wrapping_sub(cd_state.control_ts);
+    cd_state.control_ts = elapsed;
+
+    if cd_state.charge_state == ChargeStateEnum::InsulationTest {
+        return;
+    }
+    let config = &cd_state.charger_config;
+    let target_ma = match cd_state.charge_state {
+        ChargeStateEnum::ChargeLoop => {
+            current_request(cd_state, car_state).min(cd_state.available_current) as u32 * 1000
+        }
+        ChargeStateEnum::DischargeLoop => {
+            car_state
+                .max_discharge_current
+                .min(config.rated_discharge_current) as u32
+                * 1000
+        }
+        _ => {
+            cd_state.output_current_ma = 0;
+            power_stage.set_output(false, 0, 0);
+            power_stage.set_reverse_output(false, 0);
+            cd_state.present_current = power_stage.measured_current();
+            cd_state.v2h_current = power_stage.measured_reverse_current();
+            return;
+        }
+    };
+    // A/s is mA/ms. Rises are rate limited, a lower request is followed straight away.
+    cd_state.output_current_ma = if target_ma > cd_state.output_current_ma {
+        (cd_state.output_current_ma + delta_ms * CURRENT_RAMP_A_PER_S).min(target_ma)
+    } else {
+        target_ma
+    };
+    let current = (cd_state.output_current_ma / 1000) as u16;
+
+    if cd_state.charge_state == ChargeStateEnum::ChargeLoop {
+        let voltage = car_state.voltage_target.min(config.rated_voltage);
+        power_stage.set_output(true, voltage, current);
+    } else {
+        power_stage.set_reverse_output(true, current as u8);
+    }
+    cd_state.current_voltage = power_stage.measured_voltage();
+    cd_state.present_current = power_stage.measured_current();
+    cd_state.v2h_current = power_stage.measured_reverse_current();
+
+    let summary = &mut cd_state.session_summary;
+    summary.charge_time = elapsed.wrapping_sub(cd_state.charge_start_ts) / 1000;
+    summary.max_current = summary
+        .max_current
+        .max(cd_state.present_current.max(cd_state.v2h_current as u16));
+    summary.max_voltage = summary.max_voltage.max(cd_state.current_voltage);
+}
@@ -0,0 +1,51 @@
+#![deny(warnings)]
+// End of session. With the output off the discharge circuit pulls it down, and the latch is only
+// cleared once it is below UNLOCK_VOLTAGE. Runs after stop_charge (Discharge) and after a comm
+// timeout (TimeOut) while the latch is still set. connector_lock releases the lock and moves
+// Discharge on to ChargeIdle. If it doesn't get there in DISCHARGE_TIMEOUT_MS, something (a welded
+// contactor) holds the output up: the discharge circuit goes off, so it doesn't load the battery,
+// and the connector stays locked. That is latched on purpose, nothing on the console clears it.
+// The session stays in Discharge, locked and with the error bit set, until the output falls below
+// UNLOCK_VOLTAGE by itself or the charger is reset.
+use crate::add_to_activity_list;
+use crate::interfaces::PowerStage;
+use crate::types::*;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// Run every pass of the main loop, after control_loop has turned the output off.
+pub fn init<P: PowerStage>(elapsed: u32, cd_state: &mut CDState, power_stage: &mut P) {
+    let discharging = match cd_state.charge_state {
+        ChargeStateEnum::Discharge | ChargeStateEnum::TimeOut => cd_state.latch_enabled,
+        _ => false,
+    };
+    if !discharging {
+        return;
+    }
+
+    power_stage.set_output(false, 0, 0);
+    power_stage.set_discharge(!cd_state.discharge_fault);
+    let measured = power_stage.measured_voltage();
+    cd_state.current_voltage = measured;
+
+    if measured < UNLOCK_VOLTAGE {
+        power_stage.set_discharge(false);
+        cd_state.latch_enabled = false;
+        add_to_activity_list!(
+            cd_state,
+            "{} - Discharged to {} V, unlocking",
+            elapsed,
+            measured
+        );
+    } else if !cd_state.discharge_fault
+        && elapsed.wrapping_sub(cd_state.discharge_ts) > DISCHARGE_TIMEOUT_MS
+    {
+        // Stays locked.
+        power_stage.set_discharge(false);
+        cd_state.discharge_fault = true;
+        add_to_activity_list!(cd_state, "{} - Discharge timeout, {} V", elapsed, measured);
+    }
+}
@@ -0,0 +1,122 @@
+#![deny(unsafe_code)]
+#![deny(warnings)]
+#![no_main]
+#![no_std]
+
+// Vehicle side firmware: a Nucleo board playing the car, for bench testing chargers.
+// Same board set up as the charger. FCCAN carries 0x100/0x101/0x102 out and 0x108/0x109 in,
+// relay one pulls the charge permission line (j) to ground, and the targets are set from the
+// serial console.
+
+extern crate cortex_m;
+extern crate panic_halt;
+
+// Entrypoint
+use cortex_m_rt::entry;
+
+#[cfg(feature = "nucleof767zi")]
+extern crate stm32f7xx_hal as hal;
+
+#[cfg(feature = "nucleof446re")]
+extern crate stm32f4xx_hal as hal;
+
+// General HAL items
+use hal::{
+    interrupt, pac,
+    prelude::*,
+    timer::{Event, Timer},
+};
+
+// Elapsed_MS stuff...
+use core::cell::{Cell, RefCell};
+use core::ops::DerefMut;
+use cortex_m::interrupt::{free, Mutex};
+
+// Aliases
+use can_dc_fc::interfaces::{CanBus, Clock, Relay};
+use can_dc_fc::vehicle::*;
+use can_dc_fc::vehicle_console;
+
+const HUNDRED_MS: u32 = 100;
+
+static ELAPSED_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0u32));
+static TIMER_TIM2: Mutex<RefCell<Option<Timer<pac::TIM2>>>> = Mutex::new(RefCell::new(None));
+
+// TIM2 counts ELAPSED_MS up once per ms.
+struct SysTick;
+
+impl Clock for SysTick {
+    fn elapsed_ms(&self) -> u32 {
+        free(|cs| ELAPSED_MS.borrow(cs).get())
+    }
+}
+
+#[entry]
+fn main() -> ! {
+    let (
+        _fault_in,
+        mut permission,
+        _relay_2,
+        _lock,
+        _sequence,
+        d2_in,
+        mut fc_can,
+        serial,
+        timer,
+        _rtc,
+    ) = can_dc_fc::hardware_init::init_devices();
+
+    free(|cs| {
+        TIMER_TIM2.borrow(cs).replace(Some(timer));
+    });
+    can_dc_fc::hardware_init::enable_timer_interrupt();
+
+    let (mut tx, mut rx) = serial.split();
+    let clock = SysTick;
+
+    // Charges until stopped from the console, or the charger stops.
+    let config = VehicleConfig {
+        charge_time: 0,
+        evse_response_timeout: 0,
+        ..VehicleConfig::new()
+    };
+    let mut vehicle = VehicleState::new(config);
+    let mut hundred_ms_counter: u8 = 0; // Status line every fifth
+    let mut previous_100_ms_ts = 0;
+    vehicle_console::print_menu(&mut tx);
+
+    loop {
+        let elapsed = clock.elapsed_ms();
+
+        while let Some(frame) = fc_can.receive_frame() {
+            init(elapsed, &mut vehicle, frame.id, frame.data());
+        }
+
+        if let Ok(received) = rx.read() {
+            vehicle_console::init(received, elapsed, &mut vehicle, &mut tx);
+        }
+
+        permission.set_closed(vehicle.charge_permission);
+        vehicle.d2 = d2_in.is_on();
+
+        if elapsed.wrapping_sub(previous_100_ms_ts) >= HUNDRED_MS {
+            previous_100_ms_ts = elapsed;
+            hundred_ms_loop(elapsed, &mut vehicle, &mut fc_can);
+            hundred_ms_counter = (hundred_ms_counter + 1) % 5;
+            vehicle_console::display(&mut tx, &mut vehicle, elapsed, hundred_ms_counter == 0);
+        }
+    }
+}
+
+#[interrupt]
+fn TIM2() {
+    free(|cs| {
+        if let Some(ref mut tim2) = TIMER_TIM2.borrow(cs).borrow_mut().deref_mut() {
+            tim2.clear_interrupt(Event::TimeOut);
+        }
+
+        let cell = ELAPSED_MS.borrow(cs);
+        let val = cell.get();
+        cell.replace(val.wrapping_add(1));
+    });
+}
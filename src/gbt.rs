@@ -0,0 +1,562 @@
+#![deny(warnings)]
+// GB/T 27930 frame definitions.
+// 250 kbit/s, 29-bit J1939 style ids: priority, PGN, destination and source address. Each message
+// is a struct with a decode from the (reassembled) payload and an encode back to it, like
+// chademo.rs. Multi-byte values are little endian. Voltages are 0.1 V per bit. Currents are
+// 0.1 A per bit with a -400 A offset, charging current being negative, so the structs hold
+// charging current in 0.1 A and the codec does the offset.
+
+pub const CHARGER_ADDRESS: u8 = 0x56;
+pub const BMS_ADDRESS: u8 = 0xF4;
+pub const GLOBAL_ADDRESS: u8 = 0xFF;
+
+// Charger -> BMS
+pub const CHM_PGN: u32 = 0x2600; // Handshake
+pub const CRM_PGN: u32 = 0x0100; // Recognition
+pub const CML_PGN: u32 = 0x0800; // Output limits
+pub const CRO_PGN: u32 = 0x0A00; // Ready for charging
+pub const CCS_PGN: u32 = 0x1200; // Charging status
+pub const CST_PGN: u32 = 0x1A00; // Stop
+pub const CSD_PGN: u32 = 0x1D00; // Statistics
+pub const CEM_PGN: u32 = 0x1F00; // Error (timeouts)
+
+// BMS -> Charger
+pub const BHM_PGN: u32 = 0x2700; // Handshake
+pub const BRM_PGN: u32 = 0x0200; // Recognition, multi-packet
+pub const BCP_PGN: u32 = 0x0600; // Charging parameters, multi-packet
+pub const BRO_PGN: u32 = 0x0900; // Ready for charging
+pub const BCL_PGN: u32 = 0x1000; // Charging requirement
+pub const BCS_PGN: u32 = 0x1100; // Charging status, multi-packet
+pub const BSM_PGN: u32 = 0x1300; // Battery status
+pub const BST_PGN: u32 = 0x1900; // Stop
+pub const BSD_PGN: u32 = 0x1C00; // Statistics
+pub const BEM_PGN: u32 = 0x1E00; // Error (timeouts)
+
+// J1939 transport (gbt_transport.rs)
+pub const TP_CM_PGN: u32 = 0xEC00;
+pub const TP_DT_PGN: u32 = 0xEB00;
+
+pub const FRAME_LENGTH: usize = 8;
+
+// Readiness in CRM / BRO / CRO.
+pub const NOT_READY: u8 = 0x00;
+pub const READY: u8 = 0xAA;
+
+// Protocol version sent in CHM, V1.1.
+pub const PROTOCOL_VERSION: [u8; 3] = [0x01, 0x01, 0x00];
+
+// Priority for each PGN the charger sends, from the standard.
+pub fn priority(pgn: u32) -> u8 {
+    match pgn {
+        CRO_PGN | CST_PGN => 4,
+        CEM_PGN => 2,
+        TP_CM_PGN | TP_DT_PGN => 7,
+        _ => 6,
+    }
+}
+
+// PDU1 format, the PGN's low byte is the destination address.
+pub fn frame_id(pgn: u32, destination: u8, source: u8) -> u32 {
+    (priority(pgn) as u32) << 26 | (pgn & 0x3FF00) << 8 | (destination as u32) << 8 | source as u32
+}
+
+pub fn pgn(id: u32) -> u32 {
+    (id >> 8) & 0x3FF00
+}
+
+pub fn destination(id: u32) -> u8 {
+    (id >> 8) as u8
+}
+
+pub fn source(id: u32) -> u8 {
+    id as u8
+}
+
+// Short frames are treated as if the missing bytes were zero.
+fn byte(data: &[u8], index: usize) -> u8 {
+    data.get(index).copied().unwrap_or(0)
+}
+
+fn word(data: &[u8], index: usize) -> u16 {
+    (byte(data, index + 1) as u16) << 8 | byte(data, index) as u16
+}
+
+fn put_word(payload: &mut [u8; FRAME_LENGTH], index: usize, value: u16) {
+    payload[index] = (value & 0x00FF) as u8;
+    payload[index + 1] = ((value & 0xFF00) >> 8) as u8;
+}
+
+// 0.1 A charging current to and from the -400 A offset.
+fn current(data: &[u8], index: usize) -> u16 {
+    4000u16.saturating_sub(word(data, index))
+}
+
+fn put_current(payload: &mut [u8; FRAME_LENGTH], index: usize, deciamps: u16) {
+    put_word(payload, index, 4000u16.saturating_sub(deciamps));
+}
+
+// Two bit status fields: 00 normal / no, 01 fault / yes, 10 untrusted.
+fn field(value: u8, shift: u8) -> bool {
+    (value >> shift) & 0x03 == 0x01
+}
+
+// CHM - Charger handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChargerHandshake {
+    pub protocol_version: [u8; 3], // Byte 0-2
+}
+
+impl ChargerHandshake {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            protocol_version: [byte(data, 0), byte(data, 1), byte(data, 2)],
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[..3].copy_from_slice(&self.protocol_version);
+        payload
+    }
+}
+
+// BHM - BMS handshake.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmsHandshake {
+    pub max_charge_voltage: u16, // Byte 0-1, 0.1 V
+}
+
+impl BmsHandshake {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            max_charge_voltage: word(data, 0),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        put_word(&mut payload, 0, self.max_charge_voltage);
+        payload
+    }
+}
+
+// CRM - Charger recognition.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChargerRecognition {
+    pub recognized: bool,    // Byte 0, READY once the BRM has been received
+    pub charger_number: u32, // Byte 1-4
+}
+
+impl ChargerRecognition {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            recognized: byte(data, 0) == READY,
+            charger_number: word(data, 3) as u32 * 0x10000 + word(data, 1) as u32,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = if self.recognized { READY } else { NOT_READY };
+        put_word(&mut payload, 1, self.charger_number as u16);
+        put_word(&mut payload, 3, (self.charger_number >> 16) as u16);
+        payload
+    }
+}
+
+// BRM - BMS recognition, 49 bytes. Only the fields the charger uses, the VIN and the rest are
+// skipped.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmsRecognition {
+    pub protocol_version: [u8; 3], // Byte 0-2
+    pub battery_type: u8,          // Byte 3
+    pub rated_capacity: u16,       // Byte 4-5, 0.1 Ah
+    pub rated_voltage: u16,        // Byte 6-7, 0.1 V
+}
+
+pub const BRM_LENGTH: usize = 49;
+
+impl BmsRecognition {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            protocol_version: [byte(data, 0), byte(data, 1), byte(data, 2)],
+            battery_type: byte(data, 3),
+            rated_capacity: word(data, 4),
+            rated_voltage: word(data, 6),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[..3].copy_from_slice(&self.protocol_version);
+        payload[3] = self.battery_type;
+        put_word(&mut payload, 4, self.rated_capacity);
+        put_word(&mut payload, 6, self.rated_voltage);
+        payload
+    }
+}
+
+// BCP - Battery charging parameters, 13 bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmsParameters {
+    pub max_cell_voltage: u16,   // Byte 0-1, 0.01 V
+    pub max_charge_current: u16, // Byte 2-3, 0.1 A
+    pub nominal_energy: u16,     // Byte 4-5, 0.1 kWh
+    pub max_charge_voltage: u16, // Byte 6-7, 0.1 V
+    pub max_temperature: u8,     // Byte 8, deg C + 50
+    pub state_of_charge: u16,    // Byte 9-10, 0.1 %
+    pub battery_voltage: u16,    // Byte 11-12, 0.1 V
+}
+
+pub const BCP_LENGTH: usize = 13;
+
+impl BmsParameters {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            max_cell_voltage: word(data, 0),
+            max_charge_current: current(data, 2),
+            nominal_energy: word(data, 4),
+            max_charge_voltage: word(data, 6),
+            max_temperature: byte(data, 8),
+            state_of_charge: word(data, 9),
+            battery_voltage: word(data, 11),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; BCP_LENGTH] {
+        let mut first = [0u8; FRAME_LENGTH];
+        put_word(&mut first, 0, self.max_cell_voltage);
+        put_current(&mut first, 2, self.max_charge_current);
+        put_word(&mut first, 4, self.nominal_energy);
+        put_word(&mut first, 6, self.max_charge_voltage);
+        let mut rest = [0u8; FRAME_LENGTH];
+        rest[0] = self.max_temperature;
+        put_word(&mut rest, 1, self.state_of_charge);
+        put_word(&mut rest, 3, self.battery_voltage);
+        let mut payload = [0u8; BCP_LENGTH];
+        payload[..FRAME_LENGTH].copy_from_slice(&first);
+        payload[FRAME_LENGTH..].copy_from_slice(&rest[..BCP_LENGTH - FRAME_LENGTH]);
+        payload
+    }
+}
+
+// CML - Charger output limits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChargerLimits {
+    pub max_voltage: u16, // Byte 0-1, 0.1 V
+    pub min_voltage: u16, // Byte 2-3, 0.1 V
+    pub max_current: u16, // Byte 4-5, 0.1 A
+    pub min_current: u16, // Byte 6-7, 0.1 A
+}
+
+impl ChargerLimits {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            max_voltage: word(data, 0),
+            min_voltage: word(data, 2),
+            max_current: current(data, 4),
+            min_current: current(data, 6),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        put_word(&mut payload, 0, self.max_voltage);
+        put_word(&mut payload, 2, self.min_voltage);
+        put_current(&mut payload, 4, self.max_current);
+        put_current(&mut payload, 6, self.min_current);
+        payload
+    }
+}
+
+// BRO / CRO - Ready for charging, one byte each way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ready {
+    pub ready: bool, // Byte 0, READY / NOT_READY
+}
+
+impl Ready {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            ready: byte(data, 0) == READY,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = if self.ready { READY } else { NOT_READY };
+        payload
+    }
+}
+
+// BCL - Charging requirement.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmsRequirement {
+    pub voltage_request: u16,   // Byte 0-1, 0.1 V
+    pub current_request: u16,   // Byte 2-3, 0.1 A
+    pub constant_current: bool, // Byte 4, 0x02 constant current, 0x01 constant voltage
+}
+
+impl BmsRequirement {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            voltage_request: word(data, 0),
+            current_request: current(data, 2),
+            constant_current: byte(data, 4) == 0x02,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        put_word(&mut payload, 0, self.voltage_request);
+        put_current(&mut payload, 2, self.current_request);
+        payload[4] = if self.constant_current { 0x02 } else { 0x01 };
+        payload
+    }
+}
+
+// BCS - Battery charging status, 9 bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmsStatus {
+    pub measured_voltage: u16, // Byte 0-1, 0.1 V
+    pub measured_current: u16, // Byte 2-3, 0.1 A
+    pub max_cell_voltage: u16, // Byte 4-5, bits 0-11 0.01 V, bits 12-15 group
+    pub state_of_charge: u8,   // Byte 6, %
+    pub remaining_time: u16,   // Byte 7-8, min
+}
+
+pub const BCS_LENGTH: usize = 9;
+
+impl BmsStatus {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            measured_voltage: word(data, 0),
+            measured_current: current(data, 2),
+            max_cell_voltage: word(data, 4),
+            state_of_charge: byte(data, 6),
+            remaining_time: word(data, 7),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; BCS_LENGTH] {
+        let mut first = [0u8; FRAME_LENGTH];
+        put_word(&mut first, 0, self.measured_voltage);
+        put_current(&mut first, 2, self.measured_current);
+        put_word(&mut first, 4, self.max_cell_voltage);
+        first[6] = self.state_of_charge;
+        let [low, high] = self.remaining_time.to_le_bytes();
+        let mut payload = [0u8; BCS_LENGTH];
+        payload[..7].copy_from_slice(&first[..7]);
+        payload[7] = low;
+        payload[8] = high;
+        payload
+    }
+}
+
+// CCS - Charger status.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChargerStatus {
+    pub output_voltage: u16, // Byte 0-1, 0.1 V
+    pub output_current: u16, // Byte 2-3, 0.1 A
+    pub charge_time: u16,    // Byte 4-5, min
+    pub permitted: bool,     // Byte 6 bits 0-1, 01 charging permitted, 00 paused
+}
+
+impl ChargerStatus {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            output_voltage: word(data, 0),
+            output_current: current(data, 2),
+            charge_time: word(data, 4),
+            permitted: field(byte(data, 6), 0),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        put_word(&mut payload, 0, self.output_voltage);
+        put_current(&mut payload, 2, self.output_current);
+        put_word(&mut payload, 4, self.charge_time);
+        payload[6] = 0xFC | self.permitted as u8;
+        payload
+    }
+}
+
+// BSM - Battery status. Any of the fault fields, or charging not permitted, stops the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmsBatteryStatus {
+    pub cell_voltage_fault: bool, // Byte 5 bits 0-1, high / low
+    pub soc_fault: bool,          // Byte 5 bits 2-3, high / low
+    pub over_current: bool,       // Byte 5 bits 4-5
+    pub over_temperature: bool,   // Byte 5 bits 6-7
+    pub insulation_fault: bool,   // Byte 6 bits 0-1
+    pub connector_fault: bool,    // Byte 6 bits 2-3
+    pub charge_permitted: bool,   // Byte 6 bits 4-5
+}
+
+impl BmsBatteryStatus {
+    pub fn decode(data: &[u8]) -> Self {
+        let faults = byte(data, 5);
+        let status = byte(data, 6);
+        Self {
+            cell_voltage_fault: (faults & 0x03) != 0x00,
+            soc_fault: ((faults >> 2) & 0x03) != 0x00,
+            over_current: field(faults, 4),
+            over_temperature: field(faults, 6),
+            insulation_fault: field(status, 0),
+            connector_fault: field(status, 2),
+            charge_permitted: field(status, 4),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[5] = self.cell_voltage_fault as u8
+            | (self.soc_fault as u8) << 2
+            | (self.over_current as u8) << 4
+            | (self.over_temperature as u8) << 6;
+        payload[6] = self.insulation_fault as u8
+            | (self.connector_fault as u8) << 2
+            | (self.charge_permitted as u8) << 4;
+        payload
+    }
+
+    pub fn fault(&self) -> Option<&'static str> {
+        if self.cell_voltage_fault {
+            Some("Cell Volt")
+        } else if self.soc_fault {
+            Some("SoC")
+        } else if self.over_current {
+            Some("Over Curr")
+        } else if self.over_temperature {
+            Some("Over Temp")
+        } else if self.insulation_fault {
+            Some("Insulation")
+        } else if self.connector_fault {
+            Some("Connector")
+        } else {
+            None
+        }
+    }
+}
+
+// BST / CST - Stop, and why. Byte 0 is the reason (a two bit field each), bytes 1-2 faults and
+// byte 3 errors, which the charger doesn't break down.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stop {
+    pub reason: u8,  // Byte 0
+    pub faults: u16, // Byte 1-2
+    pub errors: u8,  // Byte 3
+}
+
+// BST byte 0
+pub const BST_SOC_REACHED: u8 = 0x01;
+pub const BST_VOLTAGE_REACHED: u8 = 0x04;
+pub const BST_CELL_VOLTAGE_REACHED: u8 = 0x10;
+pub const BST_CHARGER_STOPPED: u8 = 0x40;
+
+// CST byte 0
+pub const CST_CONDITION_REACHED: u8 = 0x01;
+pub const CST_MANUAL: u8 = 0x04;
+pub const CST_FAULT: u8 = 0x10;
+pub const CST_BMS_STOPPED: u8 = 0x40;
+
+impl Stop {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            reason: byte(data, 0),
+            faults: word(data, 1),
+            errors: byte(data, 3),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.reason;
+        put_word(&mut payload, 1, self.faults);
+        payload[3] = self.errors;
+        payload
+    }
+}
+
+// BSD - BMS statistics at the end of charging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BmsStatistics {
+    pub state_of_charge: u8,   // Byte 0, %
+    pub min_cell_voltage: u16, // Byte 1-2, 0.01 V
+    pub max_cell_voltage: u16, // Byte 3-4, 0.01 V
+}
+
+impl BmsStatistics {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            state_of_charge: byte(data, 0),
+            min_cell_voltage: word(data, 1),
+            max_cell_voltage: word(data, 3),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        payload[0] = self.state_of_charge;
+        put_word(&mut payload, 1, self.min_cell_voltage);
+        put_word(&mut payload, 3, self.max_cell_voltage);
+        payload
+    }
+}
+
+// CSD - Charger statistics at the end of charging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ChargerStatistics {
+    pub charge_time: u16,    // Byte 0-1, min
+    pub energy: u16,         // Byte 2-3, 0.1 kWh
+    pub charger_number: u32, // Byte 4-7
+}
+
+impl ChargerStatistics {
+    pub fn decode(data: &[u8]) -> Self {
+        Self {
+            charge_time: word(data, 0),
+            energy: word(data, 2),
+            charger_number: word(data, 6) as u32 * 0x10000 + word(data, 4) as u32,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        put_word(&mut payload, 0, self.charge_time);
+        put_word(&mut payload, 2, self.energy);
+        put_word(&mut payload, 4, self.charger_number as u16);
+        put_word(&mut payload, 6, (self.charger_number >> 16) as u16);
+        payload
+    }
+}
+
+// CEM - Charger error: which BMS message timed out. BEM is the same layout the other way round.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimeoutEnum {
+    Brm, // Byte 0 bits 0-1
+    Bcp, // Byte 1 bits 0-1
+    Bro, // Byte 1 bits 2-3
+    Bcs, // Byte 2 bits 0-1
+    Bcl, // Byte 2 bits 2-3
+    Bst, // Byte 2 bits 4-5
+    Bsd, // Byte 3 bits 0-1
+}
+
+impl TimeoutEnum {
+    pub fn encode(&self) -> [u8; FRAME_LENGTH] {
+        let mut payload = [0u8; FRAME_LENGTH];
+        let (index, shift) = match self {
+            TimeoutEnum::Brm => (0, 0),
+            TimeoutEnum::Bcp => (1, 0),
+            TimeoutEnum::Bro => (1, 2),
+            TimeoutEnum::Bcs => (2, 0),
+            TimeoutEnum::Bcl => (2, 2),
+            TimeoutEnum::Bst => (2, 4),
+            TimeoutEnum::Bsd => (3, 0),
+        };
+        payload[index] = 0x01 << shift;
+        payload
+    }
+}
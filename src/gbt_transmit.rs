@@ -0,0 +1,135 @@
+#![deny(warnings)]
+// What the charger sends on a GB/T session, by charge state. Unlike CHAdeMO's 100 ms pair of
+// frames, each message has its own period, so this runs every pass of the main loop with a
+// timestamp per period. Transport protocol replies (CTS / end of message ack) go out as soon as
+// process_gbt has queued them.
+use crate::gbt::*;
+use crate::interfaces::{CanBus, CanMessage};
+use crate::types::*;
+
+// Charger number sent in CRM and CSD.
+const CHARGER_NUMBER: u32 = 1;
+
+pub fn init<C: CanBus>(elapsed: u32, cd_state: &mut CDState, fc_can: &mut C) {
+    if let Some(reply) = cd_state.gbt.transport.reply.take() {
+        transmit(fc_can, TP_CM_PGN, &reply);
+    }
+
+    let fast = due(elapsed, &mut cd_state.gbt.fast_ts, GBT_FAST_PERIOD_MS);
+    let status = due(elapsed, &mut cd_state.gbt.status_ts, GBT_STATUS_PERIOD_MS);
+    let slow = due(elapsed, &mut cd_state.gbt.slow_ts, GBT_SLOW_PERIOD_MS);
+    let gbt = &cd_state.gbt;
+
+    match cd_state.charge_state {
+        // Handshake, until insulation is checked.
+        ChargeStateEnum::WaitForComms
+        | ChargeStateEnum::WaitChargeEnable
+        | ChargeStateEnum::InsulationTest
+            if slow =>
+        {
+            let handshake = ChargerHandshake {
+                protocol_version: PROTOCOL_VERSION,
+            };
+            transmit(fc_can, CHM_PGN, &handshake.encode());
+        }
+        // Recognition, then limits once the BMS has sent its parameters, then ready.
+        ChargeStateEnum::WaitVehicleChargeStart if slow => {
+            if !gbt.bcp_received {
+                let recognition = ChargerRecognition {
+                    recognized: gbt.brm_received,
+                    charger_number: CHARGER_NUMBER,
+                };
+                transmit(fc_can, CRM_PGN, &recognition.encode());
+            } else if !gbt.bms_ready {
+                transmit(fc_can, CML_PGN, &limits(cd_state).encode());
+            } else {
+                transmit(fc_can, CRO_PGN, &Ready { ready: true }.encode());
+            }
+        }
+        ChargeStateEnum::ChargeLoop if status => {
+            let charger_status = ChargerStatus {
+                output_voltage: cd_state.current_voltage * 10,
+                output_current: cd_state.present_current * 10,
+                charge_time: (elapsed.wrapping_sub(cd_state.charge_start_ts) / 60_000) as u16,
+                permitted: true,
+            };
+            transmit(fc_can, CCS_PGN, &charger_status.encode());
+        }
+        // Stop until the BMS has sent its statistics, then ours.
+        ChargeStateEnum::WeldCheck | ChargeStateEnum::StopCharge | ChargeStateEnum::Discharge => {
+            if gbt.bsd_received && slow {
+                transmit(fc_can, CSD_PGN, &statistics(cd_state).encode());
+            } else if !gbt.bsd_received && fast {
+                let stop = Stop {
+                    reason: stop_reason(cd_state),
+                    faults: 0,
+                    errors: 0,
+                };
+                transmit(fc_can, CST_PGN, &stop.encode());
+            }
+        }
+        ChargeStateEnum::TimeOut if slow => {
+            transmit(fc_can, CEM_PGN, &timeout(gbt).encode());
+        }
+        _ => {}
+    }
+}
+
+// True once every period, moving the timestamp on.
+fn due(elapsed: u32, timestamp: &mut u32, period: u32) -> bool {
+    if elapsed.wrapping_sub(*timestamp) >= period {
+        *timestamp = elapsed;
+        true
+    } else {
+        false
+    }
+}
+
+fn transmit<C: CanBus>(fc_can: &mut C, pgn: u32, payload: &[u8; FRAME_LENGTH]) {
+    let id = frame_id(pgn, BMS_ADDRESS, CHARGER_ADDRESS);
+    fc_can.send_frame(&CanMessage::new_extended(id, payload));
+}
+
+pub fn limits(cd_state: &CDState) -> ChargerLimits {
+    let config = &cd_state.charger_config;
+    ChargerLimits {
+        max_voltage: config.rated_voltage * 10,
+        min_voltage: 0,
+        max_current: cd_state.available_current * 10,
+        min_current: 0,
+    }
+}
+
+// Energy isn't metered, CSD reports none.
+pub fn statistics(cd_state: &CDState) -> ChargerStatistics {
+    ChargerStatistics {
+        charge_time: (cd_state.session_summary.charge_time / 60) as u16,
+        energy: 0,
+        charger_number: CHARGER_NUMBER,
+    }
+}
+
+pub fn stop_reason(cd_state: &CDState) -> u8 {
+    if cd_state.gbt.bst_received {
+        CST_BMS_STOPPED
+    } else if cd_state.fault_line || cd_state.lock_fault || cd_state.discharge_fault {
+        CST_FAULT
+    } else {
+        CST_MANUAL
+    }
+}
+
+// The first BMS message the session was still waiting for.
+pub fn timeout(gbt: &GbtState) -> TimeoutEnum {
+    if !gbt.brm_received {
+        TimeoutEnum::Brm
+    } else if !gbt.bcp_received {
+        TimeoutEnum::Bcp
+    } else if !gbt.bms_ready {
+        TimeoutEnum::Bro
+    } else if !gbt.bst_received {
+        TimeoutEnum::Bcl
+    } else {
+        TimeoutEnum::Bsd
+    }
+}
@@ -0,0 +1,142 @@
+#![deny(warnings)]
+// J1939 transport protocol, receive side, for the GB/T messages longer than a frame (BRM, BCP,
+// BCS). The BMS announces the message with TP.CM RTS (or BAM), then sends it seven bytes at a
+// time in numbered TP.DT frames. The charger answers an RTS with a CTS for the whole message and
+// acknowledges the last packet. The charger's own messages all fit in one frame.
+use crate::gbt::FRAME_LENGTH;
+
+pub const MAX_MESSAGE_LENGTH: usize = 56;
+const PACKET_LENGTH: usize = 7;
+
+// TP.CM control bytes
+pub const TP_RTS: u8 = 0x10;
+pub const TP_CTS: u8 = 0x11;
+pub const TP_END_OF_MESSAGE_ACK: u8 = 0x13;
+pub const TP_BAM: u8 = 0x20;
+pub const TP_ABORT: u8 = 0xFF;
+
+#[derive(Clone, Copy)]
+pub struct TransportReceiver {
+    pub pgn: u32,
+    pub size: usize,
+    pub packets: u8,
+    pub next_packet: u8,
+    pub acknowledge: bool, // RTS / CTS, as opposed to BAM
+    pub data: [u8; MAX_MESSAGE_LENGTH],
+    pub reply: Option<[u8; FRAME_LENGTH]>, // TP.CM to send back
+}
+
+impl Default for TransportReceiver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn control(command: u8, first: u16, second: u8, third: u8, pgn: u32) -> [u8; FRAME_LENGTH] {
+    let [size_low, size_high] = first.to_le_bytes();
+    let [pgn_low, pgn_mid, pgn_high, _] = pgn.to_le_bytes();
+    [
+        command, size_low, size_high, second, third, pgn_low, pgn_mid, pgn_high,
+    ]
+}
+
+impl TransportReceiver {
+    pub fn new() -> Self {
+        Self {
+            pgn: 0,
+            size: 0,
+            packets: 0,
+            next_packet: 0,
+            acknowledge: false,
+            data: [0u8; MAX_MESSAGE_LENGTH],
+            reply: None,
+        }
+    }
+
+    // TP.CM from the BMS. A new RTS or BAM replaces a transfer in progress.
+    pub fn receive_cm(&mut self, data: &[u8]) {
+        if data.len() < FRAME_LENGTH {
+            return;
+        }
+        let size = u16::from_le_bytes([data[1], data[2]]) as usize;
+        let pgn = u32::from_le_bytes([data[5], data[6], data[7], 0]);
+        match data[0] {
+            TP_RTS | TP_BAM => {
+                let acknowledge = data[0] == TP_RTS;
+                let packets = data[3];
+                if size > MAX_MESSAGE_LENGTH {
+                    self.next_packet = 0;
+                    if acknowledge {
+                        // Reason 1: already in one or more connections / no resources.
+                        self.reply = Some(control(TP_ABORT, 0xFF01, 0xFF, 0xFF, pgn));
+                    }
+                    return;
+                }
+                // A message that would fit in one frame, or a packet count that doesn't match.
+                if size <= FRAME_LENGTH || packets as usize != size.div_ceil(PACKET_LENGTH) {
+                    self.next_packet = 0;
+                    if acknowledge {
+                        // Reason 250: none of the others.
+                        self.reply = Some(control(TP_ABORT, 0xFFFA, 0xFF, 0xFF, pgn));
+                    }
+                    return;
+                }
+                self.pgn = pgn;
+                self.size = size;
+                self.packets = packets;
+                self.next_packet = 1;
+                self.acknowledge = acknowledge;
+                if acknowledge {
+                    // All packets in one go, starting at 1.
+                    self.reply = Some(control(
+                        TP_CTS,
+                        self.packets as u16 | 0x0100,
+                        0xFF,
+                        0xFF,
+                        pgn,
+                    ));
+                }
+            }
+            TP_ABORT => self.next_packet = 0,
+            _ => {}
+        }
+    }
+
+    // TP.DT from the BMS. The PGN of the message when it is complete, which is then in data.
+    // Packets out of order drop the transfer.
+    pub fn receive_dt(&mut self, data: &[u8]) -> Option<u32> {
+        if self.next_packet == 0 || data.is_empty() {
+            return None;
+        }
+        if data[0] != self.next_packet {
+            self.next_packet = 0;
+            return None;
+        }
+        let start = (self.next_packet as usize - 1) * PACKET_LENGTH;
+        let end = (start + PACKET_LENGTH).min(self.size);
+        let length = end.saturating_sub(start).min(data.len() - 1);
+        if let Some(packet) = self.data.get_mut(start..start + length) {
+            packet.copy_from_slice(&data[1..1 + length]);
+        }
+
+        if self.next_packet < self.packets {
+            self.next_packet += 1;
+            return None;
+        }
+        self.next_packet = 0;
+        if self.acknowledge {
+            self.reply = Some(control(
+                TP_END_OF_MESSAGE_ACK,
+                self.size as u16,
+                self.packets,
+                0xFF,
+                self.pgn,
+            ));
+        }
+        Some(self.pgn)
+    }
+
+    pub fn message(&self) -> &[u8] {
+        &self.data[..self.size]
+    }
+}
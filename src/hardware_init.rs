@@ -13,7 +13,7 @@ use hal::serial::config::Config;
 
 use cortex_m::peripheral::NVIC;
 use hal::{
-    interrupt, pac,
+    pac,
     prelude::*,
     serial::Serial,
     timer::{Event, Timer},
@@ -30,20 +30,29 @@ use hal::can::CanBitTiming;
 use hal::can::CanConfig;
 use hal::can::CanFilterConfig;
 
-use crate::types::*;
+use crate::board::*;
+
+// A message pending or an overrun on either receive FIFO. The charger firmware's tasks are bound
+// to the interrupts, which RTIC unmasks. The vehicle firmware still polls.
+pub fn listen_can_rx() {
+    let can1 = unsafe { &*pac::CAN1::ptr() };
+    can1.ier.modify(|_, w| {
+        w.fmpie0()
+            .set_bit()
+            .fovie0()
+            .set_bit()
+            .fmpie1()
+            .set_bit()
+            .fovie1()
+            .set_bit()
+    });
+}
 
-pub fn enable_interrupts() {
+// TIM2 only, for the vehicle firmware, which runs without RTIC.
+pub fn enable_timer_interrupt() {
     unsafe {
         NVIC::unmask(pac::Interrupt::TIM2);
     }
-    #[cfg(feature = "nucleof767zi")]
-    unsafe {
-        NVIC::unmask::<interrupt>(interrupt::EXTI2);
-    }
-    #[cfg(feature = "nucleof446re")]
-    unsafe {
-        NVIC::unmask::<interrupt>(interrupt::EXTI3);
-    }
 }
 
 #[cfg(feature = "nucleof767zi")]
@@ -51,6 +60,9 @@ pub fn init_devices() -> (
     FaultLinePin,
     RelayOnePin,
     RelayTwoPin,
+    LockActuator,
+    SequenceLineInputs,
+    D2Input,
     FCCAN,
     hal::serial::Serial<
         hal::pac::USART3,
@@ -65,6 +77,9 @@ pub fn init_devices() -> (
     // Hardware to initialize:
     // Fault Input
     // Latch Output
+    // Connector lock
+    // j and proximity inputs
+    // d2 input (vehicle firmware)
     // CAN Tx, Rx
     // Serial port
     // TIM2
@@ -76,7 +91,7 @@ pub fn init_devices() -> (
     let gpiod = p.GPIOD.split();
 
     // GPIO G for Fault and Latch I/O (PG2 for Fault (Read), and PG3 for Latch (Push-Pull High
-    // output)).
+    // output)). PG0 drives the connector lock, PG1 reads its position switch.
     let gpiog = p.GPIOG.split();
 
     let mut fault_in = gpiog.pg2.into_floating_input();
@@ -85,6 +100,23 @@ pub fn init_devices() -> (
     relay_1.set_high().ok();
     let mut relay_2 = gpiod.pd2.into_push_pull_output();
     relay_2.set_high().ok();
+    let mut lock_drive = gpiog.pg0.into_push_pull_output();
+    lock_drive.set_low().ok();
+    let lock = LockActuator {
+        drive: lock_drive,
+        feedback: gpiog.pg1.into_pull_down_input(),
+    };
+
+    // GPIO E for the vehicle's j line (PE0) and connector proximity (PE1), active low.
+    let gpioe = p.GPIOE.split();
+    let sequence = SequenceLineInputs {
+        charge_permission: gpioe.pe0.into_pull_up_input(),
+        proximity: gpioe.pe1.into_pull_up_input(),
+    };
+    // PE2 reads the charger's d2 when the board is the vehicle, active low.
+    let d2_in = D2Input {
+        pin: gpioe.pe2.into_pull_up_input(),
+    };
 
     // Set trigger and enable interrupt.
     fault_in.trigger_on_edge(&mut exti, Edge::RISING_FALLING);
@@ -128,10 +160,13 @@ pub fn init_devices() -> (
     // (45MHz APB1)
     // CAN_BTR: 0x001e0004
     const BIT_TIMING: CanBitTiming = CanBitTiming {
+        #[cfg(not(feature = "gbt"))]
         prescaler: 4, // Prescaler: 5
-        sjw: 0,       // CAN_SJW_1TQ
-        bs1: 14,      // CAN_BS1_15TQ
-        bs2: 1,       // CAN_BS2_2TQ
+        #[cfg(feature = "gbt")]
+        prescaler: 9, // Prescaler: 10, 250K for GB/T
+        sjw: 0,  // CAN_SJW_1TQ
+        bs1: 14, // CAN_BS1_15TQ
+        bs2: 1,  // CAN_BS2_2TQ
     };
 
     pub const HV_CAN_CONFIG: CanConfig = CanConfig {
@@ -144,7 +179,7 @@ pub fn init_devices() -> (
         rflm: false,
         txfp: false,
         // TODO - update CAN impl to calculate these
-        // HV CAN bus is configured for 500K
+        // HV CAN bus is configured for 500K (250K with the gbt feature)
         bit_timing: BIT_TIMING,
     };
 
@@ -167,7 +202,9 @@ pub fn init_devices() -> (
         &mut p.PWR,
     );
 
-    return (fault_in, relay_1, relay_2, fc_can, serial, timer, rtc);
+    return (
+        fault_in, relay_1, relay_2, lock, sequence, d2_in, fc_can, serial, timer, rtc,
+    );
 }
 
 #[cfg(feature = "nucleof446re")]
@@ -175,6 +212,9 @@ pub fn init_devices() -> (
     FaultLinePin,
     RelayOnePin,
     RelayTwoPin,
+    LockActuator,
+    SequenceLineInputs,
+    D2Input,
     FCCAN,
     hal::serial::Serial<
         hal::stm32::USART2,
@@ -189,6 +229,9 @@ pub fn init_devices() -> (
     // Hardware to initialize:
     // Fault Input
     // Latch Output
+    // Connector lock
+    // j and proximity inputs
+    // d2 input (vehicle firmware)
     // CAN Tx, Rx
     // Serial port
     // TIM2
@@ -208,6 +251,26 @@ pub fn init_devices() -> (
     let mut relay_2 = gpiob.pb6.into_push_pull_output();
     relay_2.set_high().ok();
 
+    // PC0 drives the connector lock, PC1 reads its position switch.
+    let gpioc = p.GPIOC.split();
+    let mut lock_drive = gpioc.pc0.into_push_pull_output();
+    lock_drive.set_low().ok();
+    let lock = LockActuator {
+        drive: lock_drive,
+        feedback: gpioc.pc1.into_pull_down_input(),
+    };
+
+    // PA0 reads the vehicle's j line, PA1 connector proximity, both active low.
+    let gpioa = p.GPIOA.split();
+    let sequence = SequenceLineInputs {
+        charge_permission: gpioa.pa0.into_pull_up_input(),
+        proximity: gpioa.pa1.into_pull_up_input(),
+    };
+    // PA4 reads the charger's d2 when the board is the vehicle, active low.
+    let d2_in = D2Input {
+        pin: gpioa.pa4.into_pull_up_input(),
+    };
+
     // Set trigger and enable interrupt
     fault_in.trigger_on_edge(&mut exti, Edge::RISING_FALLING);
     fault_in.enable_interrupt(&mut exti);
@@ -217,7 +280,6 @@ pub fn init_devices() -> (
     #[cfg(feature = "nucleof446re")]
     let clocks = rcc.cfgr.use_hse(8.mhz()).sysclk(180.mhz()).freeze();
 
-    let gpioa = p.GPIOA.split();
     let tx_pin = gpioa.pa2.into_alternate_af7();
     let rx_pin = gpioa.pa3.into_alternate_af7();
     let serial = Serial::usart2(
@@ -239,10 +301,13 @@ pub fn init_devices() -> (
     // CAN_BTR: 0x001e0004
     #[cfg(feature = "nucleof446re")]
     const BIT_TIMING: CanBitTiming = CanBitTiming {
+        #[cfg(not(feature = "gbt"))]
         prescaler: 4, // Prescaler: 5
-        sjw: 0,       // CAN_SJW_1TQ
-        bs1: 14,      // CAN_BS1_15TQ
-        bs2: 1,       // CAN_BS2_2TQ
+        #[cfg(feature = "gbt")]
+        prescaler: 9, // Prescaler: 10, 250K for GB/T
+        sjw: 0,  // CAN_SJW_1TQ
+        bs1: 14, // CAN_BS1_15TQ
+        bs2: 1,  // CAN_BS2_2TQ
     };
 
     pub const HV_CAN_CONFIG: CanConfig = CanConfig {
@@ -255,7 +320,7 @@ pub fn init_devices() -> (
         rflm: false,
         txfp: false,
         // TODO - update CAN impl to calculate these
-        // HV CAN bus is configured for 500K
+        // HV CAN bus is configured for 500K (250K with the gbt feature)
         bit_timing: BIT_TIMING,
     };
 
@@ -280,5 +345,7 @@ pub fn init_devices() -> (
         &mut p.PWR,
     );
 
-    return (fault_in, relay_1, relay_2, fc_can, serial, timer, rtc);
+    return (
+        fault_in, relay_1, relay_2, lock, sequence, d2_in, fc_can, serial, timer, rtc,
+    );
 }
@@ -0,0 +1,112 @@
+#![deny(warnings)]
+// Linux host implementations of the hardware interfaces: SocketCAN (vcan0 or a real adapter)
+// for the charge bus, stdout for the console, logged state changes for relays and the system
+// clock for elapsed ms.
+extern crate std;
+
+use crate::interfaces::{CanBus, CanMessage, Clock, Relay};
+use socketcan::{CANFrame, CANSocket, CANSocketOpenError, SFF_MASK};
+use std::io::Write;
+use std::time::Instant;
+use std::{eprintln, io};
+
+pub struct SocketCan {
+    socket: CANSocket,
+}
+
+impl SocketCan {
+    // Non-blocking, so receive_frame can be polled like the hardware FIFOs.
+    pub fn open(interface: &str) -> Result<Self, CANSocketOpenError> {
+        let socket = CANSocket::open(interface)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket })
+    }
+}
+
+impl CanBus for SocketCan {
+    // socketcan sets the extended flag from the id, for anything above 0x7FF, and won't take
+    // it any other way. GB/T ids are all above that. A frame whose flag and id disagree would go
+    // out with the wrong id, so it isn't sent.
+    fn send_frame(&mut self, message: &CanMessage) -> bool {
+        if message.extended != (message.id > SFF_MASK) {
+            return false;
+        }
+        match CANFrame::new(message.id, message.data(), false, false) {
+            Ok(frame) => self.socket.write_frame(&frame).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn receive_frame(&mut self) -> Option<CanMessage> {
+        while let Ok(frame) = self.socket.read_frame() {
+            if frame.is_rtr() || frame.is_error() {
+                continue;
+            }
+            if frame.is_extended() {
+                return Some(CanMessage::new_extended(frame.id(), frame.data()));
+            }
+            return Some(CanMessage::new(frame.id(), frame.data()));
+        }
+        None
+    }
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed_ms(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+}
+
+// Prints open / close transitions to stderr, so they don't get mixed up with console redraws.
+pub struct LoggedRelay {
+    name: &'static str,
+    closed: bool,
+}
+
+impl LoggedRelay {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            closed: false,
+        }
+    }
+}
+
+impl Relay for LoggedRelay {
+    fn set_closed(&mut self, closed: bool) {
+        if closed != self.closed {
+            self.closed = closed;
+            eprintln!("{}: {}", self.name, if closed { "closed" } else { "open" });
+        }
+    }
+}
+
+pub struct StdoutSink;
+
+impl core::fmt::Write for StdoutSink {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let mut stdout = io::stdout();
+        stdout
+            .write_all(s.as_bytes())
+            .and_then(|_| stdout.flush())
+            .map_err(|_| core::fmt::Error)
+    }
+}
@@ -0,0 +1,118 @@
+#![deny(warnings)]
+// Insulation (isolation) test, between WaitChargeEnable and WaitVehicleChargeStart while the
+// vehicle's contactors are still open. The output is raised to the test voltage, the isolation
+// resistance is checked against insulation_ohms_per_volt, and the output is discharged to
+// INSULATION_SAFE_VOLTAGE before switch_two tells the vehicle to go ahead. With insulation_test off
+// in the charger config there is no test, switch_two goes on straight away.
+use crate::add_to_activity_list;
+use crate::interfaces::{InsulationMonitor, PowerStage};
+use crate::types::*;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// The vehicle's maximum battery voltage, or the charger's threshold if it hasn't sent one.
+pub fn test_voltage(cd_state: &CDState, car_state: &CarState) -> u16 {
+    let config = &cd_state.charger_config;
+    let battery_max = car_state.battery_max_voltage as u16;
+    if battery_max > 0 {
+        battery_max.min(config.rated_voltage)
+    } else {
+        config.threshold_voltage.min(config.rated_voltage)
+    }
+}
+
+// Run every pass of the main loop.
+pub fn init<P: PowerStage, M: InsulationMonitor>(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &CarState,
+    power_stage: &mut P,
+    monitor: &mut M,
+) {
+    if cd_state.charge_state != ChargeStateEnum::InsulationTest {
+        return;
+    }
+
+    // Nothing to test with, d2 goes on at once and the activity list says so.
+    if !cd_state.charger_config.insulation_test {
+        cd_state.charge_state = ChargeStateEnum::WaitVehicleChargeStart;
+        cd_state.switch_two = true;
+        add_to_activity_list!(
+            cd_state,
+            "{} - No insulation test -> WaitVehicleChargeStart",
+            elapsed
+        );
+        return;
+    }
+
+    let test_voltage = test_voltage(cd_state, car_state);
+    let phase_time = elapsed.wrapping_sub(cd_state.insulation_ts);
+    let measured = power_stage.measured_voltage();
+    cd_state.current_voltage = measured;
+
+    match cd_state.insulation_phase {
+        InsulationPhaseEnum::Rise => {
+            power_stage.set_discharge(false);
+            power_stage.set_output(true, test_voltage, INSULATION_TEST_CURRENT);
+            // Within 10%.
+            if measured as u32 * 10 >= test_voltage as u32 * 9 {
+                cd_state.insulation_phase = InsulationPhaseEnum::Measure;
+                cd_state.insulation_ts = elapsed;
+            } else if phase_time > INSULATION_RISE_TIMEOUT_MS {
+                fail(elapsed, cd_state, power_stage, "No Test Volt");
+            }
+        }
+        InsulationPhaseEnum::Measure => {
+            if phase_time < INSULATION_MEASURE_MS {
+                return;
+            }
+            let resistance = monitor.resistance_ohms();
+            cd_state.insulation_resistance = resistance;
+            if resistance < cd_state.charger_config.insulation_ohms_per_volt * test_voltage as u32 {
+                fail(elapsed, cd_state, power_stage, "Low Resistance");
+                return;
+            }
+            add_to_activity_list!(
+                cd_state,
+                "{} - Insulation OK, {} kOhm",
+                elapsed,
+                resistance / 1000
+            );
+            power_stage.set_output(false, 0, 0);
+            power_stage.set_discharge(true);
+            cd_state.insulation_phase = InsulationPhaseEnum::Discharge;
+            cd_state.insulation_ts = elapsed;
+        }
+        InsulationPhaseEnum::Discharge => {
+            if measured < INSULATION_SAFE_VOLTAGE {
+                power_stage.set_discharge(false);
+                cd_state.charge_state = ChargeStateEnum::WaitVehicleChargeStart;
+                cd_state.switch_two = true;
+                add_to_activity_list!(
+                    cd_state,
+                    "{} - InsulationTest -> WaitVehicleChargeStart",
+                    elapsed
+                );
+            } else if phase_time > INSULATION_DISCHARGE_TIMEOUT_MS {
+                fail(elapsed, cd_state, power_stage, "Discharge");
+            }
+        }
+    }
+}
+
+// Output off and discharging, the session is stopped on the next vehicle frame.
+fn fail<P: PowerStage>(elapsed: u32, cd_state: &mut CDState, power_stage: &mut P, reason: &str) {
+    power_stage.set_output(false, 0, 0);
+    power_stage.set_discharge(true);
+    cd_state.insulation_fault = true;
+    cd_state.charge_state = ChargeStateEnum::StopCharge;
+    add_to_activity_list!(
+        cd_state,
+        "{} - Insulation fail -> StopCharge ({})",
+        elapsed,
+        reason
+    );
+}
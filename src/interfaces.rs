@@ -0,0 +1,124 @@
+#![deny(warnings)]
+// Hardware interfaces the charger logic is written against.
+// board.rs implements these for the stm32f4xx/stm32f7xx HAL types, mock.rs has host side
+// implementations so the logic can be built and tested without a board feature.
+use core::fmt::Write;
+
+pub const MAX_DATA_LENGTH: usize = 8;
+
+// A CAN data frame. Standard (11-bit) ids for CHAdeMO, extended (29-bit) for GB/T.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CanMessage {
+    pub id: u32,
+    pub extended: bool,
+    pub length: usize,
+    pub data: [u8; MAX_DATA_LENGTH],
+}
+
+impl CanMessage {
+    pub fn new(id: u32, data: &[u8]) -> Self {
+        let length = data.len().min(MAX_DATA_LENGTH);
+        let mut message = Self {
+            id,
+            extended: false,
+            length,
+            data: [0u8; MAX_DATA_LENGTH],
+        };
+        message.data[..length].copy_from_slice(&data[..length]);
+        message
+    }
+
+    pub fn new_extended(id: u32, data: &[u8]) -> Self {
+        Self {
+            extended: true,
+            ..Self::new(id, data)
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.length]
+    }
+}
+
+// A received frame and when it arrived (ms).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimedFrame {
+    pub message: CanMessage,
+    pub timestamp: u32,
+}
+
+// Frames lost on the way in, since startup. Overruns are frames the controller's FIFOs had no
+// room for, dropped ones were received but didn't fit in the receive queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CanRxCounters {
+    pub overruns: u32,
+    pub dropped: u32,
+}
+
+// CAN transmit / receive. Remote frames are not passed through.
+pub trait CanBus {
+    // True when the frame was queued for transmission.
+    fn send_frame(&mut self, message: &CanMessage) -> bool;
+    fn receive_frame(&mut self) -> Option<CanMessage>;
+
+    // Buses that are only polled can't say when a frame arrived, so it arrived now.
+    fn receive_timed(&mut self, now: u32) -> Option<TimedFrame> {
+        self.receive_frame().map(|message| TimedFrame {
+            message,
+            timestamp: now,
+        })
+    }
+
+    fn rx_counters(&self) -> CanRxCounters {
+        CanRxCounters::default()
+    }
+}
+
+// A relay (or any other on/off output). Closed means the contact is made.
+pub trait Relay {
+    fn set_closed(&mut self, closed: bool);
+}
+
+// Where console text goes. Anything that implements core::fmt::Write will do.
+pub trait TextSink: Write {}
+
+impl<T: Write> TextSink for T {}
+
+// DC output stage. Regulates to the current setpoint (A) without letting the output go above
+// the voltage setpoint (V). Disabled means no output at all. The discharge circuit pulls the
+// output capacitance down once disabled. Reverse output (V2H) draws up to the current setpoint
+// from the vehicle's battery instead.
+pub trait PowerStage {
+    fn set_output(&mut self, enabled: bool, voltage: u16, current: u16);
+    fn set_reverse_output(&mut self, enabled: bool, current: u8);
+    fn set_discharge(&mut self, on: bool);
+    fn measured_voltage(&self) -> u16;
+    fn measured_current(&self) -> u16;
+    fn measured_reverse_current(&self) -> u8;
+}
+
+// Isolation resistance between the output and protective earth, in ohms.
+pub trait InsulationMonitor {
+    fn resistance_ohms(&mut self) -> u32;
+}
+
+// Sequence lines from the vehicle. d1 and d2, from the charger, are Relays.
+pub trait SequenceInputs {
+    // j: the vehicle permits charging.
+    fn charge_permission(&self) -> bool;
+    // Connector proximity: the plug is in the vehicle inlet.
+    fn connector_detected(&self) -> bool;
+}
+
+// Connector lock actuator (solenoid or motor) and its position switch.
+pub trait ConnectorLock {
+    fn set_locked(&mut self, locked: bool);
+    // True when the feedback says the lock is engaged.
+    fn is_locked(&self) -> bool;
+}
+
+// Monotonic millisecond clock, wraps at u32::MAX (about 49.7 days). Time since a timestamp is
+// always elapsed.wrapping_sub(ts), which stays right across the wrap.
+pub trait Clock {
+    fn elapsed_ms(&self) -> u32;
+}
@@ -1,11 +1,37 @@
 #![no_std]
 
+#[cfg(any(feature = "nucleof446re", feature = "nucleof767zi"))]
+pub mod board;
 pub mod can_receive_logic;
+pub mod can_rx_queue;
+pub mod chademo;
+pub mod chademo_transmit;
+pub mod comm_watchdog;
+pub mod connector_lock;
+pub mod control_loop;
+pub mod discharge;
+pub mod gbt;
+pub mod gbt_transmit;
+pub mod gbt_transport;
+#[cfg(any(feature = "nucleof446re", feature = "nucleof767zi"))]
 pub mod hardware_init;
+#[cfg(feature = "host")]
+pub mod host;
 pub mod hundred_ms_loop;
+pub mod insulation_test;
+pub mod interfaces;
 pub mod macros;
+pub mod main_loop;
+#[cfg(any(test, feature = "host", feature = "simulated-io"))]
+pub mod mock;
 pub mod process_cd;
+pub mod process_gbt;
 pub mod process_serial;
 pub mod serial_console;
+pub mod serial_tx_queue;
+pub mod session;
 pub mod types;
 pub mod utils;
+pub mod vehicle;
+pub mod vehicle_console;
+pub mod weld_check;
@@ -6,9 +6,6 @@
 extern crate cortex_m;
 extern crate panic_halt;
 
-// Entrypoint
-use cortex_m_rt::entry;
-
 #[cfg(feature = "nucleof767zi")]
 extern crate stm32f7xx_hal as hal;
 
@@ -17,7 +14,7 @@ extern crate stm32f4xx_hal as hal;
 
 // General HAL items
 use hal::{
-    interrupt, pac,
+    pac,
     prelude::*,
     timer::{Event, Timer},
 };
@@ -26,190 +23,260 @@ use hal::{
 // Used to clear the pending interrupt bit in the interrupt handler.
 use hal::gpio::ExtiPin;
 
-// Elapsed_MS stuff...
-use core::cell::{Cell, RefCell};
-use core::ops::DerefMut;
-use cortex_m::interrupt::{free, Mutex};
-
-// CAN
-use hal::can::RxFifo;
+use rtic::Mutex;
 
 // Aliases
-use can_dc_fc::can_receive_logic::init as can_receive_logic;
-use can_dc_fc::hundred_ms_loop::init as hundred_ms_loop;
-use can_dc_fc::process_serial::init as process_serial;
-use can_dc_fc::serial_console::display as serial_console;
-use can_dc_fc::types::*;
-
-// Random Rust notes:
-// fn main() { needs to be fn main() -> ! to show it will never return.
-// fn main() { << Standard exampel
-// References to |cs| refer to critical (non-preemptible) sections
-
-// Dragons ahead.
-static ELAPSED_MS: Mutex<Cell<u32>> = Mutex::new(Cell::new(0u32));
-static TIMER_TIM2: Mutex<RefCell<Option<Timer<pac::TIM2>>>> = Mutex::new(RefCell::new(None));
-
-// Another Semaphore / Mutex for use with the Fault Line input
-static SEMAPHORE: Mutex<Cell<bool>> = Mutex::new(Cell::new(true));
-static FAULT_LINE: Mutex<RefCell<Option<FaultLinePin>>> = Mutex::new(RefCell::new(None));
-
-#[entry]
-fn main() -> ! {
-    // Hardware to initialize:
-    // Relay One Output
-    // Relay Two Output
-    // Fast Charge CAN Tx, Rx
-    // Clocks
-    // Serial port
-    // RTC (No alarms yet)
-    // TIM2 SysTick
-
-    let (fault_in, mut relay_1, mut relay_2, fc_can, serial, timer, _rtc) =
-        can_dc_fc::hardware_init::init_devices();
-
-    // Interrupts / Mutexes
-    free(|cs| {
-        TIMER_TIM2.borrow(cs).replace(Some(timer));
-    });
-    free(|cs| {
-        FAULT_LINE.borrow(cs).replace(Some(fault_in));
-    });
-    can_dc_fc::hardware_init::enable_interrupts();
-
-    let (mut tx, mut rx) = serial.split();
-
-    const HUNDRED_MS: u32 = 100;
-    let mut previous_100_ms_ts = 0;
-    let mut hundred_ms_counter: u8 = 0;
-
-    // Create the status structure
-    let mut cd_state = CDState::new();
-    let mut car_state = CarState::new();
-    // Status queue things
-    // Too many of these items slows down serial console, which slows down
-    // all of the loops.
-
-    // Main control loop here.
-    // Process serial input
-    // Run X ms loops (10, 100, 1000)
-
-    loop {
-        let elapsed = free(|cs| ELAPSED_MS.borrow(cs).get());
-
-        // Highly interactive pieces:
-        // CAN reception
-        for fifo in &[RxFifo::Fifo0, RxFifo::Fifo1] {
-            if let Ok(rx_frame) = fc_can.receive(fifo) {
-                can_receive_logic(&rx_frame, elapsed, &mut cd_state, &mut car_state);
-            }
-        }
+use can_dc_fc::board::*;
+use can_dc_fc::can_rx_queue::{split, RxCounters, RxQueue, RxQueueReader, RxQueueWriter};
+use can_dc_fc::interfaces::{CanBus, CanMessage};
+use can_dc_fc::main_loop::{MainLoop, Peripherals, HUNDRED_MS};
+use can_dc_fc::serial_tx_queue::{self, TxQueue, TxQueueReader, TxQueueWriter};
+
+// Tasks, highest priority first:
+// 4 - TIM2 counts the milliseconds and starts the other tasks.
+// 3 - CAN reception and the fault line, straight from their interrupts.
+// 2 - A pass of the charger logic every ms, and the protocol's 100 ms frames.
+// 1 - The console, after each 100 ms, and the serial transmit interrupt.
+// Serial input is read in idle.
+// Nothing on the console can hold up 0x108/0x109: the charger state is only locked while the
+// console copies it, the copy is rendered into the serial transmit buffer, and the transmit
+// interrupt sends it from there.
+
+type Io = Peripherals<
+    RelayOnePin,
+    RelayTwoPin,
+    NoInsulationMonitor,
+    LockActuator,
+    NoPowerStage,
+    SequenceLineInputs,
+>;
+
+static CAN_RX_COUNTERS: RxCounters = RxCounters::new();
+
+// Transmits lock the controller away from the receive tasks, received frames come from the
+// queue instead.
+struct SharedCan<M>(M);
+
+impl<M: Mutex<T = FCCAN>> CanBus for SharedCan<M> {
+    fn send_frame(&mut self, message: &CanMessage) -> bool {
+        self.0.lock(|fc_can| fc_can.send_frame(message))
+    }
 
-        // Serial input (and some output) - BUT - only gets called when there is input!
-        if let Ok(received) = rx.read() {
-            process_serial(received, elapsed, &mut cd_state, &mut car_state);
-        }
+    fn receive_frame(&mut self) -> Option<CanMessage> {
+        None
+    }
+}
 
-        // 10 ms - Done
-        /*        if (elapsed - previous_10_ms_ts) >= TEN_MS {
-            previous_10_ms_ts = elapsed;
+#[rtic::app(device = hal::pac)]
+const APP: () = {
+    struct Resources {
+        can_rx_reader: RxQueueReader,
+        can_rx_writer: RxQueueWriter,
+        console_reader: TxQueueReader,
+        console_writer: TxQueueWriter,
+        #[init(0)]
+        elapsed: u32,
+        fault_in: FaultLinePin,
+        // Raw line level (true = high), debounced by session::update_fault_line.
+        fault_line_high: bool,
+        fc_can: FCCAN,
+        #[init(0)]
+        hundred_ms_ts: u32,
+        io: Io,
+        main_loop: MainLoop,
+        rx: SerialConsoleInput,
+        timer: Timer<pac::TIM2>,
+        tx: SerialConsoleOutput,
+    }
+
+    #[init]
+    fn init(_: init::Context) -> init::LateResources {
+        // Hardware to initialize:
+        // Relay One Output (d1)
+        // Relay Two Output (d2)
+        // Connector lock
+        // j and proximity inputs
+        // Fast Charge CAN Tx, Rx
+        // Clocks
+        // Serial port
+        // RTC (No alarms yet)
+        // TIM2 SysTick
+        let (
+            fault_in,
+            d1,
+            d2,
+            connector_lock,
+            sequence_inputs,
+            _d2_in,
+            fc_can,
+            serial,
+            timer,
+            _rtc,
+        ) = can_dc_fc::hardware_init::init_devices();
+        can_dc_fc::hardware_init::listen_can_rx();
+
+        // The EXTI handler only sees edges, so pick up the level at startup.
+        let fault_line_high = fault_in.is_high().unwrap_or(false);
+
+        let rx_queue = cortex_m::singleton!(: RxQueue = RxQueue::new()).unwrap();
+        let (can_rx_writer, can_rx_reader) = split(rx_queue, &CAN_RX_COUNTERS);
+        let tx_queue = cortex_m::singleton!(: TxQueue = TxQueue::new()).unwrap();
+        let (console_writer, console_reader) = serial_tx_queue::split(tx_queue);
+        let (tx, rx) = serial.split();
+
+        // Nothing to run the insulation test with, see NoPowerStage.
+        let mut main_loop = MainLoop::new();
+        main_loop.cd_state.charger_config.insulation_test = false;
+
+        init::LateResources {
+            can_rx_reader,
+            can_rx_writer,
+            console_reader,
+            console_writer,
+            fault_in,
+            fault_line_high,
+            fc_can,
+            io: Peripherals {
+                connector_lock,
+                insulation_monitor: NoInsulationMonitor,
+                power_stage: NoPowerStage,
+                d1,
+                d2,
+                sequence_inputs,
+            },
+            main_loop,
+            rx,
+            timer,
+            tx,
         }
-        */
-
-        // 100 ms - Done
-        if (elapsed - previous_100_ms_ts) >= HUNDRED_MS {
-            previous_100_ms_ts = elapsed;
-            hundred_ms_counter =
-                hundred_ms_loop(hundred_ms_counter, &mut cd_state, &mut car_state, &fc_can);
-            if cd_state.switch_one {
-                relay_1.set_low().ok();
-            } else {
-                relay_1.set_high().ok();
-            }
-            if cd_state.switch_one && cd_state.switch_two {
-                relay_2.set_low().ok();
-            } else {
-                relay_2.set_high().ok();
-            }
+    }
 
-            serial_console(
-                &mut tx,
-                &mut cd_state,
-                &mut car_state,
-                elapsed,
-                hundred_ms_counter,
-            );
-
-            // Once run, flip it off.
-            if cd_state.quiet_to_verbose {
-                cd_state.quiet_to_verbose = false;
-            }
-            if cd_state.print_menu_request {
-                cd_state.print_menu_request = false;
+    // Serial input, whenever nothing else is running.
+    #[idle(resources = [elapsed, main_loop, rx])]
+    fn idle(mut cx: idle::Context) -> ! {
+        loop {
+            if let Ok(received) = cx.resources.rx.read() {
+                let elapsed = cx.resources.elapsed.lock(|elapsed| *elapsed);
+                cx.resources
+                    .main_loop
+                    .lock(|main_loop| main_loop.serial_input(received, elapsed));
             }
         }
     }
-}
 
-#[interrupt]
-fn TIM2() {
-    free(|cs| {
-        if let Some(ref mut tim2) = TIMER_TIM2.borrow(cs).borrow_mut().deref_mut() {
-            tim2.clear_interrupt(Event::TimeOut);
+    #[task(binds = TIM2, priority = 4, resources = [elapsed, hundred_ms_ts, timer], spawn = [hundred_ms, pass])]
+    fn tick(cx: tick::Context) {
+        cx.resources.timer.clear_interrupt(Event::TimeOut);
+        *cx.resources.elapsed = cx.resources.elapsed.wrapping_add(1);
+        let elapsed = *cx.resources.elapsed;
+
+        // Still running from the last ms (or 100 ms), skip this one.
+        cx.spawn.pass(elapsed).ok();
+        if elapsed.wrapping_sub(*cx.resources.hundred_ms_ts) >= HUNDRED_MS {
+            *cx.resources.hundred_ms_ts = elapsed;
+            cx.spawn.hundred_ms(elapsed).ok();
         }
+    }
 
-        let cell = ELAPSED_MS.borrow(cs);
-        let val = cell.get();
-        cell.replace(val + 1);
-    });
-}
+    #[task(binds = CAN1_RX0, priority = 3, resources = [can_rx_writer, elapsed, fc_can])]
+    fn can_rx0(mut cx: can_rx0::Context) {
+        let timestamp = cx.resources.elapsed.lock(|elapsed| *elapsed);
+        receive_fifo(
+            cx.resources.fc_can,
+            &RxFifo::Fifo0,
+            timestamp,
+            cx.resources.can_rx_writer,
+        );
+    }
 
-#[cfg(feature = "nucleof767zi")]
-#[interrupt]
-fn EXTI2() {
-    // This is going to fire for all pins associated with this interrupt, which is going to be all
-    // of them ending in 2 - PA2,PB2,...PG2, etc. So avoid using any more pins with the end in 2
-    // until it is known how to differentiate between them.
-    // Answer: "using EXTI_PR you have to detect which pin generated interrupt"
-    free(|cs| {
-        match FAULT_LINE.borrow(cs).borrow_mut().as_mut() {
-            // Clear the push button interrupt
-            Some(b) => {
-                b.clear_interrupt_pending_bit();
-                if b.is_high().unwrap_or(false) {
-                    SEMAPHORE.borrow(cs).set(true);
-                } else {
-                    SEMAPHORE.borrow(cs).set(false);
-                }
-            }
+    #[task(binds = CAN1_RX1, priority = 3, resources = [can_rx_writer, elapsed, fc_can])]
+    fn can_rx1(mut cx: can_rx1::Context) {
+        let timestamp = cx.resources.elapsed.lock(|elapsed| *elapsed);
+        receive_fifo(
+            cx.resources.fc_can,
+            &RxFifo::Fifo1,
+            timestamp,
+            cx.resources.can_rx_writer,
+        );
+    }
 
-            // This should never happen
-            None => (),
-        }
-    });
-}
+    // The fault line is PG2 (EXTI2) on the F767 and PB3 (EXTI3) on the F446. Each board only
+    // enables its own line, the other task never runs.
+    #[task(binds = EXTI2, priority = 3, resources = [fault_in, fault_line_high])]
+    fn fault_line_2(cx: fault_line_2::Context) {
+        fault_line_edge(cx.resources.fault_in, cx.resources.fault_line_high);
+    }
 
-#[cfg(feature = "nucleof446re")]
-#[interrupt]
-fn EXTI3() {
-    // This is going to fire for all pins associated with this interrupt, which is going to be all
-    // of them ending in 3 - PA3,PB3,...PG2, etc. So avoid using any more pins with the end in 2
-    // until it is known how to differentiate between them.
-    // Answer: "using EXTI_PR you have to detect which pin generated interrupt"
-    free(|cs| {
-        match FAULT_LINE.borrow(cs).borrow_mut().as_mut() {
-            // Clear the push button interrupt
-            Some(b) => {
-                b.clear_interrupt_pending_bit();
-                if b.is_high().unwrap_or(false) {
-                    SEMAPHORE.borrow(cs).set(true);
-                } else {
-                    SEMAPHORE.borrow(cs).set(false);
-                }
-            }
+    #[task(binds = EXTI3, priority = 3, resources = [fault_in, fault_line_high])]
+    fn fault_line_3(cx: fault_line_3::Context) {
+        fault_line_edge(cx.resources.fault_in, cx.resources.fault_line_high);
+    }
+
+    #[task(priority = 2, resources = [can_rx_reader, fault_line_high, fc_can, io, main_loop])]
+    fn pass(mut cx: pass::Context, elapsed: u32) {
+        let fault_line_high = cx.resources.fault_line_high.lock(|high| *high);
+        let io = cx.resources.io;
+        let main_loop = cx.resources.main_loop;
+        let mut fc_can = cx
+            .resources
+            .can_rx_reader
+            .bus(SharedCan(cx.resources.fc_can));
+        main_loop.pass(io, &mut fc_can, elapsed, fault_line_high);
+    }
+
+    #[task(priority = 2, resources = [can_rx_reader, fc_can, main_loop], spawn = [console])]
+    fn hundred_ms(cx: hundred_ms::Context, elapsed: u32) {
+        let mut fc_can = cx
+            .resources
+            .can_rx_reader
+            .bus(SharedCan(cx.resources.fc_can));
+        cx.resources.main_loop.hundred_ms(&mut fc_can, elapsed);
+        cx.spawn.console(elapsed).ok();
+    }
+
+    #[task(priority = 1, resources = [console_writer, main_loop])]
+    fn console(mut cx: console::Context, elapsed: u32) {
+        let mut snapshot = cx
+            .resources
+            .main_loop
+            .lock(|main_loop| main_loop.console_snapshot());
+        let writer = cx.resources.console_writer;
+        snapshot.cd_state.console_dropped = writer.dropped;
+        snapshot.display(writer, elapsed);
+        listen_console_tx(true);
+    }
 
-            // This should never happen
-            None => (),
+    // The console is on USART3 on the F767 and USART2 on the F446. Only the board's own
+    // interrupt is enabled.
+    #[task(binds = USART2, priority = 1, resources = [console_reader, tx])]
+    fn console_tx_2(cx: console_tx_2::Context) {
+        console_tx(cx.resources.tx, cx.resources.console_reader);
+    }
+
+    #[task(binds = USART3, priority = 1, resources = [console_reader, tx])]
+    fn console_tx_3(cx: console_tx_3::Context) {
+        console_tx(cx.resources.tx, cx.resources.console_reader);
+    }
+
+    // Free interrupts for the software tasks, one per priority.
+    extern "C" {
+        fn SPI1();
+        fn SPI2();
+    }
+};
+
+// Transmit register empty, the next byte goes out. Once the buffer is empty, the interrupt goes
+// off until the console queues more.
+fn console_tx(tx: &mut SerialConsoleOutput, reader: &mut TxQueueReader) {
+    match reader.dequeue() {
+        Some(byte) => {
+            tx.write(byte).ok();
         }
-    });
+        None => listen_console_tx(false),
+    }
+}
+
+fn fault_line_edge(fault_in: &mut FaultLinePin, fault_line_high: &mut bool) {
+    fault_in.clear_interrupt_pending_bit();
+    *fault_line_high = fault_in.is_high().unwrap_or(false);
 }
@@ -0,0 +1,205 @@
+#![deny(warnings)]
+// The charger main loop. The Linux host build (bin/host.rs) runs it as a superloop with poll,
+// the firmware (main.rs) runs its pieces as RTIC tasks. Everything board specific comes in
+// through the interfaces traits.
+use crate::can_receive_logic::init as can_receive_logic;
+use crate::connector_lock::init as connector_lock;
+use crate::control_loop::init as control_loop;
+use crate::discharge::init as discharge;
+use crate::hundred_ms_loop::init as hundred_ms_loop;
+use crate::insulation_test::init as insulation_test;
+use crate::interfaces::{
+    CanBus, ConnectorLock, InsulationMonitor, PowerStage, Relay, SequenceInputs, TextSink,
+};
+use crate::process_serial::init as process_serial;
+use crate::serial_console::display as serial_console;
+use crate::session::{transmit, update_fault_line, update_sequence_lines};
+use crate::types::*;
+use crate::utils::drive_sequence_lines;
+use crate::weld_check::init as weld_check;
+
+pub const HUNDRED_MS: u32 = 100;
+
+// What the loop talks to. The CAN bus and the console are passed in separately, the firmware
+// shares those with its interrupt handlers.
+pub struct Peripherals<D1, D2, I, L, P, S> {
+    pub connector_lock: L,
+    pub d1: D1,
+    pub d2: D2,
+    pub insulation_monitor: I,
+    pub power_stage: P,
+    pub sequence_inputs: S,
+}
+
+pub struct MainLoop {
+    pub car_state: CarState,
+    pub cd_state: CDState,
+    pub hundred_ms_counter: u8,
+    pub previous_100_ms_ts: u32,
+}
+
+impl Default for MainLoop {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MainLoop {
+    pub fn new() -> Self {
+        Self {
+            car_state: CarState::new(),
+            cd_state: CDState::new(),
+            hundred_ms_counter: 0,
+            previous_100_ms_ts: 0,
+        }
+    }
+
+    // One pass of the superloop (the host build). serial_input is the byte received since the
+    // last pass, if any. The firmware runs the pieces below as tasks instead.
+    pub fn poll<
+        C: CanBus,
+        D1: Relay,
+        D2: Relay,
+        I: InsulationMonitor,
+        L: ConnectorLock,
+        P: PowerStage,
+        S: SequenceInputs,
+        W: TextSink,
+    >(
+        &mut self,
+        io: &mut Peripherals<D1, D2, I, L, P, S>,
+        fc_can: &mut C,
+        tx: &mut W,
+        elapsed: u32,
+        fault_line_high: bool,
+        serial_input: Option<u8>,
+    ) {
+        // Serial input (and some output) - BUT - only gets called when there is input!
+        if let Some(received) = serial_input {
+            self.serial_input(received, elapsed);
+        }
+
+        self.pass(io, fc_can, elapsed, fault_line_high);
+
+        // 100 ms - Done
+        if elapsed.wrapping_sub(self.previous_100_ms_ts) >= HUNDRED_MS {
+            self.previous_100_ms_ts = elapsed;
+            self.hundred_ms(fc_can, elapsed);
+            self.console(tx, elapsed);
+        }
+    }
+
+    // Everything that runs every pass: the lines, CAN reception, the output and the protocol
+    // frames with periods of their own.
+    pub fn pass<
+        C: CanBus,
+        D1: Relay,
+        D2: Relay,
+        I: InsulationMonitor,
+        L: ConnectorLock,
+        P: PowerStage,
+        S: SequenceInputs,
+    >(
+        &mut self,
+        io: &mut Peripherals<D1, D2, I, L, P, S>,
+        fc_can: &mut C,
+        elapsed: u32,
+        fault_line_high: bool,
+    ) {
+        let cd_state = &mut self.cd_state;
+        let car_state = &mut self.car_state;
+
+        // Fault and sequence lines first, they can force a StopCharge.
+        update_fault_line(fault_line_high, elapsed, cd_state, car_state);
+        update_sequence_lines(
+            io.sequence_inputs.charge_permission(),
+            io.sequence_inputs.connector_detected(),
+            elapsed,
+            cd_state,
+            car_state,
+        );
+
+        // Highly interactive pieces:
+        // CAN reception
+        can_receive_logic(fc_can, elapsed, cd_state, car_state);
+
+        // The lock goes first so a confirmed lock starts the insulation test in the same pass.
+        connector_lock(elapsed, cd_state, &mut io.connector_lock, &io.power_stage);
+
+        // Output off before the relays open.
+        insulation_test(
+            elapsed,
+            cd_state,
+            car_state,
+            &mut io.power_stage,
+            &mut io.insulation_monitor,
+        );
+        control_loop(elapsed, cd_state, car_state, &mut io.power_stage);
+        weld_check(elapsed, cd_state, car_state, &mut io.power_stage);
+        discharge(elapsed, cd_state, &mut io.power_stage);
+
+        // d1 / d2 follow the switch flags every pass, so a StopCharge drops them right away.
+        drive_sequence_lines(cd_state, &mut io.d1, &mut io.d2);
+
+        // Protocol frames with periods of their own.
+        transmit(elapsed, cd_state, fc_can);
+    }
+
+    pub fn serial_input(&mut self, received: u8, elapsed: u32) {
+        process_serial(received, elapsed, &mut self.cd_state, &mut self.car_state);
+    }
+
+    // The protocol's 100 ms frames and the comm watchdog.
+    pub fn hundred_ms<C: CanBus>(&mut self, fc_can: &mut C, elapsed: u32) {
+        self.hundred_ms_counter = hundred_ms_loop(
+            self.hundred_ms_counter,
+            elapsed,
+            &mut self.cd_state,
+            &mut self.car_state,
+            fc_can,
+        );
+    }
+
+    // Status display, after each hundred_ms.
+    pub fn console<W: TextSink>(&mut self, tx: &mut W, elapsed: u32) {
+        self.console_snapshot().display(tx, elapsed);
+    }
+
+    // What the console shows, as of now. The firmware takes it with the loop's state locked and
+    // formats it once the lock is released.
+    pub fn console_snapshot(&mut self) -> ConsoleSnapshot {
+        let snapshot = ConsoleSnapshot {
+            car_state: self.car_state,
+            cd_state: self.cd_state.clone(),
+            hundred_ms_counter: self.hundred_ms_counter,
+        };
+
+        // Once taken, flip them off.
+        let cd_state = &mut self.cd_state;
+        if cd_state.quiet_to_verbose {
+            cd_state.quiet_to_verbose = false;
+        }
+        if cd_state.print_menu_request {
+            cd_state.print_menu_request = false;
+        }
+        snapshot
+    }
+}
+
+pub struct ConsoleSnapshot {
+    pub car_state: CarState,
+    pub cd_state: CDState,
+    pub hundred_ms_counter: u8,
+}
+
+impl ConsoleSnapshot {
+    pub fn display<W: TextSink>(&mut self, tx: &mut W, elapsed: u32) {
+        serial_console(
+            tx,
+            &mut self.cd_state,
+            &mut self.car_state,
+            elapsed,
+            self.hundred_ms_counter,
+        );
+    }
+}
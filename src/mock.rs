@@ -0,0 +1,357 @@
+#![deny(warnings)]
+// Host side implementations of the hardware interfaces, for running the charger logic without
+// a board.
+use crate::interfaces::*;
+use core::fmt::Write;
+use heapless::consts::*;
+use heapless::spsc::Queue;
+use heapless::{String, Vec};
+
+// Records everything transmitted, hands out whatever was queued with push_rx. Frames pushed
+// while the receive queue is full are dropped and counted.
+pub struct MockCan {
+    pub dropped: u32,
+    pub sent: Vec<CanMessage, U64>,
+    pub rx: Queue<CanMessage, U16>,
+}
+
+impl Default for MockCan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockCan {
+    pub fn new() -> Self {
+        Self {
+            dropped: 0,
+            sent: Vec::new(),
+            rx: Queue::new(),
+        }
+    }
+
+    pub fn push_rx(&mut self, id: u32, data: &[u8]) {
+        self.push_rx_message(CanMessage::new(id, data));
+    }
+
+    pub fn push_rx_message(&mut self, message: CanMessage) {
+        if self.rx.enqueue(message).is_err() {
+            self.dropped += 1;
+        }
+    }
+
+    pub fn clear_sent(&mut self) {
+        self.sent = Vec::new();
+    }
+
+    // Most recent frame transmitted with this id.
+    pub fn last_sent(&self, id: u32) -> Option<&CanMessage> {
+        self.sent.iter().rev().find(|message| message.id == id)
+    }
+}
+
+impl CanBus for MockCan {
+    fn send_frame(&mut self, message: &CanMessage) -> bool {
+        self.sent.push(*message).is_ok()
+    }
+
+    fn receive_frame(&mut self) -> Option<CanMessage> {
+        self.rx.dequeue()
+    }
+
+    fn rx_counters(&self) -> CanRxCounters {
+        CanRxCounters {
+            overruns: 0,
+            dropped: self.dropped,
+        }
+    }
+}
+
+pub struct MockRelay {
+    pub closed: bool,
+}
+
+impl Default for MockRelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockRelay {
+    pub fn new() -> Self {
+        Self { closed: false }
+    }
+}
+
+impl Relay for MockRelay {
+    fn set_closed(&mut self, closed: bool) {
+        self.closed = closed;
+    }
+}
+
+// Feedback follows the drive at once, unless stuck.
+pub struct MockLock {
+    pub locked: bool,
+    pub stuck: bool,
+}
+
+impl Default for MockLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockLock {
+    pub fn new() -> Self {
+        Self {
+            locked: false,
+            stuck: false,
+        }
+    }
+}
+
+impl ConnectorLock for MockLock {
+    fn set_locked(&mut self, locked: bool) {
+        if !self.stuck {
+            self.locked = locked;
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked
+    }
+}
+
+// Plugged in with charge permission, until changed.
+pub struct MockSequenceInputs {
+    pub charge_permission: bool,
+    pub connector_detected: bool,
+}
+
+impl Default for MockSequenceInputs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockSequenceInputs {
+    pub fn new() -> Self {
+        Self {
+            charge_permission: true,
+            connector_detected: true,
+        }
+    }
+}
+
+impl SequenceInputs for MockSequenceInputs {
+    fn charge_permission(&self) -> bool {
+        self.charge_permission
+    }
+
+    fn connector_detected(&self) -> bool {
+        self.connector_detected
+    }
+}
+
+// Console output is kept until cleared. Writes past the end are dropped.
+pub struct MockSink {
+    pub output: String<U2048>,
+}
+
+impl Default for MockSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockSink {
+    pub fn new() -> Self {
+        Self {
+            output: String::new(),
+        }
+    }
+}
+
+impl Write for MockSink {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.output.push_str(s).map_err(|_| core::fmt::Error)
+    }
+}
+
+pub struct MockClock {
+    pub now: u32,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self { now: 0 }
+    }
+
+    pub fn advance(&mut self, ms: u32) {
+        self.now += ms;
+    }
+}
+
+impl Clock for MockClock {
+    fn elapsed_ms(&self) -> u32 {
+        self.now
+    }
+}
+
+// A supply charging a battery: open circuit battery_voltage (V) behind resistance_mohm, connected
+// while the vehicle's contactors are closed. Runs at the current setpoint until the terminal
+// voltage would pass the voltage setpoint, then holds the voltage and the current falls off.
+// With the battery disconnected the output capacitance follows the voltage setpoint while
+// enabled, and bleeds down (faster with the discharge circuit on) once disabled. update moves it
+// on in time.
+pub struct SimulatedSupply {
+    pub battery_voltage: u16,
+    pub resistance_mohm: u32,
+    pub battery_connected: bool,
+    pub enabled: bool,
+    pub discharging: bool,
+    pub reverse: bool, // V2H
+    pub voltage_setpoint: u16,
+    pub current_setpoint: u16,
+    pub reverse_setpoint: u8,
+    pub output_mv: u32, // Output capacitance, battery disconnected
+    update_ts: u32,
+}
+
+// Output capacitance decay, V/s.
+pub const SIMULATED_BLEED_RATE: u32 = 50;
+pub const SIMULATED_DISCHARGE_RATE: u32 = 1000;
+
+impl Default for SimulatedSupply {
+    fn default() -> Self {
+        Self::new(360, 100)
+    }
+}
+
+impl SimulatedSupply {
+    pub fn new(battery_voltage: u16, resistance_mohm: u32) -> Self {
+        Self {
+            battery_voltage,
+            resistance_mohm,
+            battery_connected: false,
+            enabled: false,
+            discharging: false,
+            reverse: false,
+            voltage_setpoint: 0,
+            current_setpoint: 0,
+            reverse_setpoint: 0,
+            output_mv: 0,
+            update_ts: 0,
+        }
+    }
+
+    pub fn update(&mut self, elapsed: u32) {
+        let delta_ms = elapsed.wrapping_sub(self.update_ts);
+        self.update_ts = elapsed;
+        self.output_mv = if self.battery_connected {
+            self.terminal_mv()
+        } else if self.enabled {
+            self.voltage_setpoint as u32 * 1000
+        } else {
+            // V/s is mV/ms.
+            let rate = if self.discharging {
+                SIMULATED_DISCHARGE_RATE
+            } else {
+                SIMULATED_BLEED_RATE
+            };
+            self.output_mv.saturating_sub(rate * delta_ms)
+        };
+    }
+
+    fn current_ma(&self) -> u32 {
+        if !self.enabled || !self.battery_connected || self.voltage_setpoint < self.battery_voltage
+        {
+            return 0;
+        }
+        let setpoint_ma = self.current_setpoint as u32 * 1000;
+        if self.resistance_mohm == 0 {
+            return setpoint_ma;
+        }
+        // mV / mOhm = A
+        let headroom_mv = (self.voltage_setpoint - self.battery_voltage) as u32 * 1000;
+        setpoint_ma.min(headroom_mv * 1000 / self.resistance_mohm)
+    }
+
+    fn reverse_current_ma(&self) -> u32 {
+        if self.reverse && self.battery_connected {
+            self.reverse_setpoint as u32 * 1000
+        } else {
+            0
+        }
+    }
+
+    // Charging raises the terminal voltage above the battery's, discharging pulls it below.
+    fn terminal_mv(&self) -> u32 {
+        let rise_mv = self.current_ma() as u64 * self.resistance_mohm as u64 / 1000;
+        let drop_mv = self.reverse_current_ma() as u64 * self.resistance_mohm as u64 / 1000;
+        (self.battery_voltage as u32 * 1000 + rise_mv as u32).saturating_sub(drop_mv as u32)
+    }
+}
+
+impl PowerStage for SimulatedSupply {
+    fn set_output(&mut self, enabled: bool, voltage: u16, current: u16) {
+        self.enabled = enabled;
+        self.voltage_setpoint = voltage;
+        self.current_setpoint = current;
+    }
+
+    fn set_reverse_output(&mut self, enabled: bool, current: u8) {
+        self.reverse = enabled;
+        self.reverse_setpoint = current;
+    }
+
+    fn set_discharge(&mut self, on: bool) {
+        self.discharging = on;
+    }
+
+    fn measured_voltage(&self) -> u16 {
+        if self.battery_connected {
+            (self.terminal_mv() / 1000) as u16
+        } else {
+            (self.output_mv / 1000) as u16
+        }
+    }
+
+    fn measured_current(&self) -> u16 {
+        (self.current_ma() / 1000) as u16
+    }
+
+    fn measured_reverse_current(&self) -> u8 {
+        (self.reverse_current_ma() / 1000) as u8
+    }
+}
+
+// Reports whatever resistance_ohms is set to. Nothing is measured until it is, so by default it
+// reads as a short to earth and the insulation test fails.
+pub struct SimulatedInsulation {
+    pub resistance_ohms: u32,
+}
+
+impl Default for SimulatedInsulation {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl SimulatedInsulation {
+    pub fn new(resistance_ohms: u32) -> Self {
+        Self { resistance_ohms }
+    }
+}
+
+impl InsulationMonitor for SimulatedInsulation {
+    fn resistance_ohms(&mut self) -> u32 {
+        self.resistance_ohms
+    }
+}
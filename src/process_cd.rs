@@ -1,154 +1,130 @@
 #![deny(warnings)]
+// CHAdeMO protocol engine: 0x100 / 0x101 / 0x102 (and 0x110 / 0x200) into car_state, with the
+// 0x102 flags as the vehicle's requests, and 0x108 / 0x109 out from the 100 ms loop
+// (chademo_transmit).
 use crate::add_to_activity_list;
+use crate::chademo::*;
+use crate::chademo_transmit::init as chademo_transmit;
+use crate::interfaces::{CanBus, CanMessage};
+use crate::session::{ProtocolEngine, SessionEvent};
 use crate::types::*;
-use crate::utils::stop_charge;
 
 // Logging
 use heapless::consts::U60;
 use heapless::String;
 use ufmt::uwrite;
 
-pub fn update_car_data(id: u32, data: &[u8], mut car_state: &mut CarState) {
-    if id == 0x100 {
-        // Battery ID
-        car_state.battery_max_voltage = ((data[5] as u32) << 8 | data[4] as u32) as f32;
-        car_state.battery_pack_size = data[6] as f32;
-    }
-    if id == 0x102 {
-        car_state.current_target = data[3];
-        car_state.voltage_target = ((data[2] as u16) << 8 | data[1] as u16) as u16;
-        if (data[5] & 0x1) == 1 {
-            car_state.charging_enabled = true;
-        } else {
-            car_state.charging_enabled = false;
+pub fn update_car_data(id: u32, data: &[u8], car_state: &mut CarState) {
+    match id {
+        VEHICLE_PARAMS_ID => {
+            // Battery ID
+            let params = VehicleParams100::decode(data);
+            car_state.minimum_charge_current = params.minimum_charge_current;
+            car_state.battery_max_voltage = params.maximum_battery_voltage as f32;
+            car_state.charged_rate_reference = params.charged_rate_reference;
         }
-        if (data[5] & 0x2) == 2 {
-            car_state.not_park = true;
-        } else {
-            car_state.not_park = false;
+        VEHICLE_TIME_ID => {
+            let time = VehicleTime101::decode(data);
+            car_state.charge_time_max = time.max_charge_time_seconds();
+            car_state.charge_time_estimate = time.estimated_charge_time as u32 * 60;
+            // 0.1 kWh per bit
+            car_state.battery_pack_size = time.battery_capacity as f32 / 10.0;
         }
-        if (data[5] & 0x4) == 4 {
-            car_state.malfunction = true;
-        } else {
-            car_state.malfunction = false;
+        VEHICLE_STATUS_ID => {
+            let status = VehicleStatus102::decode(data);
+            car_state.protocol_number = status.protocol_number;
+            car_state.state_of_charge = status.state_of_charge;
+            car_state.battery_over_voltage = status.faults.battery_over_voltage;
+            car_state.battery_under_voltage = status.faults.battery_under_voltage;
+            car_state.current_deviation = status.faults.current_deviation;
+            car_state.battery_over_temperature = status.faults.battery_over_temperature;
+            car_state.voltage_deviation = status.faults.voltage_deviation;
+            car_state.current_target = status.current_request;
+            car_state.voltage_target = status.target_voltage;
+            car_state.charging_enabled = status.status.charging_enabled;
+            car_state.not_park = status.status.not_park;
+            car_state.malfunction = status.status.malfunction;
+            car_state.contactor_open = status.status.contactor_open;
+            car_state.stop_before_charge = status.status.stop_before_charge;
         }
-        if (data[5] & 0x8) == 8 {
-            // open when 1
-            car_state.contactor_open = true;
-        } else {
-            // closed when 0
-            car_state.contactor_open = false;
+        VEHICLE_EXTENDED_ID => {
+            let extended = VehicleExtended110::decode(data);
+            car_state.dynamic_control = extended.functions.dynamic_control;
+            car_state.high_current_control = extended.functions.high_current_control;
+            car_state.extended_current_request = extended.current_request;
         }
-        if (data[5] & 0x10) == 0x10 {
-            car_state.stop_before_charge = true;
-        } else {
-            car_state.stop_before_charge = false;
+        VEHICLE_DISCHARGE_ID => {
+            let discharge = VehicleDischarge200::decode(data);
+            car_state.discharge_capable = true;
+            car_state.max_discharge_current = discharge.maximum_discharge_current;
+            car_state.min_discharge_voltage = discharge.minimum_discharge_voltage;
+            car_state.min_discharge_soc = discharge.minimum_discharge_soc;
         }
+        _ => {}
     }
 }
-pub fn init(
-    elapsed: u32,
-    mut cd_state: &mut CDState,
-    mut car_state: &mut CarState,
-    id: u32,
-    data: &[u8],
-) {
-    // Always attempt to update car data.
-    update_car_data(id, &data, &mut car_state);
-    // Main state machine for charge state here
-    match cd_state.charge_state {
-        ChargeStateEnum::ChargeIdle => {
-            // Nothing to see here.
-        }
-        ChargeStateEnum::InitiateCharge => {
-            // Nothing to see here as nothing coming in yet.
-        }
-        ChargeStateEnum::WaitForComms => {
-            // Wait for 100,101,102 from EV
-            match id {
-                0x100 | 0x101 | 0x102 => {
-                    // Compute max time...ehhh.
-                    // Start transmitting 0x108, 0x109
-                    cd_state.enable_can_transmit = true;
-                    cd_state.charge_state = ChargeStateEnum::WaitChargeEnable;
-                    add_to_activity_list!(
-                        cd_state,
-                        "{} - WaitForComms -> WaitChargeEnable",
-                        elapsed
-                    );
-                }
-                _ => {
-                    // Do nothing.
-                }
-            }
-        }
-        ChargeStateEnum::WaitChargeEnable => {
-            if car_state.charging_enabled {
-                add_to_activity_list!(cd_state, "{} - WaitChargeEnable -> InsulationTest", elapsed);
-                cd_state.latch_enabled = true;
-                cd_state.charge_state = ChargeStateEnum::InsulationTest;
-            }
-        }
-        ChargeStateEnum::InsulationTest => {
-            if cd_state.delaycount > 80 {
-                add_to_activity_list!(
-                    cd_state,
-                    "{} - InsulationTest -> WaitVehicleChargeStart",
-                    elapsed
-                );
-                cd_state.charge_state = ChargeStateEnum::WaitVehicleChargeStart;
-                cd_state.delaycount = 0;
-                cd_state.current_voltage = 0;
-                cd_state.switch_two = true;
-            } else {
-                if cd_state.simulate_insulation_test {
-                    if cd_state.current_voltage + 10 <= car_state.voltage_target {
-                        cd_state.current_voltage += 10;
-                    } else {
-                        cd_state.current_voltage = car_state.voltage_target;
-                    }
-                }
 
-                cd_state.delaycount += 1;
-            }
-        }
-        ChargeStateEnum::WaitVehicleChargeStart => {
-            if car_state.contactor_open == false && car_state.current_target > 0 {
-                // Current > 0, Contactors closed.
-                cd_state.start_charge = true;
-                cd_state.charge_state = ChargeStateEnum::ChargeLoop;
-                add_to_activity_list!(
-                    cd_state,
-                    "{} - WaitVehicleChargeStart -> ChargeLoop",
-                    elapsed
-                );
-            }
-            if car_state.charging_enabled == false {
-                cd_state.charge_state = ChargeStateEnum::StopCharge;
-                add_to_activity_list!(
-                    cd_state,
-                    "{} - WtVehChgSt -> StopCharge (Chg Disbld)",
-                    elapsed
-                );
-            }
-            if car_state.malfunction {
-                cd_state.charge_state = ChargeStateEnum::StopCharge;
-                add_to_activity_list!(cd_state, "{} - WtVehChgSt -> StopCharge (Malfunc)", elapsed);
-            }
-        }
-        ChargeStateEnum::ChargeLoop => {
-            if car_state.charging_enabled == false && car_state.current_target == 0 {
-                cd_state.charge_state = ChargeStateEnum::StopCharge;
-                add_to_activity_list!(cd_state, "{} - ChgLp -> StopCharge (Chg Disbld)", elapsed);
-            }
-            if car_state.malfunction {
-                cd_state.charge_state = ChargeStateEnum::StopCharge;
-                add_to_activity_list!(cd_state, "{} - ChgLp -> StopCharge (Malfnctn)", elapsed);
-            }
-        }
-        ChargeStateEnum::StopCharge => {
-            stop_charge(cd_state, car_state, elapsed);
+// The session runs at the lower of the two protocol numbers, 0.9 at least, with the extended
+// functions both sides offer (and the protocol allows). A vehicle without 0x110 gets neither.
+pub fn negotiate(cd_state: &mut CDState, car_state: &CarState) {
+    let config = &cd_state.charger_config;
+    let protocol_number = config
+        .protocol_number
+        .min(car_state.protocol_number)
+        .max(PROTOCOL_0_9);
+    cd_state.protocol_number = protocol_number;
+    cd_state.dynamic_control =
+        protocol_number >= PROTOCOL_1_0 && config.dynamic_control && car_state.dynamic_control;
+    cd_state.high_current_control = protocol_number >= PROTOCOL_2_0
+        && config.high_current_control
+        && car_state.high_current_control;
+}
+
+pub struct Chademo;
+
+impl ProtocolEngine for Chademo {
+    const DISCHARGE: bool = true;
+
+    fn receive(
+        _elapsed: u32,
+        message: &CanMessage,
+        _cd_state: &mut CDState,
+        car_state: &mut CarState,
+    ) -> Option<SessionEvent> {
+        if message.extended {
+            return None;
         }
-        ChargeStateEnum::TimeOut => {}
+        let event = match message.id {
+            VEHICLE_PARAMS_ID | VEHICLE_TIME_ID | VEHICLE_STATUS_ID => SessionEvent::Detected,
+            VEHICLE_EXTENDED_ID | VEHICLE_DISCHARGE_ID => SessionEvent::Data,
+            _ => return None,
+        };
+        update_car_data(message.id, message.data(), car_state);
+        Some(event)
+    }
+
+    fn start(cd_state: &mut CDState) {
+        cd_state.protocol_number = cd_state.charger_config.protocol_number;
+        cd_state.dynamic_control = false;
+        cd_state.high_current_control = false;
+    }
+
+    fn negotiate(cd_state: &mut CDState, car_state: &CarState) {
+        negotiate(cd_state, car_state);
+    }
+
+    fn locking(elapsed: u32, cd_state: &mut CDState, _car_state: &CarState) {
+        add_to_activity_list!(
+            cd_state,
+            "{} - Protocol {} (dyn ctl {}, high curr {})",
+            elapsed,
+            cd_state.protocol_number,
+            cd_state.dynamic_control as u8,
+            cd_state.high_current_control as u8
+        );
+    }
+
+    fn periodic<C: CanBus>(elapsed: u32, cd_state: &CDState, car_state: &CarState, fc_can: &mut C) {
+        chademo_transmit(elapsed, cd_state, car_state, fc_can);
     }
 }
@@ -0,0 +1,186 @@
+#![deny(warnings)]
+// GB/T protocol engine. The sequence is handshake (BHM), recognition (BRM), parameters (BCP),
+// ready (BRO), then the charging loop on BCL / BCS / BSM until either side sends a stop (BST /
+// CST) and statistics (BSD / CSD). The BMS messages map onto the same requests as CHAdeMO's:
+// BHM asks to charge, BRO closes the contactors, BCL is the request and BST withdraws it. What
+// the charger sends in each state is in gbt_transmit.
+use crate::add_to_activity_list;
+use crate::gbt::*;
+use crate::gbt_transmit::init as gbt_transmit;
+use crate::gbt_transport::MAX_MESSAGE_LENGTH;
+use crate::interfaces::{CanBus, CanMessage};
+use crate::session::{ProtocolEngine, SessionEvent};
+use crate::types::*;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+pub fn update_car_data(pgn: u32, data: &[u8], cd_state: &mut CDState, car_state: &mut CarState) {
+    let gbt = &mut cd_state.gbt;
+    match pgn {
+        BHM_PGN => {
+            let handshake = BmsHandshake::decode(data);
+            car_state.battery_max_voltage = handshake.max_charge_voltage as f32 / 10.0;
+            car_state.charging_enabled = true;
+            car_state.malfunction = false;
+        }
+        BRM_PGN => {
+            let recognition = BmsRecognition::decode(data);
+            // 0.1 Ah * 0.1 V
+            car_state.battery_pack_size =
+                recognition.rated_capacity as f32 * recognition.rated_voltage as f32 / 100_000.0;
+            gbt.brm_received = true;
+        }
+        BCP_PGN => {
+            let parameters = BmsParameters::decode(data);
+            car_state.battery_max_voltage = parameters.max_charge_voltage as f32 / 10.0;
+            car_state.state_of_charge = (parameters.state_of_charge / 10) as u8;
+            gbt.bcp_received = true;
+        }
+        BRO_PGN => {
+            // The BMS closes its contactors before it says it is ready.
+            let ready = Ready::decode(data).ready;
+            gbt.bms_ready = ready;
+            car_state.contactor_open = !ready;
+        }
+        BCL_PGN if !gbt.bst_received => {
+            let requirement = BmsRequirement::decode(data);
+            car_state.voltage_target = requirement.voltage_request / 10;
+            // BSM can pause charging, the request only counts while permitted.
+            let current = if gbt.charge_paused {
+                0
+            } else {
+                requirement.current_request / 10
+            };
+            car_state.extended_current_request = current;
+            car_state.current_target = current.min(0xFF) as u8;
+        }
+        BCS_PGN => {
+            car_state.state_of_charge = BmsStatus::decode(data).state_of_charge;
+        }
+        BSM_PGN => {
+            let status = BmsBatteryStatus::decode(data);
+            car_state.malfunction = status.fault().is_some();
+            gbt.charge_paused = !status.charge_permitted;
+            if gbt.charge_paused {
+                car_state.extended_current_request = 0;
+                car_state.current_target = 0;
+            }
+        }
+        BST_PGN => {
+            // Current is down by the time the BMS stops, it opens its contactors next.
+            gbt.bst_received = true;
+            car_state.charging_enabled = false;
+            car_state.contactor_open = true;
+            car_state.extended_current_request = 0;
+            car_state.current_target = 0;
+        }
+        BSD_PGN => {
+            car_state.state_of_charge = BmsStatistics::decode(data).state_of_charge;
+            gbt.bsd_received = true;
+        }
+        _ => {}
+    }
+}
+
+// A whole BMS message, into car_state, and what it means for the session.
+fn receive_message(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &mut CarState,
+    pgn: u32,
+    data: &[u8],
+) -> SessionEvent {
+    let gbt = &cd_state.gbt;
+    let (recognized, parameters, ready) = (gbt.brm_received, gbt.bcp_received, gbt.bms_ready);
+    update_car_data(pgn, data, cd_state, car_state);
+    let gbt = &cd_state.gbt;
+    if gbt.brm_received && !recognized {
+        add_to_activity_list!(cd_state, "{} - BRM, recognized", elapsed);
+    }
+    if gbt.bcp_received && !parameters {
+        let max_voltage = car_state.battery_max_voltage as u16;
+        add_to_activity_list!(cd_state, "{} - BCP, {} V max", elapsed, max_voltage);
+        if max_voltage > cd_state.charger_config.rated_voltage {
+            // Not refused, the output is clamped to rated_voltage anyway.
+            add_to_activity_list!(cd_state, "{} - BCP above rated voltage", elapsed);
+        }
+    }
+    if cd_state.gbt.bms_ready && !ready {
+        add_to_activity_list!(cd_state, "{} - BRO, BMS ready", elapsed);
+    }
+    match pgn {
+        BHM_PGN => SessionEvent::Detected,
+        // The BMS timed out on one of ours.
+        BEM_PGN => SessionEvent::Abort("BEM"),
+        BSM_PGN => match BmsBatteryStatus::decode(data).fault() {
+            Some(reason) => SessionEvent::Abort(reason),
+            None => SessionEvent::Data,
+        },
+        _ => SessionEvent::Data,
+    }
+}
+
+pub struct Gbt;
+
+impl ProtocolEngine for Gbt {
+    const PERMISSION_LINE: bool = false;
+
+    // Frames from the BMS to the charger (or everyone). Multi-packet messages are handled once
+    // the last TP.DT has arrived.
+    fn receive(
+        elapsed: u32,
+        message: &CanMessage,
+        cd_state: &mut CDState,
+        car_state: &mut CarState,
+    ) -> Option<SessionEvent> {
+        let id = message.id;
+        if !message.extended || source(id) != BMS_ADDRESS {
+            return None;
+        }
+        if destination(id) != CHARGER_ADDRESS && destination(id) != GLOBAL_ADDRESS {
+            return None;
+        }
+        let data = message.data();
+        let event = match pgn(id) {
+            TP_CM_PGN => {
+                cd_state.gbt.transport.receive_cm(data);
+                SessionEvent::Data
+            }
+            TP_DT_PGN => match cd_state.gbt.transport.receive_dt(data) {
+                Some(pgn) => {
+                    let transport = &cd_state.gbt.transport;
+                    let mut message = [0u8; MAX_MESSAGE_LENGTH];
+                    let size = transport.size;
+                    message[..size].copy_from_slice(transport.message());
+                    receive_message(elapsed, cd_state, car_state, pgn, &message[..size])
+                }
+                None => SessionEvent::Data,
+            },
+            pgn => receive_message(elapsed, cd_state, car_state, pgn, data),
+        };
+        Some(event)
+    }
+
+    fn start(cd_state: &mut CDState) {
+        cd_state.gbt = GbtState::new();
+    }
+
+    // Requests are 16 bit and CML can be lowered at any time, like high current and dynamic
+    // control.
+    fn negotiate(cd_state: &mut CDState, _car_state: &CarState) {
+        cd_state.high_current_control = true;
+        cd_state.dynamic_control = true;
+    }
+
+    fn locking(elapsed: u32, cd_state: &mut CDState, car_state: &CarState) {
+        let max_voltage = car_state.battery_max_voltage as u16;
+        add_to_activity_list!(cd_state, "{} - BHM, {} V max", elapsed, max_voltage);
+    }
+
+    fn transmit<C: CanBus>(elapsed: u32, cd_state: &mut CDState, fc_can: &mut C) {
+        gbt_transmit(elapsed, cd_state, fc_can);
+    }
+}
@@ -1,5 +1,6 @@
 #![deny(warnings)]
 use crate::add_to_activity_list;
+use crate::session::start as session_start;
 use crate::types::CDState;
 use crate::types::*;
 use crate::utils::stop_charge;
@@ -9,36 +10,33 @@ use heapless::consts::U60;
 use heapless::String;
 use ufmt::uwrite;
 
-pub fn init(command: u8, elapsed: u32, mut cd_state: &mut CDState, mut car_state: &mut CarState) {
-    normal_input(command, elapsed, &mut cd_state, &mut car_state);
+pub fn init(command: u8, elapsed: u32, cd_state: &mut CDState, car_state: &mut CarState) {
+    normal_input(command, elapsed, cd_state, car_state);
 }
-pub fn normal_input(
-    command: u8,
-    elapsed: u32,
-    mut cd_state: &mut CDState,
-    car_state: &mut CarState,
-) {
+pub fn normal_input(command: u8, elapsed: u32, cd_state: &mut CDState, car_state: &mut CarState) {
     match command {
-        // a
-        0x61 => {}
-        // A
-        0x41 => {}
-        // c
-        0x63 => {
-            add_to_activity_list!(cd_state, "{} - User initiated start of charge.", elapsed);
-            // Turn on Relay to power EV side.
-            cd_state.switch_one = true;
-            cd_state.charge_state = ChargeStateEnum::WaitForComms;
-            add_to_activity_list!(cd_state, "{} - InitiateCharge -> WaitForComms", elapsed);
+        // a / A - Available current down / up.
+        0x61 => {
+            let current = cd_state
+                .available_current
+                .saturating_sub(AVAILABLE_CURRENT_STEP);
+            set_available_current(elapsed, cd_state, current);
+        }
+        0x41 => {
+            let current = (cd_state.available_current + AVAILABLE_CURRENT_STEP)
+                .min(cd_state.charger_config.rated_current);
+            set_available_current(elapsed, cd_state, current);
         }
+        // c
+        0x63 => session_start(elapsed, cd_state, car_state, false),
         // C
         0x43 => {
             cd_state.charge_state = ChargeStateEnum::StopCharge;
             add_to_activity_list!(cd_state, "{} - User initiated stop of charge.", elapsed);
             stop_charge(cd_state, car_state, elapsed);
         }
-        // d
-        0x64 => {}
+        // d - V2H
+        0x64 => session_start(elapsed, cd_state, car_state, true),
         // D
         0x44 => {}
         // e
@@ -70,3 +68,14 @@ pub fn normal_input(
         }
     }
 }
+
+// Once the connector is locked the vehicle has planned on what 0x108 said, so without dynamic
+// control the available current stays put until the session is over.
+fn set_available_current(elapsed: u32, cd_state: &mut CDState, current: u16) {
+    if cd_state.latch_enabled && !cd_state.dynamic_control {
+        add_to_activity_list!(cd_state, "{} - No dynamic control, not changing.", elapsed);
+        return;
+    }
+    cd_state.available_current = current;
+    add_to_activity_list!(cd_state, "{} - Available current {} A", elapsed, current);
+}
@@ -1,10 +1,24 @@
 #![deny(warnings)]
+use crate::interfaces::{CanRxCounters, TextSink};
+use crate::session::vehicle_fault;
 use crate::types::*;
 use crate::{uprint, uprintln};
-use core::fmt::Write;
 
-pub fn display(
-    tx: &mut SerialConsoleOutput,
+// The verbose screen, 80 x 24, is drawn by print_header_to_serial: "Commands:" and three lines
+// of commands, "Command?", the activity box's title and top border, the box, two free rows for
+// the last session and the counters, then the charger's and the car's state labels with the
+// rows display fills in under them.
+const MENU_ROWS: u8 = 4;
+const ACTIVITY_ROW: u8 = MENU_ROWS + 4; // First row inside the box
+const ACTIVITY_ROWS: u8 = 8;
+const SUMMARY_ROW: u8 = ACTIVITY_ROW + ACTIVITY_ROWS + 1; // Under the box
+const COUNTERS_ROW: u8 = SUMMARY_ROW + 1;
+const CHARGER_ROW: u8 = ACTIVITY_ROW + ACTIVITY_ROWS + 4; // Under "Charger State:"
+const CAR_ROW: u8 = CHARGER_ROW + 3; // Under "Car State:"
+const STATUS_ROW: u8 = CAR_ROW + 1; // The last row, nothing on it ends with a newline
+
+pub fn display<W: TextSink>(
+    tx: &mut W,
     cd_state: &mut CDState,
     car_state: &mut CarState,
     sys_ticks: u32,
@@ -19,25 +33,73 @@ pub fn display(
             print_header_to_serial(tx, verbose_console);
         }
 
-        let mut line = 10;
-        for i in cd_state.activity_list.iter() {
+        for (line, i) in (ACTIVITY_ROW..).zip(cd_state.activity_list.iter()) {
             uprintln!(tx, "\x1B[{};3H{}", line, i);
-            line = line + 1;
         }
 
         if hundred_ms_counter % 5 == 0 {
+            let summary = &cd_state.session_summary;
+            uprintln!(
+                tx,
+                "\x1B[{}HLast session: {} s, {} A max, {} V max, Weld: {}\x1B[K",
+                SUMMARY_ROW,
+                summary.charge_time,
+                summary.max_current,
+                summary.max_voltage,
+                summary.weld_check,
+            );
             uprintln!(
                 tx,
-                "\x1B[21HPhase 1: {}\x1B[21;20HPhase 2: {}",
-                cd_state.switch_one,
-                cd_state.switch_two,
+                "\x1B[{}HCAN rx overruns: {}  dropped: {}  Console dropped: {}\x1B[K",
+                COUNTERS_ROW,
+                cd_state.can_rx.overruns,
+                cd_state.can_rx.dropped,
+                cd_state.console_dropped,
             );
             uprintln!(
                 tx,
-                "\x1B[23HTgt V: {}, Tgt A: {}, Error: {}, Chg Enbld: {}, Contactors Closed: {}, Pack Size: {}",
+                "\x1B[{}Hd1: {}  d2: {}  j: {}  k: {}\x1B[{};40HSoC: {}%  {}: {} / {}",
+                CHARGER_ROW,
+                if cd_state.switch_one { "Y" } else { "N" },
+                if cd_state.switch_two { "Y" } else { "N" },
+                if car_state.charge_permission {
+                    "Y"
+                } else {
+                    "N"
+                },
+                if car_state.connector_detected {
+                    "Y"
+                } else {
+                    "N"
+                },
+                CHARGER_ROW,
+                car_state.state_of_charge,
+                cd_state.charger_config.protocol.as_str(),
+                car_state.protocol_number,
+                cd_state.protocol_number,
+            );
+            uprintln!(
+                tx,
+                "\x1B[{}HOut V: {}  Out A: {} / {}{}  V2H A: {}  Iso: {} kOhm   ",
+                CHARGER_ROW + 1,
+                cd_state.current_voltage,
+                cd_state.present_current,
+                cd_state.available_current,
+                if cd_state.dynamic_control {
+                    " (dyn)"
+                } else {
+                    ""
+                },
+                cd_state.v2h_current,
+                cd_state.insulation_resistance / 1000,
+            );
+            uprintln!(
+                tx,
+                "\x1B[{}HTgt V: {}, Tgt A: {}, Error: {}, Chg Enbld: {}, Cont Closed: {}, Pack: {}",
+                CAR_ROW,
                 car_state.voltage_target,
                 car_state.current_target,
-                if car_state.malfunction { "Y" } else { "N" },
+                if car_state.malfunction || vehicle_fault(car_state).is_some() { "Y" } else { "N" },
                 if car_state.charging_enabled { "Y" } else { "N" },
                 if car_state.contactor_open { "N" } else { "Y" },
                 car_state.battery_pack_size,
@@ -45,9 +107,21 @@ pub fn display(
         }
         uprint!(
             tx,
-            "\x1B[24HUptime: {}\x1B[24;20HState: {}",
+            "\x1B[{}HUptime: {}\x1B[{};20HState: {}\x1B[{};60HFault: {}",
+            STATUS_ROW,
             sys_ticks,
-            cd_state.charge_state
+            STATUS_ROW,
+            cd_state.charge_state,
+            STATUS_ROW,
+            if cd_state.fault_line
+                || cd_state.discharge_fault
+                || cd_state.lock_fault
+                || cd_state.insulation_fault
+            {
+                "Y"
+            } else {
+                "N"
+            },
         ); // 18 characters
     } else if hundred_ms_counter % 5 == 0 {
         if print_menu {
@@ -64,27 +138,55 @@ pub fn display(
         } else {
             uprint!(tx, "Disabled  ");
         }
+        if cd_state.charge_state == ChargeStateEnum::DischargeLoop {
+            uprint!(tx, "V2H: {} A  ", cd_state.v2h_current);
+        }
+        if cd_state.fault_line {
+            uprint!(tx, "FAULT     ");
+        }
+        if cd_state.discharge_fault {
+            uprint!(tx, "DISCHARGE FAULT  ");
+        }
+        if cd_state.lock_fault {
+            uprint!(tx, "LOCK FAULT  ");
+        }
+        if cd_state.insulation_fault {
+            uprint!(tx, "INSULATION FAULT  ");
+        }
+        if cd_state.incompatible {
+            uprint!(tx, "INCOMPATIBLE  ");
+        }
+        if cd_state.can_rx != CanRxCounters::default() {
+            let can_rx = cd_state.can_rx;
+            uprint!(tx, "CAN RX LOST: {}  ", can_rx.overruns + can_rx.dropped);
+        }
         uprintln!(tx, "Uptime: {}", sys_ticks);
     }
 }
-pub fn print_header_to_serial(tx: &mut SerialConsoleOutput, verbose_console: bool) {
+pub fn print_header_to_serial<W: TextSink>(tx: &mut W, verbose_console: bool) {
     if verbose_console {
         uprintln!(tx, "\x1B[2J\x1B[HCommands: ");
     } else {
         uprintln!(tx, "Commands: ");
     }
-    uprintln!(tx, "c / C - Start / End Charge.");
-    uprintln!(tx, "e - Clear / rEfresh the screen.");
-    uprintln!(tx, "m - Show menu with verbose disabled.");
-    uprintln!(tx, "v / V - Enable / Disable verbose.");
+    // Two to a line, MENU_ROWS with "Commands:".
+    uprintln!(tx, "c / C - Start / End Charge.          d - Start Discharge (V2H), C to end.");
+    uprintln!(tx, "a / A - Available current down / up. e - Clear / rEfresh the screen.");
+    uprintln!(tx, "m - Show menu with verbose disabled. v / V - Enable / Disable verbose.");
     if verbose_console {
         verbose_footer(tx);
+    } else {
+        // No room on the verbose screen.
+        uprintln!(
+            tx,
+            "DISCHARGE FAULT is latched: the connector stays locked until < {} V or a reset.",
+            UNLOCK_VOLTAGE
+        );
     }
 }
 #[rustfmt::skip]
-pub fn verbose_footer(tx: &mut SerialConsoleOutput) {
+pub fn verbose_footer<W: TextSink>(tx: &mut W) {
     uprintln!(tx, "Command? ");
-    uprintln!(tx, "");
     uprintln!(tx, "                          Activity");
     uprintln!(tx, "+--------------------------------------------------------------+");
     uprintln!(tx, "|                                                              |");
@@ -97,7 +199,9 @@ pub fn verbose_footer(tx: &mut SerialConsoleOutput) {
     uprintln!(tx, "|                                                              |");
     uprintln!(tx, "+--------------------------------------------------------------+");
     uprintln!(tx, "");
+    uprintln!(tx, "");
     uprintln!(tx, "Charger State:");
     uprintln!(tx, "");
+    uprintln!(tx, "");
     uprintln!(tx, "Car State:");
 }
@@ -0,0 +1,122 @@
+#![deny(warnings)]
+// Buffered console output. The console writes into a ring buffer and the serial transmit
+// interrupt sends it a byte at a time, so a write never waits on the serial port. A write (one
+// uprint! / uprintln!, however many pieces it is formatted in) that doesn't fit is dropped whole
+// and counted instead, the screen never gets half a line. One producer (the console) and one
+// consumer (the interrupt), so no lock is needed.
+use core::fmt::{self, Arguments, Result, Write};
+use core::sync::atomic::{AtomicU32, Ordering};
+use heapless::consts::U2048;
+use heapless::spsc::{Consumer, Producer, Queue};
+
+// A full verbose screen, with room to spare at 230400 baud.
+pub type TxQueueLength = U2048;
+
+pub struct TxQueue {
+    queue: Queue<u8, TxQueueLength>,
+    // Bytes taken by the interrupt, so the console can tell how much room is left.
+    sent: AtomicU32,
+}
+
+impl Default for TxQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TxQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: Queue::new(),
+            sent: AtomicU32::new(0),
+        }
+    }
+}
+
+// Console side.
+pub struct TxQueueWriter {
+    capacity: u32,
+    producer: Producer<'static, u8, TxQueueLength>,
+    queued: u32,
+    sent: &'static AtomicU32,
+    pub dropped: u32, // Writes
+}
+
+impl TxQueueWriter {
+    fn free(&self) -> usize {
+        (self.capacity - self.queued.wrapping_sub(self.sent.load(Ordering::Acquire))) as usize
+    }
+}
+
+impl Write for TxQueueWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        self.write_fmt(format_args!("{}", s))
+    }
+
+    // Formatted once to measure it, and again into the queue only if all of it fits.
+    fn write_fmt(&mut self, args: Arguments) -> Result {
+        let mut length = Length(0);
+        fmt::write(&mut length, args)?;
+        if length.0 > self.free() {
+            self.dropped += 1;
+            return Ok(());
+        }
+        fmt::write(&mut Reserved(self), args)
+    }
+}
+
+struct Length(usize);
+
+impl Write for Length {
+    fn write_str(&mut self, s: &str) -> Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+// A write whose room has been checked.
+struct Reserved<'a>(&'a mut TxQueueWriter);
+
+impl Write for Reserved<'_> {
+    fn write_str(&mut self, s: &str) -> Result {
+        let writer = &mut *self.0;
+        for byte in s.bytes() {
+            // Can't fail, the room was checked and the interrupt only makes more.
+            writer.producer.enqueue(byte).ok();
+        }
+        writer.queued = writer.queued.wrapping_add(s.len() as u32);
+        Ok(())
+    }
+}
+
+// Interrupt side.
+pub struct TxQueueReader {
+    consumer: Consumer<'static, u8, TxQueueLength>,
+    sent: &'static AtomicU32,
+}
+
+impl TxQueueReader {
+    pub fn dequeue(&mut self) -> Option<u8> {
+        let byte = self.consumer.dequeue();
+        if byte.is_some() {
+            self.sent.fetch_add(1, Ordering::Release);
+        }
+        byte
+    }
+}
+
+pub fn split(buffer: &'static mut TxQueue) -> (TxQueueWriter, TxQueueReader) {
+    let capacity = buffer.queue.capacity() as u32;
+    let (producer, consumer) = buffer.queue.split();
+    let sent = &buffer.sent;
+    (
+        TxQueueWriter {
+            capacity,
+            producer,
+            queued: 0,
+            sent,
+            dropped: 0,
+        },
+        TxQueueReader { consumer, sent },
+    )
+}
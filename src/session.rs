@@ -0,0 +1,494 @@
+#![deny(warnings)]
+// The charge session, whatever the protocol: starting one, the charge state machine, the
+// charger's limits and the interlocks (fault line, j, proximity). A protocol engine turns bus
+// traffic into vehicle requests in car_state plus a SessionEvent, and sends its own frames:
+// process_cd for CHAdeMO, process_gbt for GB/T. Another protocol is a ProtocolEngine and an arm
+// in each of the dispatches below.
+use crate::add_to_activity_list;
+use crate::interfaces::{CanBus, CanMessage};
+use crate::process_cd::Chademo;
+use crate::process_gbt::Gbt;
+use crate::types::*;
+use crate::utils::stop_charge;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// What a vehicle frame means for the session, beyond the requests in car_state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    Data,                // Requests updated
+    Detected,            // One of the vehicle's opening frames, it is there and talking
+    Abort(&'static str), // The protocol has failed, stop now
+}
+
+// State is kept in CDState / CarState like everything else, so engines have no self.
+pub trait ProtocolEngine {
+    // Whether j gates the session. Without it the vehicle's handshake is the permission.
+    const PERMISSION_LINE: bool = true;
+    const DISCHARGE: bool = false; // V2H
+
+    // A frame from the charge bus. None if it isn't from a vehicle speaking this protocol.
+    fn receive(
+        elapsed: u32,
+        message: &CanMessage,
+        cd_state: &mut CDState,
+        car_state: &mut CarState,
+    ) -> Option<SessionEvent>;
+
+    // A session is starting, back to what the charger offers.
+    fn start(cd_state: &mut CDState);
+
+    // Agree the session's functions with the vehicle, on every frame until the connector locks.
+    fn negotiate(cd_state: &mut CDState, car_state: &CarState);
+
+    // The connector is about to lock, what has been agreed is final.
+    fn locking(_elapsed: u32, _cd_state: &mut CDState, _car_state: &CarState) {}
+
+    // From the 100 ms loop.
+    fn periodic<C: CanBus>(
+        _elapsed: u32,
+        _cd_state: &CDState,
+        _car_state: &CarState,
+        _fc_can: &mut C,
+    ) {
+    }
+
+    // Every pass of the main loop, for messages with periods of their own.
+    fn transmit<C: CanBus>(_elapsed: u32, _cd_state: &mut CDState, _fc_can: &mut C) {}
+}
+
+pub fn receive(
+    elapsed: u32,
+    message: &CanMessage,
+    cd_state: &mut CDState,
+    car_state: &mut CarState,
+) {
+    match cd_state.charger_config.protocol {
+        ProtocolEnum::Chademo => receive_with::<Chademo>(elapsed, message, cd_state, car_state),
+        ProtocolEnum::Gbt => receive_with::<Gbt>(elapsed, message, cd_state, car_state),
+    }
+}
+
+pub fn periodic<C: CanBus>(elapsed: u32, cd_state: &CDState, car_state: &CarState, fc_can: &mut C) {
+    match cd_state.charger_config.protocol {
+        ProtocolEnum::Chademo => Chademo::periodic(elapsed, cd_state, car_state, fc_can),
+        ProtocolEnum::Gbt => Gbt::periodic(elapsed, cd_state, car_state, fc_can),
+    }
+}
+
+pub fn transmit<C: CanBus>(elapsed: u32, cd_state: &mut CDState, fc_can: &mut C) {
+    match cd_state.charger_config.protocol {
+        ProtocolEnum::Chademo => Chademo::transmit(elapsed, cd_state, fc_can),
+        ProtocolEnum::Gbt => Gbt::transmit(elapsed, cd_state, fc_can),
+    }
+}
+
+// Charge ('c') and discharge ('d') sessions start the same way, v2h_mode picks the loop once the
+// vehicle closes its contactors.
+pub fn start(elapsed: u32, cd_state: &mut CDState, car_state: &CarState, v2h_mode: bool) {
+    match cd_state.charger_config.protocol {
+        ProtocolEnum::Chademo => start_with::<Chademo>(elapsed, cd_state, car_state, v2h_mode),
+        ProtocolEnum::Gbt => start_with::<Gbt>(elapsed, cd_state, car_state, v2h_mode),
+    }
+}
+
+fn permission_line(protocol: ProtocolEnum) -> bool {
+    match protocol {
+        ProtocolEnum::Chademo => Chademo::PERMISSION_LINE,
+        ProtocolEnum::Gbt => Gbt::PERMISSION_LINE,
+    }
+}
+
+fn start_with<E: ProtocolEngine>(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &CarState,
+    v2h_mode: bool,
+) {
+    if cd_state.fault_line {
+        add_to_activity_list!(cd_state, "{} - Fault line asserted, not starting.", elapsed);
+        return;
+    }
+    if !car_state.connector_detected {
+        add_to_activity_list!(cd_state, "{} - No connector, not starting.", elapsed);
+        return;
+    }
+    let discharging = match cd_state.charge_state {
+        ChargeStateEnum::Discharge => true,
+        ChargeStateEnum::TimeOut => cd_state.latch_enabled,
+        _ => false,
+    };
+    if discharging {
+        add_to_activity_list!(cd_state, "{} - Still discharging, not starting.", elapsed);
+        return;
+    }
+    if cd_state.latch_enabled {
+        add_to_activity_list!(cd_state, "{} - Session in progress, not starting.", elapsed);
+        return;
+    }
+    if v2h_mode {
+        if !cd_state.charger_config.discharge_capable || !E::DISCHARGE {
+            add_to_activity_list!(cd_state, "{} - No V2H on this charger.", elapsed);
+            return;
+        }
+        add_to_activity_list!(cd_state, "{} - User initiated start of V2H.", elapsed);
+    } else {
+        add_to_activity_list!(cd_state, "{} - User initiated start of charge.", elapsed);
+    }
+    cd_state.v2h_mode = v2h_mode;
+    // Negotiated again once the vehicle is talking.
+    E::start(cd_state);
+    cd_state.discharge_fault = false;
+    cd_state.lock_fault = false;
+    cd_state.incompatible = false;
+    cd_state.insulation_fault = false;
+    // d1 on, to power the EV side.
+    cd_state.switch_one = true;
+    // Vehicle has comm_start_timeout_limit from here to start talking.
+    cd_state.previous_can_ts = elapsed;
+    cd_state.comm_timeout = true;
+    cd_state.charge_state = ChargeStateEnum::WaitForComms;
+    add_to_activity_list!(cd_state, "{} - InitiateCharge -> WaitForComms", elapsed);
+}
+
+// Can only say you've gotten a frame, not that you _haven't_ gotten a frame.
+// Timeout is set by comm_watchdog from the 100 ms loop.
+fn receive_with<E: ProtocolEngine>(
+    elapsed: u32,
+    message: &CanMessage,
+    cd_state: &mut CDState,
+    car_state: &mut CarState,
+) {
+    if let Some(event) = E::receive(elapsed, message, cd_state, car_state) {
+        cd_state.previous_can_ts = elapsed;
+        cd_state.comm_timeout = false;
+        update::<E>(elapsed, cd_state, car_state, event);
+    }
+}
+
+// First vehicle fault flag set, if any.
+pub fn vehicle_fault(car_state: &CarState) -> Option<&'static str> {
+    if car_state.battery_over_voltage {
+        Some("Over Volt")
+    } else if car_state.battery_under_voltage {
+        Some("Under Volt")
+    } else if car_state.current_deviation {
+        Some("Curr Dev")
+    } else if car_state.battery_over_temperature {
+        Some("Over Temp")
+    } else if car_state.voltage_deviation {
+        Some("Volt Dev")
+    } else {
+        None
+    }
+}
+
+// Why the charger can't meet what the vehicle asks for, if it can't.
+pub fn incompatibility(config: &ChargerConfig, car_state: &CarState) -> Option<&'static str> {
+    if car_state.voltage_target > config.rated_voltage {
+        Some("Tgt Volt")
+    } else if car_state.voltage_target > config.threshold_voltage {
+        Some("Threshold")
+    } else if car_state.minimum_charge_current as u16 > config.rated_current {
+        Some("Min Curr")
+    } else {
+        None
+    }
+}
+
+// What the vehicle is asking for, in full with high current control.
+pub fn current_request(cd_state: &CDState, car_state: &CarState) -> u16 {
+    if cd_state.high_current_control {
+        car_state.extended_current_request
+    } else {
+        car_state.current_target as u16
+    }
+}
+
+// Why a V2H session can't go ahead, once the vehicle has enabled it. Checked after
+// incompatibility, the vehicle's discharge limits have arrived along with the enable by then.
+pub fn discharge_incompatibility(
+    config: &ChargerConfig,
+    car_state: &CarState,
+) -> Option<&'static str> {
+    if !config.discharge_capable {
+        Some("No V2H EVSE")
+    } else if !car_state.discharge_capable {
+        Some("No V2H EV")
+    } else if car_state.max_discharge_current == 0 {
+        Some("Dis Curr")
+    } else {
+        None
+    }
+}
+
+// Debounce the raw fault line level (as latched by the EXTI handler) and act on changes.
+// Called every pass of the main loop, not just when a frame arrives.
+pub fn update_fault_line(
+    line_high: bool,
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &mut CarState,
+) {
+    let fault_level = line_high == FAULT_ACTIVE_HIGH;
+    if fault_level != cd_state.fault_level {
+        cd_state.fault_level = fault_level;
+        cd_state.fault_level_ts = elapsed;
+    }
+    if fault_level == cd_state.fault_line
+        || elapsed.wrapping_sub(cd_state.fault_level_ts) < FAULT_DEBOUNCE_MS
+    {
+        return;
+    }
+
+    cd_state.fault_line = fault_level;
+    if fault_level {
+        add_to_activity_list!(cd_state, "{} - Fault line asserted", elapsed);
+        // A weld check is cut short too, d1 / d2 are still closed during it.
+        if cd_state.charge_state.is_active() || cd_state.charge_state == ChargeStateEnum::WeldCheck
+        {
+            // Keep transmitting so the vehicle sees the error.
+            let transmitting = cd_state.enable_can_transmit;
+            cd_state.charge_state = ChargeStateEnum::StopCharge;
+            add_to_activity_list!(cd_state, "{} - Fault line -> StopCharge", elapsed);
+            stop_charge(cd_state, car_state, elapsed);
+            cd_state.enable_can_transmit = transmitting;
+        }
+    } else {
+        add_to_activity_list!(cd_state, "{} - Fault line cleared", elapsed);
+        if cd_state.charge_state == ChargeStateEnum::ChargeIdle {
+            cd_state.enable_can_transmit = false;
+        }
+    }
+}
+
+// Sample the vehicle's sequence lines (j, connector proximity) and act on losing them. Called
+// every pass of the main loop, like update_fault_line.
+pub fn update_sequence_lines(
+    charge_permission: bool,
+    connector_detected: bool,
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &mut CarState,
+) {
+    if connector_detected != car_state.connector_detected {
+        car_state.connector_detected = connector_detected;
+        if connector_detected {
+            add_to_activity_list!(cd_state, "{} - Connector detected", elapsed);
+        } else {
+            add_to_activity_list!(cd_state, "{} - Connector removed", elapsed);
+            if cd_state.charge_state.is_active() {
+                cd_state.charge_state = ChargeStateEnum::StopCharge;
+                add_to_activity_list!(cd_state, "{} - Connector removed -> StopCharge", elapsed);
+            }
+        }
+    }
+
+    let charge_permission = charge_permission || !permission_line(cd_state.charger_config.protocol);
+    if charge_permission != car_state.charge_permission {
+        car_state.charge_permission = charge_permission;
+        if charge_permission {
+            add_to_activity_list!(cd_state, "{} - j: charge permitted", elapsed);
+        } else {
+            add_to_activity_list!(cd_state, "{} - j: permission withdrawn", elapsed);
+            match cd_state.charge_state {
+                ChargeStateEnum::InsulationTest | ChargeStateEnum::WaitVehicleChargeStart => {
+                    cd_state.charge_state = ChargeStateEnum::StopCharge;
+                    add_to_activity_list!(cd_state, "{} - j off -> StopCharge", elapsed);
+                }
+                ChargeStateEnum::ChargeLoop | ChargeStateEnum::DischargeLoop => {
+                    end_charge(elapsed, cd_state, "j off")
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+// Normal end of ChargeLoop or DischargeLoop, through the weld check if the charger does one.
+fn end_charge(elapsed: u32, cd_state: &mut CDState, reason: &str) {
+    let from = if cd_state.charge_state == ChargeStateEnum::DischargeLoop {
+        "DisLp"
+    } else {
+        "ChgLp"
+    };
+    if cd_state.charger_config.weld_detection {
+        cd_state.charge_state = ChargeStateEnum::WeldCheck;
+        cd_state.weld_check_ts = elapsed;
+        add_to_activity_list!(cd_state, "{} - {} -> WeldCheck ({})", elapsed, from, reason);
+    } else {
+        cd_state.charge_state = ChargeStateEnum::StopCharge;
+        add_to_activity_list!(
+            cd_state,
+            "{} - {} -> StopCharge ({})",
+            elapsed,
+            from,
+            reason
+        );
+    }
+}
+
+// A session the charger can't give is stopped at once. Transmit stays on so the vehicle sees the
+// incompatible and stopped flags, the 100 ms loop turns it off once they have gone out.
+fn refuse(elapsed: u32, cd_state: &mut CDState, car_state: &mut CarState, reason: &str) {
+    cd_state.incompatible = true;
+    cd_state.charge_state = ChargeStateEnum::StopCharge;
+    add_to_activity_list!(cd_state, "{} - Incompatible ({}) -> StopCharge", elapsed, reason);
+    stop_charge(cd_state, car_state, elapsed);
+    cd_state.enable_can_transmit = true;
+}
+
+// Main state machine for charge state, on every vehicle frame.
+fn update<E: ProtocolEngine>(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &mut CarState,
+    event: SessionEvent,
+) {
+    // Any vehicle fault, or the protocol giving up, ends an active session.
+    if cd_state.charge_state.is_active() {
+        if let SessionEvent::Abort(reason) = event {
+            cd_state.charge_state = ChargeStateEnum::StopCharge;
+            add_to_activity_list!(cd_state, "{} - Abort -> StopCharge ({})", elapsed, reason);
+        } else if let Some(reason) = vehicle_fault(car_state) {
+            cd_state.charge_state = ChargeStateEnum::StopCharge;
+            add_to_activity_list!(
+                cd_state,
+                "{} - Vehicle fault -> StopCharge ({})",
+                elapsed,
+                reason
+            );
+        }
+    }
+    match cd_state.charge_state {
+        ChargeStateEnum::ChargeIdle => {
+            // Nothing to see here.
+        }
+        ChargeStateEnum::InitiateCharge => {
+            // Nothing to see here as nothing coming in yet.
+        }
+        ChargeStateEnum::WaitForComms => {
+            if event == SessionEvent::Detected {
+                // Start transmitting.
+                cd_state.enable_can_transmit = true;
+                cd_state.charge_state = ChargeStateEnum::WaitChargeEnable;
+                add_to_activity_list!(cd_state, "{} - WaitForComms -> WaitChargeEnable", elapsed);
+            }
+        }
+        ChargeStateEnum::WaitChargeEnable => {
+            let refusal = incompatibility(&cd_state.charger_config, car_state).or_else(|| {
+                if cd_state.v2h_mode && car_state.charging_enabled {
+                    discharge_incompatibility(&cd_state.charger_config, car_state)
+                } else {
+                    None
+                }
+            });
+            if let Some(reason) = refusal {
+                refuse(elapsed, cd_state, car_state, reason);
+            } else {
+                // Needs j as well as the vehicle's enable. connector_lock moves on to
+                // InsulationTest once the lock confirms.
+                if !cd_state.latch_enabled {
+                    E::negotiate(cd_state, car_state);
+                }
+                if car_state.charging_enabled
+                    && car_state.charge_permission
+                    && !cd_state.latch_enabled
+                {
+                    E::locking(elapsed, cd_state, car_state);
+                    add_to_activity_list!(cd_state, "{} - Locking connector", elapsed);
+                    cd_state.latch_enabled = true;
+                }
+            }
+        }
+        ChargeStateEnum::InsulationTest => {
+            // insulation_test runs the test and moves on to WaitVehicleChargeStart.
+        }
+        ChargeStateEnum::WaitVehicleChargeStart => {
+            if cd_state.v2h_mode {
+                // No current request when discharging, closed contactors are the go ahead.
+                if !car_state.contactor_open {
+                    cd_state.charge_start_ts = elapsed;
+                    cd_state.session_summary = SessionSummary::new();
+                    cd_state.charge_state = ChargeStateEnum::DischargeLoop;
+                    add_to_activity_list!(
+                        cd_state,
+                        "{} - WaitVehicleChargeStart -> DischargeLoop",
+                        elapsed
+                    );
+                }
+            } else if !car_state.contactor_open && car_state.current_target > 0 {
+                // Current > 0, Contactors closed.
+                cd_state.start_charge = true;
+                cd_state.charge_start_ts = elapsed;
+                cd_state.session_summary = SessionSummary::new();
+                cd_state.charge_state = ChargeStateEnum::ChargeLoop;
+                add_to_activity_list!(
+                    cd_state,
+                    "{} - WaitVehicleChargeStart -> ChargeLoop",
+                    elapsed
+                );
+            }
+            if !car_state.charging_enabled {
+                cd_state.charge_state = ChargeStateEnum::StopCharge;
+                add_to_activity_list!(
+                    cd_state,
+                    "{} - WtVehChgSt -> StopCharge (Chg Disbld)",
+                    elapsed
+                );
+            }
+            if car_state.malfunction {
+                cd_state.charge_state = ChargeStateEnum::StopCharge;
+                add_to_activity_list!(cd_state, "{} - WtVehChgSt -> StopCharge (Malfunc)", elapsed);
+            }
+        }
+        ChargeStateEnum::ChargeLoop => {
+            // One way out per pass, a malfunction first as it skips the weld check. The vehicle's
+            // maximum charging time is counted from the start of charging.
+            if car_state.malfunction {
+                cd_state.charge_state = ChargeStateEnum::StopCharge;
+                add_to_activity_list!(cd_state, "{} - ChgLp -> StopCharge (Malfnctn)", elapsed);
+            } else if !car_state.charging_enabled && car_state.current_target == 0 {
+                end_charge(elapsed, cd_state, "Chg Disbld");
+            } else if car_state.charge_time_max > 0
+                && elapsed.wrapping_sub(cd_state.charge_start_ts)
+                    >= car_state.charge_time_max * 1000
+            {
+                end_charge(elapsed, cd_state, "Max Time");
+            }
+        }
+        ChargeStateEnum::DischargeLoop => {
+            // As in ChargeLoop, a malfunction first. Voltage limits are checked under load, once
+            // current is flowing.
+            if car_state.malfunction {
+                cd_state.charge_state = ChargeStateEnum::StopCharge;
+                add_to_activity_list!(cd_state, "{} - DisLp -> StopCharge (Malfnctn)", elapsed);
+            } else if !car_state.charging_enabled {
+                end_charge(elapsed, cd_state, "Chg Disbld");
+            } else if car_state.state_of_charge <= car_state.min_discharge_soc {
+                end_charge(elapsed, cd_state, "Min SoC");
+            } else if cd_state.v2h_current > 0
+                && (cd_state.current_voltage < car_state.min_discharge_voltage
+                    || cd_state.current_voltage
+                        < cd_state.charger_config.discharge_threshold_voltage)
+            {
+                end_charge(elapsed, cd_state, "Min Volt");
+            }
+        }
+        ChargeStateEnum::WeldCheck => {
+            // weld_check watches the output voltage and moves on to StopCharge.
+        }
+        ChargeStateEnum::StopCharge => {
+            stop_charge(cd_state, car_state, elapsed);
+        }
+        ChargeStateEnum::Discharge => {
+            // discharge clears the latch, connector_lock moves on to ChargeIdle once released.
+        }
+        ChargeStateEnum::TimeOut => {
+            // Outputs already off, comm_watchdog moves on to ChargeIdle.
+        }
+    }
+}
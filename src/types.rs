@@ -1,10 +1,7 @@
 #![deny(warnings)]
-#[cfg(feature = "nucleof767zi")]
-extern crate stm32f7xx_hal as hal;
-
-#[cfg(any(feature = "nucleof446re",))]
-extern crate stm32f4xx_hal as hal;
-
+use crate::chademo::PROTOCOL_2_0;
+use crate::gbt_transport::TransportReceiver;
+use crate::interfaces::CanRxCounters;
 use arraydeque::{ArrayDeque, Wrapping};
 use core::fmt::Display;
 use heapless::consts::*;
@@ -21,12 +18,15 @@ impl Display for ChargeStateEnum {
             ChargeStateEnum::InsulationTest => write!(f, "Insulation Test"),
             ChargeStateEnum::WaitVehicleChargeStart => write!(f, "Wait for Vehicle Charge Start"),
             ChargeStateEnum::ChargeLoop => write!(f, "Charge Loop"),
+            ChargeStateEnum::DischargeLoop => write!(f, "Discharge Loop (V2H)"),
+            ChargeStateEnum::WeldCheck => write!(f, "Weld Check"),
+            ChargeStateEnum::Discharge => write!(f, "Discharge"),
             ChargeStateEnum::StopCharge => write!(f, "Stop Charge"),
         }
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ChargeStateEnum {
     TimeOut,
     ChargeIdle,
@@ -36,73 +36,386 @@ pub enum ChargeStateEnum {
     InsulationTest,
     WaitVehicleChargeStart,
     ChargeLoop,
+    DischargeLoop, // V2H, power from the vehicle
+    WeldCheck,
     StopCharge,
+    Discharge,
+}
+
+impl ChargeStateEnum {
+    // A session is in progress and the vehicle is expected to be talking to us.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            ChargeStateEnum::WaitForComms
+                | ChargeStateEnum::WaitChargeEnable
+                | ChargeStateEnum::InsulationTest
+                | ChargeStateEnum::WaitVehicleChargeStart
+                | ChargeStateEnum::ChargeLoop
+                | ChargeStateEnum::DischargeLoop
+        )
+    }
+}
+
+// Vehicle communication watchdog defaults, in ms.
+pub const COMM_TIMEOUT_MS: u32 = 1000; // Gap allowed between vehicle frames once talking
+pub const COMM_START_TIMEOUT_MS: u32 = 5000; // Time allowed for the first vehicle frame
+pub const TIMEOUT_RECOVERY_MS: u32 = 2000; // Time spent in TimeOut before ChargeIdle
+
+// Fault line input. The hardware holds the line high when it is OK to charge, a fault pulls it
+// low. The level has to be stable for FAULT_DEBOUNCE_MS before it is acted on.
+pub const FAULT_ACTIVE_HIGH: bool = false;
+pub const FAULT_DEBOUNCE_MS: u32 = 5;
+
+// How fast output current may rise, A/s (CHAdeMO limit).
+pub const CURRENT_RAMP_A_PER_S: u32 = 20;
+
+// Console steps for the available current (a / A).
+pub const AVAILABLE_CURRENT_STEP: u16 = 5;
+
+// Insulation test, with the vehicle's contactors open: raise the output to the test voltage,
+// measure, then discharge to INSULATION_SAFE_VOLTAGE. Times in ms.
+pub const INSULATION_RISE_TIMEOUT_MS: u32 = 2000;
+pub const INSULATION_MEASURE_MS: u32 = 1000; // Settling time before the resistance is read
+pub const INSULATION_DISCHARGE_TIMEOUT_MS: u32 = 2000;
+pub const INSULATION_SAFE_VOLTAGE: u16 = 20;
+pub const INSULATION_TEST_CURRENT: u16 = 2; // A, limit while the test voltage is applied
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsulationPhaseEnum {
+    Rise,
+    Measure,
+    Discharge,
+}
+
+// The connector stays locked until the output is below UNLOCK_VOLTAGE. Not getting there within
+// DISCHARGE_TIMEOUT_MS of the stop is a fault.
+pub const UNLOCK_VOLTAGE: u16 = 10;
+pub const DISCHARGE_TIMEOUT_MS: u32 = 5000;
+
+// The lock's position feedback has to follow the drive within LOCK_TIMEOUT_MS.
+pub const LOCK_TIMEOUT_MS: u32 = 1000;
+
+// Welding detection after a normal stop: once the vehicle reports its contactors open, the output
+// has to discharge below WELD_CHECK_VOLTAGE, within WELD_CHECK_TIMEOUT_MS of the stop.
+pub const WELD_CHECK_TIMEOUT_MS: u32 = 4000;
+pub const WELD_CHECK_VOLTAGE: u16 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeldCheckEnum {
+    NotRun,
+    Passed,
+    Welded,
+    ContactorsClosed, // Vehicle never reported its contactors open
+}
+
+impl WeldCheckEnum {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WeldCheckEnum::NotRun => "Not Run",
+            WeldCheckEnum::Passed => "Passed",
+            WeldCheckEnum::Welded => "Welded",
+            WeldCheckEnum::ContactorsClosed => "Contactors Closed",
+        }
+    }
+}
+
+impl Display for WeldCheckEnum {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::result::Result<(), core::fmt::Error> {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+// The last session that got as far as ChargeLoop (or DischargeLoop).
+#[derive(Clone, Copy)]
+pub struct SessionSummary {
+    pub charge_time: u32, // Seconds in ChargeLoop / DischargeLoop
+    pub max_current: u16,
+    pub max_voltage: u16,
+    pub weld_check: WeldCheckEnum,
+}
+
+impl Default for SessionSummary {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SessionSummary {
+    pub fn new() -> Self {
+        Self {
+            charge_time: 0,
+            max_current: 0,
+            max_voltage: 0,
+            weld_check: WeldCheckEnum::NotRun,
+        }
+    }
+}
+
+// Charging protocol on the charge bus. GB/T needs the bus at 250 kbit/s, which the firmware
+// sets at build time (the gbt feature), so only the host build switches at run time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtocolEnum {
+    Chademo,
+    Gbt,
+}
+
+impl ProtocolEnum {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProtocolEnum::Chademo => "CHAdeMO",
+            ProtocolEnum::Gbt => "GB/T",
+        }
+    }
+}
+
+#[cfg(not(feature = "gbt"))]
+pub const DEFAULT_PROTOCOL: ProtocolEnum = ProtocolEnum::Chademo;
+#[cfg(feature = "gbt")]
+pub const DEFAULT_PROTOCOL: ProtocolEnum = ProtocolEnum::Gbt;
+
+// GB/T transmit periods, ms.
+pub const GBT_FAST_PERIOD_MS: u32 = 10; // CST
+pub const GBT_STATUS_PERIOD_MS: u32 = 50; // CCS
+pub const GBT_SLOW_PERIOD_MS: u32 = 250; // CHM, CRM, CML, CRO, CSD, CEM
+
+// Where a GB/T session has got to, beyond what charge_state says. Reset when a session starts.
+#[derive(Clone, Copy)]
+pub struct GbtState {
+    pub bcp_received: bool,
+    pub bms_ready: bool, // BRO READY
+    pub brm_received: bool,
+    pub bsd_received: bool,
+    pub bst_received: bool,
+    pub charge_paused: bool, // BSM, charging not permitted
+    pub fast_ts: u32,
+    pub slow_ts: u32,
+    pub status_ts: u32,
+    pub transport: TransportReceiver,
+}
+
+impl Default for GbtState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GbtState {
+    pub fn new() -> Self {
+        Self {
+            bcp_received: false,
+            bms_ready: false,
+            brm_received: false,
+            bsd_received: false,
+            bst_received: false,
+            charge_paused: false,
+            fast_ts: 0,
+            slow_ts: 0,
+            status_ts: 0,
+            transport: TransportReceiver::new(),
+        }
+    }
 }
 
+// What the charger advertises in 0x108 (vehicle requests are checked against it), and its own
+// limits. The protocol number and extended functions are the most it offers, a vehicle that
+// doesn't support them gets the basic sequence.
+#[derive(Clone, Copy)]
+pub struct ChargerConfig {
+    pub rated_voltage: u16,     // V
+    pub rated_current: u16,     // A
+    pub threshold_voltage: u16, // V, output is stopped above this
+    pub weld_detection: bool,
+    pub insulation_ohms_per_volt: u32, // Minimum isolation resistance, per V of test voltage
+    pub insulation_test: bool, // Off without a power stage and monitor to test with, it is skipped
+    pub protocol: ProtocolEnum,
+    pub protocol_number: u8,
+    pub dynamic_control: bool,
+    pub high_current_control: bool,
+    // V2H, advertised in 0x208 when discharge_capable.
+    pub discharge_capable: bool,
+    pub rated_discharge_current: u8,      // A
+    pub discharge_threshold_voltage: u16, // V, discharge is stopped below this
+}
+
+impl Default for ChargerConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChargerConfig {
+    pub fn new() -> Self {
+        Self {
+            rated_voltage: 430,
+            rated_current: 32,
+            threshold_voltage: 430,
+            weld_detection: true,
+            insulation_ohms_per_volt: 100,
+            insulation_test: true,
+            protocol: DEFAULT_PROTOCOL,
+            protocol_number: PROTOCOL_2_0,
+            dynamic_control: true,
+            high_current_control: true,
+            discharge_capable: true,
+            rated_discharge_current: 20,
+            discharge_threshold_voltage: 250,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct CDState {
     pub activity_list: ArrayDeque<[String<U60>; 8], Wrapping>,
+    pub available_current: u16, // A, advertised in 0x108 / 0x118
+    pub can_rx: CanRxCounters,  // As last seen by can_receive_logic
+    pub charge_start_ts: u32,
     pub charge_state: ChargeStateEnum,
+    pub charger_config: ChargerConfig,
+    pub comm_start_timeout_limit: u32,
     pub comm_timeout: bool,
+    pub comm_timeout_limit: u32,
+    pub connector_locked: bool, // Lock position feedback
+    pub console_dropped: u32,   // Console writes that didn't fit in the serial buffer
+    pub control_ts: u32,
     pub current_voltage: u16,
-    pub delaycount: u8,
+    pub discharge_fault: bool,
+    pub discharge_ts: u32,
+    pub dynamic_control: bool, // Negotiated for this session
     pub enable_can_transmit: bool,
     pub evse_request: bool,
-    pub latch_enabled: bool,
+    pub fault_level: bool,
+    pub fault_level_ts: u32,
+    pub fault_line: bool,
+    pub gbt: GbtState,
+    pub high_current_control: bool, // Negotiated for this session
+    pub incompatible: bool,
+    pub insulation_fault: bool, // The last insulation test failed
+    pub insulation_phase: InsulationPhaseEnum,
+    pub insulation_resistance: u32, // Last measured, ohms
+    pub insulation_ts: u32,         // Start of the current phase
+    pub latch_enabled: bool,        // Lock command
+    pub lock_fault: bool,
+    pub lock_ts: u32,           // Last time the feedback agreed with the command
+    pub output_current_ma: u32, // Ramped current setpoint
+    pub present_current: u16,   // Measured, A
     pub previous_can_ts: u32,
     pub print_menu_request: bool,
+    pub protocol_number: u8, // Negotiated, the charger's own until the vehicle's is known
     pub quiet_to_verbose: bool,
-    pub simulate_insulation_test: bool,
+    pub session_summary: SessionSummary,
     pub start_charge: bool,
-    pub switch_one: bool,
-    pub switch_two: bool,
+    pub switch_one: bool, // d1
+    pub switch_two: bool, // d2 (only with d1)
+    pub timeout_ts: u32,
+    pub v2h_current: u8, // Measured discharge current, A
+    pub v2h_mode: bool,  // Session started as a discharge ('d')
     pub verbose_stats: bool,
+    pub weld_check_ts: u32,
+}
+
+impl Default for CDState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CDState {
     pub fn new() -> Self {
+        let charger_config = ChargerConfig::new();
         Self {
             activity_list: ArrayDeque::new(),
+            available_current: charger_config.rated_current,
+            can_rx: CanRxCounters::default(),
+            charge_start_ts: 0,
             charge_state: ChargeStateEnum::StopCharge,
+            charger_config,
+            comm_start_timeout_limit: COMM_START_TIMEOUT_MS,
             comm_timeout: true,
+            comm_timeout_limit: COMM_TIMEOUT_MS,
+            connector_locked: false,
+            console_dropped: 0,
+            control_ts: 0,
             current_voltage: 0,
-            delaycount: 0,
+            discharge_fault: false,
+            discharge_ts: 0,
+            dynamic_control: false,
             enable_can_transmit: false,
             evse_request: false,
+            fault_level: false,
+            fault_level_ts: 0,
+            fault_line: false,
+            gbt: GbtState::new(),
+            high_current_control: false,
+            incompatible: false,
+            insulation_fault: false,
+            insulation_phase: InsulationPhaseEnum::Rise,
+            insulation_resistance: 0,
+            insulation_ts: 0,
             latch_enabled: false,
+            lock_fault: false,
+            lock_ts: 0,
+            output_current_ma: 0,
+            present_current: 0,
             previous_can_ts: 0,
             print_menu_request: false,
+            protocol_number: charger_config.protocol_number,
             quiet_to_verbose: false,
-            simulate_insulation_test: false,
+            session_summary: SessionSummary::new(),
             start_charge: false,
             switch_one: false,
             switch_two: false,
+            timeout_ts: 0,
+            v2h_current: 0,
+            v2h_mode: false,
             verbose_stats: false,
+            weld_check_ts: 0,
         }
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct CarState {
     pub battery_over_temperature: bool,
     pub battery_over_voltage: bool,
     pub battery_pack_size: f32,
     pub battery_max_voltage: f32,
     pub battery_under_voltage: bool,
+    pub charge_permission: bool, // j
     pub charge_stop_request: bool,
-    pub charge_time_estimate: f32,
-    pub charge_time_max: f32,
+    pub charge_time_estimate: u32, // Seconds
+    pub charge_time_max: u32,      // Seconds
+    pub charged_rate_reference: u8,
     pub charging_enabled: bool,
     pub charging_malfunction: bool,
+    pub connector_detected: bool, // Proximity
     pub contactor_open: bool,
     pub current_deviation: bool,
     pub current_target: u8,
+    pub discharge_capable: bool,       // Vehicle sends 0x200
+    pub dynamic_control: bool,         // 0x110
+    pub extended_current_request: u16, // 0x110, A
+    pub high_current_control: bool,    // 0x110
     pub malfunction: bool,
+    pub max_discharge_current: u8,  // A
+    pub min_discharge_soc: u8,      // %
+    pub min_discharge_voltage: u16, // V
+    pub minimum_charge_current: u8,
     pub not_park: bool,
+    pub protocol_number: u8,
+    pub state_of_charge: u8,
     pub stop_before_charge: bool,
     pub voltage_deviation: bool,
     pub voltage_target: u16,
     pub vehicle_parked: bool,
 }
 
+impl Default for CarState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CarState {
     pub fn new() -> Self {
         Self {
@@ -111,68 +424,33 @@ impl CarState {
             battery_pack_size: 0.0,
             battery_max_voltage: 0.0,
             battery_under_voltage: false,
+            charge_permission: false,
             charge_stop_request: false,
-            charge_time_estimate: 0.0,
-            charge_time_max: 0.0,
+            charge_time_estimate: 0,
+            charge_time_max: 0,
+            charged_rate_reference: 0,
             charging_enabled: false,
             charging_malfunction: false,
+            connector_detected: false,
             contactor_open: true,
             current_deviation: false,
             current_target: 0,
+            discharge_capable: false,
+            dynamic_control: false,
+            extended_current_request: 0,
+            high_current_control: false,
             malfunction: false,
+            max_discharge_current: 0,
+            min_discharge_soc: 0,
+            min_discharge_voltage: 0,
+            minimum_charge_current: 0,
             not_park: true,
+            protocol_number: 0,
+            state_of_charge: 0,
             stop_before_charge: false,
-
             voltage_deviation: false,
             voltage_target: 0,
             vehicle_parked: false,
         }
     }
 }
-
-// Generic type abstractions
-// Why? Remove reference to hal, so that it does not need to be included in many spots with
-// conditional code around it.
-pub type BaseID = hal::can::BaseID;
-pub type CanFrame = hal::can::CanFrame;
-pub type DataFrame = hal::can::DataFrame;
-pub type ID = hal::can::ID;
-pub type Rtc = hal::rtc::Rtc;
-
-// HW specific type abstractions
-#[cfg(feature = "nucleof767zi")]
-mod abstractions {
-    extern crate stm32f7xx_hal as hal;
-    use hal::can::Can;
-    use hal::gpio::gpiod::{PD0, PD1, PD2};
-    use hal::gpio::gpiog::{PG2, PG3};
-    use hal::gpio::AF9;
-    use hal::gpio::{Alternate, Floating, Input, Output, PushPull};
-    use hal::pac::CAN1;
-    pub type FCCAN = Can<CAN1, (PD1<Alternate<AF9>>, PD0<Alternate<AF9>>)>;
-    pub type SerialConsoleOutput = hal::serial::Tx<hal::pac::USART3>;
-    pub type FaultLinePin = PG2<Input<Floating>>;
-    pub type RelayOnePin = PG3<Output<PushPull>>;
-    pub type RelayTwoPin = PD2<Output<PushPull>>;
-}
-
-#[cfg(feature = "nucleof446re")]
-mod abstractions {
-    extern crate stm32f4xx_hal as hal;
-    use hal::can::Can;
-    use hal::gpio::gpiob::{PB3, PB5, PB6, PB8, PB9};
-    use hal::gpio::AF9;
-    use hal::gpio::{Alternate, Floating, Input, Output, PushPull};
-    use hal::pac::CAN1;
-    pub type FCCAN = Can<CAN1, (PB9<Alternate<AF9>>, PB8<Alternate<AF9>>)>;
-    pub type SerialConsoleOutput = hal::serial::Tx<hal::pac::USART2>;
-    pub type FaultLinePin = PB3<Input<Floating>>;
-    pub type RelayOnePin = PB5<Output<PushPull>>;
-    pub type RelayTwoPin = PB6<Output<PushPull>>; // FIXME: Not actual pin.
-}
-
-pub type FCCAN = abstractions::FCCAN;
-pub type SerialConsoleOutput = abstractions::SerialConsoleOutput;
-pub type FaultLinePin = abstractions::FaultLinePin;
-pub type RelayOnePin = abstractions::RelayOnePin;
-pub type RelayTwoPin = abstractions::RelayTwoPin;
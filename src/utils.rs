@@ -1,5 +1,6 @@
 #![deny(warnings)]
 use crate::add_to_activity_list;
+use crate::interfaces::Relay;
 use crate::types::*;
 
 // Logging
@@ -7,21 +8,49 @@ use heapless::consts::U60;
 use heapless::String;
 use ufmt::uwrite;
 
-pub fn reset_car_data(mut car_state: &mut CarState) {
-    car_state.battery_max_voltage = 0.0;
-    car_state.battery_pack_size = 0.0;
-    car_state.voltage_target = 0;
-    car_state.charging_enabled = false;
-    car_state.contactor_open = true;
+// Everything the vehicle sent for the session goes. Its fault flags stay for 0x109 until its next
+// frame, and j and proximity are the charger's own samples of the lines, which
+// update_sequence_lines acts on the changes of.
+pub fn reset_car_data(car_state: &mut CarState) {
+    *car_state = CarState {
+        battery_over_temperature: car_state.battery_over_temperature,
+        battery_over_voltage: car_state.battery_over_voltage,
+        battery_under_voltage: car_state.battery_under_voltage,
+        charge_permission: car_state.charge_permission,
+        connector_detected: car_state.connector_detected,
+        current_deviation: car_state.current_deviation,
+        malfunction: car_state.malfunction,
+        voltage_deviation: car_state.voltage_deviation,
+        ..CarState::new()
+    };
 }
 
-pub fn stop_charge(mut cd_state: &mut CDState, mut car_state: &mut CarState, elapsed: u32) {
-    reset_car_data(&mut car_state);
+// Output and relays off. A locked connector is only released by discharge, once the output is
+// below UNLOCK_VOLTAGE.
+pub fn stop_charge(cd_state: &mut CDState, car_state: &mut CarState, elapsed: u32) {
+    reset_car_data(car_state);
     cd_state.switch_one = false;
     cd_state.switch_two = false;
-    cd_state.latch_enabled = false;
-    cd_state.enable_can_transmit = false;
-    cd_state.current_voltage = 0;
-    cd_state.charge_state = ChargeStateEnum::ChargeIdle;
-    add_to_activity_list!(cd_state, "{} - StopCharge -> ChargeIdle", elapsed);
+    cd_state.start_charge = false;
+    cd_state.output_current_ma = 0;
+    cd_state.discharge_ts = elapsed;
+    if cd_state.charge_state == ChargeStateEnum::TimeOut {
+        // The vehicle has gone, comm_watchdog moves on to ChargeIdle.
+        cd_state.enable_can_transmit = false;
+    } else if cd_state.latch_enabled {
+        // Keep 0x109 going so the vehicle sees the connector unlock.
+        cd_state.charge_state = ChargeStateEnum::Discharge;
+        add_to_activity_list!(cd_state, "{} - StopCharge -> Discharge", elapsed);
+    } else {
+        cd_state.enable_can_transmit = false;
+        cd_state.current_voltage = 0;
+        cd_state.charge_state = ChargeStateEnum::ChargeIdle;
+        add_to_activity_list!(cd_state, "{} - StopCharge -> ChargeIdle", elapsed);
+    }
+}
+
+// d1 follows switch_one, d2 needs both switches.
+pub fn drive_sequence_lines<D1: Relay, D2: Relay>(cd_state: &CDState, d1: &mut D1, d2: &mut D2) {
+    d1.set_closed(cd_state.switch_one);
+    d2.set_closed(cd_state.switch_one && cd_state.switch_two);
 }
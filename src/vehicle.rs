@@ -0,0 +1,433 @@
+#![deny(warnings)]
+// Vehicle half of the CHAdeMO sequence, for testing the charger side.
+// Sends 0x100/0x101/0x102 with the configured targets (and 0x200 when it offers V2H), follows
+// the charger through its 0x108/0x109 frames and drives the charge permission line (j). Uses
+// the same frame definitions as process_cd. Runs in ev-sim on the host and in the can-dc-ev
+// firmware.
+use crate::add_to_activity_list;
+use crate::chademo::*;
+use crate::interfaces::{CanBus, CanMessage};
+use arraydeque::{ArrayDeque, Wrapping};
+use core::fmt::Display;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// Gap allowed between charger frames once talking, in ms.
+pub const EVSE_COMM_TIMEOUT_MS: u32 = 1000;
+
+// Vehicle side checks on what the charger reports while charging. More current than requested,
+// or a voltage away from the vehicle's own battery voltage (when configured), for longer than
+// DEVIATION_TIME_MS is a fault. Decreases are followed at once, so there is no ramp to allow for.
+pub const CURRENT_DEVIATION_A: u8 = 10;
+pub const VOLTAGE_DEVIATION_V: u16 = 10;
+pub const DEVIATION_TIME_MS: u32 = 1000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum VehicleStateEnum {
+    Idle,
+    WaitEvse,
+    WaitLock,
+    WaitInsulation,
+    Charging,
+    Stopping,
+    Stopped,
+    Fault,
+}
+
+impl Display for VehicleStateEnum {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::result::Result<(), core::fmt::Error> {
+        match self {
+            VehicleStateEnum::Idle => write!(f, "Idle"),
+            VehicleStateEnum::WaitEvse => write!(f, "Wait for EVSE"),
+            VehicleStateEnum::WaitLock => write!(f, "Wait for Connector Lock"),
+            VehicleStateEnum::WaitInsulation => write!(f, "Wait for Insulation Test"),
+            VehicleStateEnum::Charging => write!(f, "Charging"),
+            VehicleStateEnum::Stopping => write!(f, "Stopping"),
+            VehicleStateEnum::Stopped => write!(f, "Stopped"),
+            VehicleStateEnum::Fault => write!(f, "Fault"),
+        }
+    }
+}
+
+// What the vehicle asks for. current_steps is a script of (ms after charging starts, A)
+// pairs, the last step whose time has passed is the current request.
+#[derive(Clone, Copy)]
+pub struct VehicleConfig {
+    pub battery_capacity: u16, // 0.1 kWh
+    pub battery_voltage: u16,  // V, the vehicle's own measurement, 0 = not measured
+    pub charge_time: u32,      // ms of charging before the vehicle stops, 0 = until max time
+    pub contactor_delay: u32,  // ms from connector lock to closing the contactors, once d2 is on
+    pub current_steps: [(u32, u8); 4],
+    pub estimated_charge_time: u8,  // min
+    pub evse_response_timeout: u32, // ms the charger gets to answer, 0 = wait for ever
+    pub max_battery_voltage: u16,
+    pub max_charge_time: u8,       // min
+    pub max_discharge_current: u8, // A, V2H offered in 0x200 when not 0
+    pub min_discharge_soc: u8,     // %
+    pub minimum_charge_current: u8,
+    pub protocol_number: u8,
+    pub state_of_charge: u8,
+    pub target_voltage: u16,
+}
+
+impl VehicleConfig {
+    pub fn new() -> Self {
+        Self {
+            battery_capacity: 240,
+            battery_voltage: 0,
+            charge_time: 60_000,
+            contactor_delay: 4000,
+            current_steps: [(0, 10); 4],
+            estimated_charge_time: 30,
+            evse_response_timeout: 30_000,
+            max_battery_voltage: 410,
+            max_charge_time: 60,
+            max_discharge_current: 0,
+            min_discharge_soc: 30,
+            minimum_charge_current: 0,
+            protocol_number: 1,
+            state_of_charge: 50,
+            target_voltage: 400,
+        }
+    }
+
+    pub fn current_at(&self, charge_ms: u32) -> u8 {
+        let mut current = 0;
+        for (start, amps) in self.current_steps.iter() {
+            if charge_ms >= *start {
+                current = *amps;
+            }
+        }
+        current
+    }
+}
+
+impl Default for VehicleConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct VehicleState {
+    pub activity_list: ArrayDeque<[String<U60>; 8], Wrapping>,
+    pub charge_permission: bool, // j
+    pub charge_start_ts: u32,
+    pub charging_enabled: bool,
+    pub config: VehicleConfig,
+    pub contactor_open: bool,
+    pub current_deviation_ts: u32, // Last time the current was within limits
+    pub current_request: u8,
+    pub d2: bool, // The charger's charge sequence signal two, set by the caller
+    pub evse_charging: bool,
+    pub evse_discharge: Option<EvseDischarge208>,
+    pub evse_params: Option<EvseParams108>,
+    pub evse_status: Option<EvseStatus109>,
+    pub evse_ts: u32,
+    pub faults: VehicleFaults,
+    pub malfunction: bool,
+    pub max_current_seen: u8,
+    pub max_voltage_seen: u16,
+    pub state: VehicleStateEnum,
+    pub state_ts: u32,
+    pub voltage_deviation_ts: u32, // Last time the voltage was within limits
+}
+
+impl VehicleState {
+    pub fn new(config: VehicleConfig) -> Self {
+        Self {
+            activity_list: ArrayDeque::new(),
+            charge_permission: false,
+            charge_start_ts: 0,
+            charging_enabled: false,
+            config,
+            contactor_open: true,
+            current_deviation_ts: 0,
+            current_request: 0,
+            d2: false,
+            evse_charging: false,
+            evse_discharge: None,
+            evse_params: None,
+            evse_status: None,
+            evse_ts: 0,
+            faults: VehicleFaults::default(),
+            malfunction: false,
+            max_current_seen: 0,
+            max_voltage_seen: 0,
+            state: VehicleStateEnum::Idle,
+            state_ts: 0,
+            voltage_deviation_ts: 0,
+        }
+    }
+
+    pub fn params100(&self) -> VehicleParams100 {
+        VehicleParams100 {
+            minimum_charge_current: self.config.minimum_charge_current,
+            minimum_battery_voltage: 0,
+            maximum_battery_voltage: self.config.max_battery_voltage,
+            charged_rate_reference: 100,
+        }
+    }
+
+    pub fn time101(&self) -> VehicleTime101 {
+        VehicleTime101 {
+            max_charge_time_10s: 0xFF,
+            max_charge_time_1min: self.config.max_charge_time,
+            estimated_charge_time: self.config.estimated_charge_time,
+            battery_capacity: self.config.battery_capacity,
+        }
+    }
+
+    pub fn discharge200(&self) -> VehicleDischarge200 {
+        VehicleDischarge200 {
+            maximum_discharge_current: self.config.max_discharge_current,
+            minimum_discharge_voltage: 0,
+            minimum_discharge_soc: self.config.min_discharge_soc,
+            maximum_charge_soc: 100,
+        }
+    }
+
+    pub fn status102(&self) -> VehicleStatus102 {
+        VehicleStatus102 {
+            protocol_number: self.config.protocol_number,
+            target_voltage: self.config.target_voltage,
+            current_request: self.current_request,
+            faults: self.faults,
+            status: VehicleStatusFlags {
+                charging_enabled: self.charging_enabled,
+                not_park: false,
+                malfunction: self.malfunction,
+                contactor_open: self.contactor_open,
+                stop_before_charge: false,
+            },
+            state_of_charge: self.config.state_of_charge,
+        }
+    }
+}
+
+fn set_state(vehicle: &mut VehicleState, state: VehicleStateEnum, elapsed: u32) {
+    vehicle.state = state;
+    vehicle.state_ts = elapsed;
+}
+
+// Plug in and start talking.
+pub fn start(vehicle: &mut VehicleState, elapsed: u32) {
+    let config = vehicle.config;
+    *vehicle = VehicleState::new(config);
+    vehicle.evse_ts = elapsed;
+    set_state(vehicle, VehicleStateEnum::WaitEvse, elapsed);
+    add_to_activity_list!(vehicle, "{} - Idle -> WaitEvse", elapsed);
+}
+
+// Vehicle side normal stop: drop the request, contactors open once the charger is at 0 A.
+pub fn stop(vehicle: &mut VehicleState, elapsed: u32) {
+    match vehicle.state {
+        VehicleStateEnum::Idle | VehicleStateEnum::Stopped | VehicleStateEnum::Fault => {}
+        _ => {
+            vehicle.charging_enabled = false;
+            vehicle.charge_permission = false;
+            vehicle.current_request = 0;
+            set_state(vehicle, VehicleStateEnum::Stopping, elapsed);
+            add_to_activity_list!(vehicle, "{} - Vehicle stop -> Stopping", elapsed);
+        }
+    }
+}
+
+fn fault(vehicle: &mut VehicleState, elapsed: u32, reason: &str) {
+    vehicle.charging_enabled = false;
+    vehicle.charge_permission = false;
+    vehicle.current_request = 0;
+    vehicle.contactor_open = true;
+    vehicle.malfunction = true;
+    set_state(vehicle, VehicleStateEnum::Fault, elapsed);
+    add_to_activity_list!(vehicle, "{} - Fault ({})", elapsed, reason);
+}
+
+// Charger frames 0x108 / 0x109, and 0x208 from a V2H charger.
+pub fn init(elapsed: u32, vehicle: &mut VehicleState, id: u32, data: &[u8]) {
+    match id {
+        EVSE_DISCHARGE_ID => {
+            vehicle.evse_discharge = Some(EvseDischarge208::decode(data));
+            return;
+        }
+        EVSE_PARAMS_ID => {
+            vehicle.evse_params = Some(EvseParams108::decode(data));
+        }
+        EVSE_STATUS_ID => {
+            let status = EvseStatus109::decode(data);
+            if status.present_voltage > vehicle.max_voltage_seen {
+                vehicle.max_voltage_seen = status.present_voltage;
+            }
+            if status.present_current > vehicle.max_current_seen {
+                vehicle.max_current_seen = status.present_current;
+            }
+            vehicle.evse_status = Some(status);
+        }
+        _ => return,
+    }
+    vehicle.evse_ts = elapsed;
+
+    let status = match vehicle.evse_status {
+        Some(status) => status,
+        None => return,
+    };
+
+    if status.status.error
+        && vehicle.state != VehicleStateEnum::Stopped
+        && vehicle.state != VehicleStateEnum::Fault
+    {
+        fault(vehicle, elapsed, "EVSE error");
+        return;
+    }
+
+    match vehicle.state {
+        VehicleStateEnum::WaitEvse => {
+            if let Some(params) = vehicle.evse_params {
+                if params.available_voltage < vehicle.config.target_voltage
+                    || status.status.incompatible
+                {
+                    fault(vehicle, elapsed, "Incompatible");
+                } else {
+                    vehicle.charging_enabled = true;
+                    vehicle.charge_permission = true;
+                    set_state(vehicle, VehicleStateEnum::WaitLock, elapsed);
+                    add_to_activity_list!(vehicle, "{} - WaitEvse -> WaitLock", elapsed);
+                }
+            }
+        }
+        VehicleStateEnum::WaitLock if status.status.connector_locked => {
+            set_state(vehicle, VehicleStateEnum::WaitInsulation, elapsed);
+            add_to_activity_list!(vehicle, "{} - WaitLock -> WaitInsulation", elapsed);
+        }
+        VehicleStateEnum::Charging => {
+            // The charger reports stopped until it has seen the contactors close.
+            if status.status.charging {
+                vehicle.evse_charging = true;
+            } else if vehicle.evse_charging && status.status.stopped {
+                add_to_activity_list!(vehicle, "{} - EVSE stopped", elapsed);
+                stop(vehicle, elapsed);
+                return;
+            }
+            deviation_check(elapsed, vehicle, &status);
+        }
+        // Contactors open once the charger is at 0 A. Keep talking while the charger checks for
+        // welded contactors, until it unlocks the connector or goes quiet.
+        VehicleStateEnum::Stopping if vehicle.contactor_open && !status.status.connector_locked => {
+            set_state(vehicle, VehicleStateEnum::Stopped, elapsed);
+            add_to_activity_list!(vehicle, "{} - Stopping -> Stopped", elapsed);
+        }
+        VehicleStateEnum::Stopping
+            if !vehicle.contactor_open
+                && status.present_current == 0
+                && !status.status.charging =>
+        {
+            vehicle.contactor_open = true;
+            add_to_activity_list!(vehicle, "{} - Contactors open", elapsed);
+        }
+        _ => {}
+    }
+}
+
+// Over voltage at once, current and voltage deviation once they have lasted DEVIATION_TIME_MS.
+// The fault flag goes out in 0x102 along with the malfunction.
+fn deviation_check(elapsed: u32, vehicle: &mut VehicleState, status: &EvseStatus109) {
+    if status.present_voltage > vehicle.config.max_battery_voltage {
+        vehicle.faults.battery_over_voltage = true;
+        fault(vehicle, elapsed, "Over Volt");
+        return;
+    }
+
+    if status.present_current <= vehicle.current_request.saturating_add(CURRENT_DEVIATION_A) {
+        vehicle.current_deviation_ts = elapsed;
+    } else if elapsed.wrapping_sub(vehicle.current_deviation_ts) > DEVIATION_TIME_MS {
+        vehicle.faults.current_deviation = true;
+        fault(vehicle, elapsed, "Curr Dev");
+        return;
+    }
+
+    let measured = vehicle.config.battery_voltage;
+    let voltage_ok = measured == 0
+        || (status.present_voltage as i32 - measured as i32).abs() <= VOLTAGE_DEVIATION_V as i32;
+    if voltage_ok {
+        vehicle.voltage_deviation_ts = elapsed;
+    } else if elapsed.wrapping_sub(vehicle.voltage_deviation_ts) > DEVIATION_TIME_MS {
+        vehicle.faults.voltage_deviation = true;
+        fault(vehicle, elapsed, "Volt Dev");
+    }
+}
+
+// Run every 100 ms: timed transitions, then the vehicle frames. A faulted vehicle keeps sending,
+// so the charger sees the malfunction.
+pub fn hundred_ms_loop<C: CanBus>(elapsed: u32, vehicle: &mut VehicleState, fc_can: &mut C) {
+    match vehicle.state {
+        VehicleStateEnum::Idle | VehicleStateEnum::Stopped => return,
+        VehicleStateEnum::WaitEvse
+            if vehicle.config.evse_response_timeout > 0
+                && elapsed.wrapping_sub(vehicle.evse_ts) > vehicle.config.evse_response_timeout =>
+        {
+            fault(vehicle, elapsed, "No EVSE response");
+        }
+        // The contactors are powered through d2, they can't close before the charger sets it.
+        VehicleStateEnum::WaitInsulation
+            if vehicle.d2
+                && elapsed.wrapping_sub(vehicle.state_ts) >= vehicle.config.contactor_delay =>
+        {
+            vehicle.contactor_open = false;
+            vehicle.current_request = vehicle.config.current_at(0);
+            vehicle.charge_start_ts = elapsed;
+            vehicle.current_deviation_ts = elapsed;
+            vehicle.voltage_deviation_ts = elapsed;
+            set_state(vehicle, VehicleStateEnum::Charging, elapsed);
+            add_to_activity_list!(vehicle, "{} - WaitInsulation -> Charging", elapsed);
+        }
+        VehicleStateEnum::Charging => {
+            let charge_ms = elapsed.wrapping_sub(vehicle.charge_start_ts);
+            vehicle.current_request = vehicle.config.current_at(charge_ms);
+            if vehicle.config.charge_time > 0 && charge_ms >= vehicle.config.charge_time {
+                stop(vehicle, elapsed);
+            }
+        }
+        // Charger stops sending 0x109 once it has shut down.
+        VehicleStateEnum::Stopping
+            if elapsed.wrapping_sub(vehicle.evse_ts) > EVSE_COMM_TIMEOUT_MS =>
+        {
+            vehicle.contactor_open = true;
+            set_state(vehicle, VehicleStateEnum::Stopped, elapsed);
+            add_to_activity_list!(vehicle, "{} - Stopping -> Stopped (EVSE quiet)", elapsed);
+            return;
+        }
+        _ => {}
+    }
+
+    match vehicle.state {
+        VehicleStateEnum::WaitLock
+        | VehicleStateEnum::WaitInsulation
+        | VehicleStateEnum::Charging
+            if elapsed.wrapping_sub(vehicle.evse_ts) > EVSE_COMM_TIMEOUT_MS =>
+        {
+            fault(vehicle, elapsed, "EVSE comm timeout");
+        }
+        _ => {}
+    }
+
+    fc_can.send_frame(&CanMessage::new(
+        VEHICLE_PARAMS_ID,
+        &vehicle.params100().encode(),
+    ));
+    fc_can.send_frame(&CanMessage::new(
+        VEHICLE_TIME_ID,
+        &vehicle.time101().encode(),
+    ));
+    fc_can.send_frame(&CanMessage::new(
+        VEHICLE_STATUS_ID,
+        &vehicle.status102().encode(),
+    ));
+    if vehicle.config.max_discharge_current > 0 {
+        fc_can.send_frame(&CanMessage::new(
+            VEHICLE_DISCHARGE_ID,
+            &vehicle.discharge200().encode(),
+        ));
+    }
+}
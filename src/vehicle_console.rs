@@ -0,0 +1,106 @@
+#![deny(warnings)]
+// Serial console for the vehicle firmware (can-dc-ev). Single key commands, like the charger's:
+// start / stop, and the targets sent in 0x100/0x102 nudged up and down.
+use crate::add_to_activity_list;
+use crate::interfaces::TextSink;
+use crate::vehicle::*;
+use crate::{uprint, uprintln};
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+pub const VOLTAGE_STEP: u16 = 10;
+pub const CURRENT_STEP: u8 = 1;
+pub const SOC_STEP: u8 = 5;
+
+pub fn init<W: TextSink>(command: u8, elapsed: u32, vehicle: &mut VehicleState, tx: &mut W) {
+    let config = &mut vehicle.config;
+    match command {
+        // c
+        0x63 => match vehicle.state {
+            VehicleStateEnum::Idle | VehicleStateEnum::Stopped | VehicleStateEnum::Fault => {
+                start(vehicle, elapsed);
+            }
+            _ => {
+                add_to_activity_list!(vehicle, "{} - Already started.", elapsed);
+            }
+        },
+        // C
+        0x43 => {
+            add_to_activity_list!(vehicle, "{} - User initiated stop.", elapsed);
+            stop(vehicle, elapsed);
+        }
+        // t / T - Target voltage, the maximum follows it up.
+        0x74 => {
+            config.target_voltage = config.target_voltage.saturating_sub(VOLTAGE_STEP);
+        }
+        0x54 => {
+            config.target_voltage = config.target_voltage.saturating_add(VOLTAGE_STEP);
+            if config.max_battery_voltage < config.target_voltage {
+                config.max_battery_voltage = config.target_voltage;
+            }
+        }
+        // a / A - Current request, for the rest of the session.
+        0x61 => {
+            config.current_steps = [(0, config.current_at(0).saturating_sub(CURRENT_STEP)); 4];
+        }
+        0x41 => {
+            config.current_steps = [(0, config.current_at(0).saturating_add(CURRENT_STEP)); 4];
+        }
+        // s / S - State of charge.
+        0x73 => {
+            config.state_of_charge = config.state_of_charge.saturating_sub(SOC_STEP);
+        }
+        0x53 => {
+            config.state_of_charge = (config.state_of_charge + SOC_STEP).min(100);
+        }
+        // m
+        0x6D => print_menu(tx),
+        _ => {
+            add_to_activity_list!(vehicle, "{} - Invalid selection!", elapsed);
+        }
+    }
+}
+
+pub fn print_menu<W: TextSink>(tx: &mut W) {
+    uprintln!(tx, "Commands: ");
+    uprintln!(tx, "c / C - Plug in and start / Stop.");
+    uprintln!(tx, "t / T - Target voltage down / up {} V.", VOLTAGE_STEP);
+    uprintln!(tx, "a / A - Current request down / up {} A.", CURRENT_STEP);
+    uprintln!(tx, "s / S - State of charge down / up {}%.", SOC_STEP);
+    uprintln!(tx, "m - Show this menu.");
+}
+
+// Activity as it happens, and the status line when asked for.
+pub fn display<W: TextSink>(tx: &mut W, vehicle: &mut VehicleState, sys_ticks: u32, status: bool) {
+    while let Some(entry) = vehicle.activity_list.pop_front() {
+        uprintln!(tx, "{}", entry);
+    }
+    if !status {
+        return;
+    }
+    let config = &vehicle.config;
+    uprint!(
+        tx,
+        "State: {}  Tgt: {} V {} A  SoC: {}%  j: {}  ",
+        vehicle.state,
+        config.target_voltage,
+        config.current_at(0),
+        config.state_of_charge,
+        if vehicle.charge_permission { "Y" } else { "N" },
+    );
+    if let Some(status) = vehicle.evse_status {
+        uprint!(
+            tx,
+            "EVSE: {} V {} A  ",
+            status.present_voltage,
+            status.present_current
+        );
+    }
+    if let Some(discharge) = vehicle.evse_discharge {
+        uprint!(tx, "V2H: {} A  ", discharge.present_discharge_current);
+    }
+    uprintln!(tx, "Uptime: {}", sys_ticks);
+}
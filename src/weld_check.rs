@@ -0,0 +1,59 @@
+#![deny(warnings)]
+// Contactor welding detection after a normal stop. The output stays off but measured while the
+// vehicle opens its contactors. Once they are reported open the output is discharged, and with
+// the battery really disconnected it drops below WELD_CHECK_VOLTAGE. If it doesn't within
+// WELD_CHECK_TIMEOUT_MS, a contactor is welded.
+use crate::add_to_activity_list;
+use crate::interfaces::PowerStage;
+use crate::types::*;
+
+// Logging
+use heapless::consts::U60;
+use heapless::String;
+use ufmt::uwrite;
+
+// Run every pass of the main loop, after control_loop has turned the output off.
+pub fn init<P: PowerStage>(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    car_state: &CarState,
+    power_stage: &mut P,
+) {
+    if cd_state.charge_state != ChargeStateEnum::WeldCheck {
+        return;
+    }
+
+    let measured = power_stage.measured_voltage();
+    cd_state.current_voltage = measured;
+    // Don't load the battery through the discharge circuit while it is still connected.
+    power_stage.set_discharge(car_state.contactor_open);
+
+    if car_state.contactor_open && measured < WELD_CHECK_VOLTAGE {
+        finish(elapsed, cd_state, power_stage, WeldCheckEnum::Passed);
+    } else if elapsed.wrapping_sub(cd_state.weld_check_ts) > WELD_CHECK_TIMEOUT_MS {
+        let result = if car_state.contactor_open {
+            WeldCheckEnum::Welded
+        } else {
+            WeldCheckEnum::ContactorsClosed
+        };
+        finish(elapsed, cd_state, power_stage, result);
+    }
+}
+
+fn finish<P: PowerStage>(
+    elapsed: u32,
+    cd_state: &mut CDState,
+    power_stage: &mut P,
+    result: WeldCheckEnum,
+) {
+    power_stage.set_discharge(false);
+    cd_state.session_summary.weld_check = result;
+    cd_state.charge_state = ChargeStateEnum::StopCharge;
+    add_to_activity_list!(
+        cd_state,
+        "{} - Weld {}, {} V -> StopCharge",
+        elapsed,
+        result.as_str(),
+        cd_state.current_voltage
+    );
+}
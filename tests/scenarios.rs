@@ -0,0 +1,2297 @@
+#![deny(warnings)]
+// Golden charge sessions. A scripted vehicle sends 0x100/0x101/0x102 (and 0x110 / 0x200 when it
+// has them) every 100 ms, steps change what it sends, type on the console or work the fault line,
+// and after each step the charge state, relay flags, latch and the 0x108/0x109 frames from the
+// last run of the 100 ms loop are checked. The charger is the firmware's MainLoop, a pass every
+// ms. GB/T sessions swap the vehicle for a BMS that answers whatever the charger sent in the
+// previous 100 ms.
+use can_dc_fc::can_receive_logic::init as can_receive_logic;
+use can_dc_fc::can_rx_queue::{split, RxCounters, RxQueue};
+use can_dc_fc::chademo::*;
+use can_dc_fc::chademo_transmit::evse_status;
+use can_dc_fc::comm_watchdog;
+use can_dc_fc::gbt;
+use can_dc_fc::gbt_transport::{TransportReceiver, TP_ABORT, TP_CTS, TP_RTS};
+use can_dc_fc::interfaces::{CanMessage, CanRxCounters};
+use can_dc_fc::main_loop::{MainLoop, Peripherals};
+use can_dc_fc::mock::{
+    MockCan, MockLock, MockRelay, MockSequenceInputs, MockSink, SimulatedInsulation,
+    SimulatedSupply,
+};
+use can_dc_fc::serial_console;
+use can_dc_fc::serial_tx_queue::{self, TxQueue};
+use can_dc_fc::session::update_sequence_lines;
+use can_dc_fc::types::ChargeStateEnum::*;
+use can_dc_fc::types::*;
+use can_dc_fc::vehicle::{self, VehicleConfig, VehicleState, VehicleStateEnum};
+use can_dc_fc::weld_check;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+const TICK_MS: u32 = 100;
+const TARGET_VOLTAGE: u16 = 400;
+const BATTERY_VOLTAGE: u16 = 360;
+const BATTERY_RESISTANCE_MOHM: u32 = 100;
+const PASS_MS: u32 = 1; // The firmware runs a main loop pass every ms
+
+enum Event {
+    Key(u8),
+    Vehicle(fn(&mut VehicleStatus102)),
+    Silent,
+    MaxChargeTime(u8), // 0x101, 10 s units
+    Insulation(u32),
+    Weld,
+    LockStuck,
+    Permission(bool), // j
+    Unplug,
+    OfferDischarge(u8), // 0x200 with this maximum current, A
+    Bms(fn(&mut Bms)),
+    Fault(bool),      // The fault line, held
+    FaultGlitch(u32), // The fault line for this many ms
+    Wait,
+}
+
+struct Expect {
+    state: ChargeStateEnum,
+    switch_one: bool,
+    switch_two: bool,
+    latch_enabled: bool,
+    tx108: Option<[u8; FRAME_LENGTH]>,
+    tx109: Option<[u8; FRAME_LENGTH]>,
+}
+
+struct Step {
+    at: u32,
+    event: Event,
+    expect: Expect,
+}
+
+struct Vehicle {
+    params: VehicleParams100,
+    time: VehicleTime101,
+    status: VehicleStatus102,
+    extended: Option<VehicleExtended110>,
+    discharge: Option<VehicleDischarge200>,
+    talking: bool,
+}
+
+impl Vehicle {
+    fn new() -> Self {
+        Self {
+            params: VehicleParams100 {
+                minimum_charge_current: 0,
+                minimum_battery_voltage: 300,
+                maximum_battery_voltage: 410,
+                charged_rate_reference: 100,
+            },
+            time: VehicleTime101 {
+                max_charge_time_10s: 0xFF,
+                max_charge_time_1min: 90,
+                estimated_charge_time: 60,
+                battery_capacity: 240,
+            },
+            status: VehicleStatus102 {
+                protocol_number: 1,
+                target_voltage: TARGET_VOLTAGE,
+                current_request: 0,
+                faults: VehicleFaults::default(),
+                status: VehicleStatusFlags::default(),
+                state_of_charge: 50,
+            },
+            extended: None,
+            discharge: None,
+            talking: true,
+        }
+    }
+
+    fn frames(&self) -> Vec<CanMessage> {
+        let mut frames = vec![
+            CanMessage::new(VEHICLE_PARAMS_ID, &self.params.encode()),
+            CanMessage::new(VEHICLE_TIME_ID, &self.time.encode()),
+            CanMessage::new(VEHICLE_STATUS_ID, &self.status.encode()),
+        ];
+        if let Some(extended) = self.extended {
+            frames.push(CanMessage::new(VEHICLE_EXTENDED_ID, &extended.encode()));
+        }
+        if let Some(discharge) = self.discharge {
+            frames.push(CanMessage::new(VEHICLE_DISCHARGE_ID, &discharge.encode()));
+        }
+        frames
+    }
+}
+
+// GB/T BMS. Each 100 ms it sends what the last charger frames call for: BHM to CHM, BRM to CRM
+// 00, BCP to CRM AA, BRO to CML, then BCL / BCS / BSM once the charger is ready, and BST / BSD
+// when either side stops. BRM, BCP and BCS go as an RTS and their TP.DT packets.
+struct Bms {
+    ready: bool,
+    stop: bool,
+    fault: bool,
+    voltage_request: u16, // 0.1 V
+    current_request: u16, // 0.1 A
+    state_of_charge: u8,
+    received: HashMap<u32, [u8; gbt::FRAME_LENGTH]>, // Last charger frame for each PGN
+}
+
+impl Bms {
+    fn new() -> Self {
+        Self {
+            ready: true,
+            stop: false,
+            fault: false,
+            voltage_request: TARGET_VOLTAGE * 10,
+            current_request: 200,
+            state_of_charge: 50,
+            received: HashMap::new(),
+        }
+    }
+
+    fn receive(&mut self, sent: &[CanMessage]) {
+        for message in sent.iter().filter(|message| message.extended) {
+            assert_eq!(gbt::source(message.id), gbt::CHARGER_ADDRESS);
+            self.received.insert(gbt::pgn(message.id), message.data);
+        }
+    }
+
+    fn received(&self, pgn: u32) -> Option<[u8; gbt::FRAME_LENGTH]> {
+        self.received.get(&pgn).copied()
+    }
+
+    fn frames(&self) -> Vec<CanMessage> {
+        let stopped_by_charger = self.received(gbt::CST_PGN).is_some();
+        let charger_ready = self.received(gbt::CRO_PGN).map(|data| data[0]) == Some(gbt::READY);
+        let recognized = self.received(gbt::CRM_PGN).map(|data| data[0]);
+        if self.stop || stopped_by_charger {
+            let stop = gbt::Stop {
+                reason: gbt::BST_SOC_REACHED,
+                faults: 0,
+                errors: 0,
+            };
+            let mut frames = vec![bms_frame(gbt::BST_PGN, &stop.encode())];
+            if stopped_by_charger {
+                let statistics = gbt::BmsStatistics {
+                    state_of_charge: self.state_of_charge,
+                    min_cell_voltage: 360,
+                    max_cell_voltage: 380,
+                };
+                frames.push(bms_frame(gbt::BSD_PGN, &statistics.encode()));
+            }
+            frames
+        } else if charger_ready {
+            let requirement = gbt::BmsRequirement {
+                voltage_request: self.voltage_request,
+                current_request: self.current_request,
+                constant_current: true,
+            };
+            let status = gbt::BmsStatus {
+                measured_voltage: BATTERY_VOLTAGE * 10,
+                measured_current: self.current_request,
+                max_cell_voltage: 380,
+                state_of_charge: self.state_of_charge,
+                remaining_time: 60,
+            };
+            let battery = gbt::BmsBatteryStatus {
+                over_temperature: self.fault,
+                charge_permitted: true,
+                ..gbt::BmsBatteryStatus::default()
+            };
+            let mut frames = vec![bms_frame(gbt::BCL_PGN, &requirement.encode())];
+            frames.extend(multi_packet(gbt::BCS_PGN, &status.encode()));
+            frames.push(bms_frame(gbt::BSM_PGN, &battery.encode()));
+            frames
+        } else if self.received(gbt::CML_PGN).is_some() {
+            let ready = gbt::Ready { ready: self.ready };
+            vec![bms_frame(gbt::BRO_PGN, &ready.encode())]
+        } else if recognized == Some(gbt::READY) {
+            let parameters = gbt::BmsParameters {
+                max_cell_voltage: 420,
+                max_charge_current: 1000,
+                nominal_energy: 240,
+                max_charge_voltage: 4100,
+                max_temperature: 100,
+                state_of_charge: self.state_of_charge as u16 * 10,
+                battery_voltage: BATTERY_VOLTAGE * 10,
+            };
+            multi_packet(gbt::BCP_PGN, &parameters.encode())
+        } else if recognized == Some(gbt::NOT_READY) {
+            let recognition = gbt::BmsRecognition {
+                protocol_version: gbt::PROTOCOL_VERSION,
+                battery_type: 0x03,
+                rated_capacity: 600,
+                rated_voltage: 4000,
+            };
+            // The skipped fields (manufacturer, VIN, ...) as not available.
+            let mut brm = [0xFFu8; gbt::BRM_LENGTH];
+            brm[..gbt::FRAME_LENGTH].copy_from_slice(&recognition.encode());
+            multi_packet(gbt::BRM_PGN, &brm)
+        } else if self.received(gbt::CHM_PGN).is_some() {
+            let handshake = gbt::BmsHandshake {
+                max_charge_voltage: 4100,
+            };
+            vec![bms_frame(gbt::BHM_PGN, &handshake.encode())]
+        } else {
+            vec![]
+        }
+    }
+}
+
+fn bms_frame(pgn: u32, data: &[u8]) -> CanMessage {
+    let id = gbt::frame_id(pgn, gbt::CHARGER_ADDRESS, gbt::BMS_ADDRESS);
+    CanMessage::new_extended(id, data)
+}
+
+fn multi_packet(pgn: u32, data: &[u8]) -> Vec<CanMessage> {
+    let packets = data.len().div_ceil(7) as u8;
+    let [size_low, size_high] = (data.len() as u16).to_le_bytes();
+    let [pgn_low, pgn_mid, pgn_high, _] = pgn.to_le_bytes();
+    let rts = [
+        TP_RTS, size_low, size_high, packets, 0xFF, pgn_low, pgn_mid, pgn_high,
+    ];
+    let mut frames = vec![bms_frame(gbt::TP_CM_PGN, &rts)];
+    for (index, chunk) in data.chunks(7).enumerate() {
+        let mut packet = [0xFF; gbt::FRAME_LENGTH];
+        packet[0] = index as u8 + 1;
+        packet[1..1 + chunk.len()].copy_from_slice(chunk);
+        frames.push(bms_frame(gbt::TP_DT_PGN, &packet));
+    }
+    frames
+}
+
+// The simulated charger hardware, as the firmware has it but with mock relays, lock and lines.
+type Io = Peripherals<
+    MockRelay,
+    MockRelay,
+    SimulatedInsulation,
+    MockLock,
+    SimulatedSupply,
+    MockSequenceInputs,
+>;
+
+struct Session {
+    now: u32,
+    main_loop: MainLoop,
+    vehicle: Vehicle,
+    bms: Option<Bms>, // Instead of the vehicle, for GB/T
+    fc_can: MockCan,
+    io: Io,
+    fault: bool,
+    fault_glitch_ms: u32, // Fault for this long at the start of the next tick
+    welded: bool,         // Battery stays connected whatever the vehicle reports
+}
+
+impl Session {
+    fn new() -> Self {
+        let mut session = Self {
+            now: 0,
+            main_loop: MainLoop::new(),
+            vehicle: Vehicle::new(),
+            bms: None,
+            fc_can: MockCan::new(),
+            io: Peripherals {
+                connector_lock: MockLock::new(),
+                d1: MockRelay::new(),
+                d2: MockRelay::new(),
+                insulation_monitor: SimulatedInsulation::new(10_000_000),
+                power_stage: SimulatedSupply::new(BATTERY_VOLTAGE, BATTERY_RESISTANCE_MOHM),
+                sequence_inputs: MockSequenceInputs::new(),
+            },
+            fault: false,
+            fault_glitch_ms: 0,
+            welded: false,
+        };
+        // CHAdeMO unless a test says otherwise, whatever the build's default.
+        session.main_loop.cd_state.charger_config.protocol = ProtocolEnum::Chademo;
+        // Sampled once up front, so a key at 0 ms sees the connector.
+        update_sequence_lines(
+            session.io.sequence_inputs.charge_permission,
+            session.io.sequence_inputs.connector_detected,
+            session.now,
+            &mut session.main_loop.cd_state,
+            &mut session.main_loop.car_state,
+        );
+        session
+    }
+
+    // One 100 ms period: the vehicle's frames (if it is talking) arrive at the start, then a
+    // main loop pass every ms as the firmware's pass task runs it, with the 100 ms loop after the
+    // last one.
+    fn tick(&mut self) {
+        if let Some(bms) = self.bms.as_mut() {
+            bms.receive(&self.fc_can.sent);
+        }
+        self.fc_can.clear_sent();
+        let frames = match self.bms.as_ref() {
+            Some(bms) => bms.frames(),
+            None => self.vehicle.frames(),
+        };
+        if self.vehicle.talking {
+            for frame in frames.iter() {
+                self.fc_can.push_rx_message(*frame);
+            }
+        }
+        for offset in (0..TICK_MS).step_by(PASS_MS as usize) {
+            let elapsed = self.now + offset;
+            let fault = self.fault || offset < self.fault_glitch_ms;
+            let fault_line_high = fault == FAULT_ACTIVE_HIGH;
+            let power_stage = &mut self.io.power_stage;
+            power_stage.battery_connected = !self.main_loop.car_state.contactor_open || self.welded;
+            power_stage.update(elapsed);
+            self.main_loop
+                .pass(&mut self.io, &mut self.fc_can, elapsed, fault_line_high);
+            if offset == TICK_MS - PASS_MS {
+                self.main_loop.hundred_ms(&mut self.fc_can, elapsed);
+            }
+        }
+        self.fault_glitch_ms = 0;
+        self.now += TICK_MS;
+    }
+
+    fn apply(&mut self, event: &Event) {
+        match event {
+            Event::Key(key) => self.main_loop.serial_input(*key, self.now),
+            Event::Vehicle(change) => change(&mut self.vehicle.status),
+            Event::Silent => self.vehicle.talking = false,
+            Event::MaxChargeTime(time) => self.vehicle.time.max_charge_time_10s = *time,
+            Event::Insulation(ohms) => self.io.insulation_monitor.resistance_ohms = *ohms,
+            Event::Weld => self.welded = true,
+            Event::LockStuck => self.io.connector_lock.stuck = true,
+            Event::Permission(on) => self.io.sequence_inputs.charge_permission = *on,
+            Event::Unplug => self.io.sequence_inputs.connector_detected = false,
+            Event::OfferDischarge(current) => {
+                self.vehicle.discharge = Some(VehicleDischarge200 {
+                    maximum_discharge_current: *current,
+                    minimum_discharge_voltage: 300,
+                    minimum_discharge_soc: 30,
+                    maximum_charge_soc: 100,
+                })
+            }
+            Event::Bms(change) => {
+                if let Some(bms) = self.bms.as_mut() {
+                    change(bms)
+                }
+            }
+            Event::Fault(on) => self.fault = *on,
+            Event::FaultGlitch(ms) => self.fault_glitch_ms = *ms,
+            Event::Wait => {}
+        }
+    }
+
+    fn sent(&self, id: u32) -> Option<[u8; FRAME_LENGTH]> {
+        self.fc_can.last_sent(id).map(|message| message.data)
+    }
+}
+
+fn run(name: &str, steps: &[Step]) -> Session {
+    run_on(Session::new(), name, steps)
+}
+
+// Carries on from where an earlier run left off.
+fn run_on(mut session: Session, name: &str, steps: &[Step]) -> Session {
+    for (index, step) in steps.iter().enumerate() {
+        while session.now < step.at {
+            session.tick();
+        }
+        session.apply(&step.event);
+        session.tick();
+
+        let context = format!("{} step {} at {} ms", name, index, step.at);
+        let expect = &step.expect;
+        assert_eq!(
+            session.main_loop.cd_state.charge_state, expect.state,
+            "{}: state",
+            context
+        );
+        assert_eq!(
+            session.main_loop.cd_state.switch_one, expect.switch_one,
+            "{}: switch_one",
+            context
+        );
+        assert_eq!(
+            session.main_loop.cd_state.switch_two, expect.switch_two,
+            "{}: switch_two",
+            context
+        );
+        assert_eq!(
+            session.main_loop.cd_state.latch_enabled, expect.latch_enabled,
+            "{}: latch",
+            context
+        );
+        assert_eq!(
+            session.sent(EVSE_PARAMS_ID),
+            expect.tx108,
+            "{}: 0x108",
+            context
+        );
+        assert_eq!(
+            session.sent(EVSE_STATUS_ID),
+            expect.tx109,
+            "{}: 0x109",
+            context
+        );
+    }
+    session
+}
+
+fn expect(
+    state: ChargeStateEnum,
+    switch_one: bool,
+    switch_two: bool,
+    latch_enabled: bool,
+    tx108: Option<[u8; FRAME_LENGTH]>,
+    tx109: Option<[u8; FRAME_LENGTH]>,
+) -> Expect {
+    Expect {
+        state,
+        switch_one,
+        switch_two,
+        latch_enabled,
+        tx108,
+        tx109,
+    }
+}
+
+// Default ChargerConfig: weld detection, 430 V / 32 A available, 430 V threshold.
+fn params108() -> Option<[u8; FRAME_LENGTH]> {
+    Some([0x01, 0xAE, 0x01, 0x20, 0xAE, 0x01, 0x00, 0x00])
+}
+
+// Remaining time is (10 s, 1 min) units. The vehicle's 90 minute maximum counts down once
+// charging starts, and is 0 once the session is over.
+fn status109(
+    voltage: u16,
+    current: u8,
+    flags: u8,
+    (remaining_10s, remaining_1min): (u8, u8),
+) -> Option<[u8; FRAME_LENGTH]> {
+    let [low, high] = voltage.to_le_bytes();
+    Some([
+        0x01,
+        low,
+        high,
+        current,
+        0x00,
+        flags,
+        remaining_10s,
+        remaining_1min,
+    ])
+}
+
+const MAX_TIME: (u8, u8) = (0xFF, 90);
+const NO_TIME: (u8, u8) = (0, 0);
+
+// EvseStatusFlags bits
+const CHARGING: u8 = 0x01;
+const ERROR: u8 = 0x02;
+const LOCKED: u8 = 0x04;
+const INCOMPATIBLE: u8 = 0x08;
+const BATTERY_ERROR: u8 = 0x10;
+const STOPPED: u8 = 0x20;
+
+fn enable(status: &mut VehicleStatus102) {
+    status.status.charging_enabled = true;
+}
+
+fn start(status: &mut VehicleStatus102) {
+    status.status.contactor_open = false;
+    status.current_request = 10;
+}
+
+fn finish(status: &mut VehicleStatus102) {
+    status.status.charging_enabled = false;
+    status.current_request = 0;
+}
+
+fn open_contactors(status: &mut VehicleStatus102) {
+    status.status.contactor_open = true;
+}
+
+fn disable(status: &mut VehicleStatus102) {
+    status.status.charging_enabled = false;
+}
+
+fn malfunction(status: &mut VehicleStatus102) {
+    status.status.malfunction = true;
+}
+
+fn over_voltage(status: &mut VehicleStatus102) {
+    status.faults.battery_over_voltage = true;
+}
+
+// Start from the console, vehicle answers straight away and enables charging. The insulation test
+// raises the output to the vehicle's 410 V maximum (500), measures (1500) and discharges at
+// 1000 V/s to below 20 V (1800). Then the vehicle closes its contactors.
+fn into_charge_loop() -> Vec<Step> {
+    vec![
+        Step {
+            at: 0,
+            event: Event::Key(b'c'),
+            expect: expect(
+                WaitChargeEnable,
+                true,
+                false,
+                false,
+                params108(),
+                status109(0, 0, STOPPED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 500,
+            event: Event::Vehicle(enable),
+            expect: expect(
+                InsulationTest,
+                true,
+                false,
+                true,
+                params108(),
+                status109(410, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 1_700,
+            event: Event::Wait,
+            expect: expect(
+                InsulationTest,
+                true,
+                false,
+                true,
+                params108(),
+                status109(112, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 1_800,
+            event: Event::Wait,
+            expect: expect(
+                WaitVehicleChargeStart,
+                true,
+                true,
+                true,
+                params108(),
+                status109(19, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 3_500,
+            event: Event::Vehicle(start),
+            expect: charging(360, 2, 90),
+        },
+    ]
+}
+
+fn charging(voltage: u16, current: u8, minutes: u8) -> Expect {
+    expect(
+        ChargeLoop,
+        true,
+        true,
+        true,
+        params108(),
+        status109(voltage, current, CHARGING | LOCKED, (0xFF, minutes)),
+    )
+}
+
+fn weld_check_at(voltage: u16) -> Expect {
+    expect(
+        WeldCheck,
+        true,
+        true,
+        true,
+        params108(),
+        status109(voltage, 0, STOPPED | LOCKED, NO_TIME),
+    )
+}
+
+// Stopped with the output off, the connector stays locked until the voltage is down.
+fn discharging(voltage: u16, flags: u8) -> Expect {
+    expect(
+        Discharge,
+        false,
+        false,
+        true,
+        params108(),
+        status109(voltage, 0, STOPPED | LOCKED | flags, NO_TIME),
+    )
+}
+
+// The unlock goes out in 0x109 once, then transmission stops in the same pass.
+fn unlocked(voltage: u16, flags: u8) -> Expect {
+    expect(
+        ChargeIdle,
+        false,
+        false,
+        false,
+        params108(),
+        status109(voltage, 0, STOPPED | flags, NO_TIME),
+    )
+}
+
+fn idle() -> Expect {
+    expect(ChargeIdle, false, false, false, None, None)
+}
+
+#[test]
+fn normal_session() {
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 60_000,
+            event: Event::Wait,
+            expect: charging(361, 10, 89),
+        },
+        // Output off, the battery is still there until the vehicle opens its contactors.
+        Step {
+            at: 60_100,
+            event: Event::Vehicle(finish),
+            expect: weld_check_at(360),
+        },
+        // Bleeds down, then discharges at 1000 V/s.
+        Step {
+            at: 60_200,
+            event: Event::Vehicle(open_contactors),
+            expect: weld_check_at(261),
+        },
+        Step {
+            at: 60_400,
+            event: Event::Wait,
+            expect: weld_check_at(61),
+        },
+        Step {
+            at: 60_500,
+            event: Event::Wait,
+            expect: discharging(59, 0),
+        },
+        Step {
+            at: 60_600,
+            event: Event::Wait,
+            expect: unlocked(9, 0),
+        },
+        Step {
+            at: 60_700,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ]);
+    let session = run("normal session", &steps);
+    let summary = session.main_loop.cd_state.session_summary;
+    assert_eq!(summary.weld_check, WeldCheckEnum::Passed);
+    assert_eq!(summary.charge_time, 56);
+    assert_eq!(summary.max_current, 10);
+    assert_eq!(summary.max_voltage, 361);
+}
+
+// The voltage stays up after the vehicle reports its contactors open.
+#[test]
+fn welded_contactor() {
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 10_000,
+            event: Event::Weld,
+            expect: charging(361, 10, 89),
+        },
+        Step {
+            at: 10_100,
+            event: Event::Vehicle(finish),
+            expect: weld_check_at(360),
+        },
+        Step {
+            at: 10_200,
+            event: Event::Vehicle(open_contactors),
+            expect: weld_check_at(360),
+        },
+        Step {
+            at: 14_000,
+            event: Event::Wait,
+            expect: weld_check_at(360),
+        },
+        Step {
+            at: 14_100,
+            event: Event::Wait,
+            expect: discharging(360, 0),
+        },
+        // The battery holds the output up, so the connector stays locked and the error bit is set.
+        Step {
+            at: 19_100,
+            event: Event::Wait,
+            expect: discharging(360, 0),
+        },
+        Step {
+            at: 19_200,
+            event: Event::Wait,
+            expect: discharging(360, ERROR),
+        },
+    ]);
+    let session = run("welded contactor", &steps);
+    assert!(session.main_loop.cd_state.discharge_fault);
+    // Not left loading the battery.
+    assert!(!session.io.power_stage.discharging);
+    assert_eq!(
+        session.main_loop.cd_state.session_summary.weld_check,
+        WeldCheckEnum::Welded
+    );
+}
+
+// Rises at 20 A/s (2 A per 100 ms), stops at the charger's 32 A, follows a lower request at once.
+#[test]
+fn current_ramp_and_clamp() {
+    let mut steps = into_charge_loop();
+    steps.pop();
+    steps.extend(vec![
+        Step {
+            at: 3_500,
+            event: Event::Vehicle(|status| {
+                status.status.contactor_open = false;
+                status.current_request = 50;
+            }),
+            expect: charging(360, 2, 90),
+        },
+        Step {
+            at: 4_000,
+            event: Event::Wait,
+            expect: charging(361, 12, 90),
+        },
+        Step {
+            at: 5_000,
+            event: Event::Wait,
+            expect: charging(363, 32, 89),
+        },
+        Step {
+            at: 5_100,
+            event: Event::Wait,
+            expect: charging(363, 32, 89),
+        },
+        Step {
+            at: 6_000,
+            event: Event::Vehicle(|status| status.current_request = 5),
+            expect: charging(360, 5, 89),
+        },
+    ]);
+    run("current ramp", &steps);
+}
+
+#[test]
+fn vehicle_disables_charge_before_start() {
+    let mut steps = into_charge_loop();
+    steps.truncate(4);
+    steps.extend(vec![
+        Step {
+            at: 3_400,
+            event: Event::Vehicle(disable),
+            expect: discharging(19, 0),
+        },
+        Step {
+            at: 3_500,
+            event: Event::Wait,
+            expect: unlocked(0, 0),
+        },
+        Step {
+            at: 3_600,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ]);
+    run("vehicle disables charge", &steps);
+}
+
+#[test]
+fn malfunction_while_charging() {
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 4_000,
+            event: Event::Vehicle(malfunction),
+            expect: discharging(361, BATTERY_ERROR),
+        },
+        Step {
+            at: 4_100,
+            event: Event::Vehicle(open_contactors),
+            expect: discharging(260, BATTERY_ERROR),
+        },
+        Step {
+            at: 4_400,
+            event: Event::Wait,
+            expect: unlocked(9, BATTERY_ERROR),
+        },
+        Step {
+            at: 4_500,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ]);
+    run("malfunction", &steps);
+}
+
+// A 0x102 fault bit stops the charge at once, through StopCharge with d1 / d2 released.
+#[test]
+fn vehicle_fault_while_charging() {
+    let mut steps = into_charge_loop();
+    steps.push(Step {
+        at: 4_000,
+        event: Event::Vehicle(over_voltage),
+        expect: discharging(262, BATTERY_ERROR),
+    });
+    let session = run("vehicle fault", &steps);
+    let activity = &session.main_loop.cd_state.activity_list;
+    assert!(activity
+        .iter()
+        .any(|line| line.as_str() == "4000 - Vehicle fault -> StopCharge (Over Volt)"));
+    assert!(!session.io.d1.closed);
+    assert!(!session.io.d2.closed);
+}
+
+#[test]
+fn user_stop() {
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 4_000,
+            event: Event::Key(b'C'),
+            expect: discharging(360, 0),
+        },
+        Step {
+            at: 4_100,
+            event: Event::Vehicle(open_contactors),
+            expect: discharging(261, 0),
+        },
+        Step {
+            at: 4_400,
+            event: Event::Wait,
+            expect: unlocked(9, 0),
+        },
+        Step {
+            at: 4_500,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ]);
+    run("user stop", &steps);
+}
+
+// The vehicle's maximum charge time runs out, the charger ends it through the weld check.
+#[test]
+fn max_charge_time_expires() {
+    let remaining = |voltage, current, time| {
+        expect(
+            ChargeLoop,
+            true,
+            true,
+            true,
+            params108(),
+            status109(voltage, current, CHARGING | LOCKED, time),
+        )
+    };
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        // 60 s, counted from the start of charging at 3500.
+        Step {
+            at: 4_000,
+            event: Event::MaxChargeTime(6),
+            expect: remaining(361, 10, (6, 1)),
+        },
+        Step {
+            at: 63_400,
+            event: Event::Wait,
+            expect: remaining(361, 10, (0, 0)),
+        },
+        Step {
+            at: 63_500,
+            event: Event::Wait,
+            expect: weld_check_at(360),
+        },
+        Step {
+            at: 63_600,
+            event: Event::Vehicle(open_contactors),
+            expect: weld_check_at(261),
+        },
+        Step {
+            at: 63_900,
+            event: Event::Wait,
+            expect: discharging(59, 0),
+        },
+        Step {
+            at: 64_000,
+            event: Event::Wait,
+            expect: unlocked(9, 0),
+        },
+        Step {
+            at: 64_100,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ]);
+    run("max charge time", &steps);
+}
+
+// Without a maximum from the vehicle the remaining time is sent as unknown.
+#[test]
+fn no_max_charge_time() {
+    let steps = [
+        Step {
+            at: 0,
+            event: Event::MaxChargeTime(0),
+            expect: idle(),
+        },
+        Step {
+            at: 100,
+            event: Event::Key(b'c'),
+            expect: expect(
+                WaitChargeEnable,
+                true,
+                false,
+                false,
+                params108(),
+                status109(0, 0, STOPPED, (0xFF, 0xFF)),
+            ),
+        },
+    ];
+    run("no max charge time", &steps);
+}
+
+#[test]
+fn comms_loss() {
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 4_000,
+            event: Event::Silent,
+            expect: charging(361, 10, 90),
+        },
+        // Last frames at 3900, COMM_TIMEOUT_MS later the outputs drop.
+        Step {
+            at: 4_800,
+            event: Event::Wait,
+            expect: charging(361, 10, 89),
+        },
+        // Nothing more is sent, the output is discharged before the connector unlocks.
+        Step {
+            at: 4_900,
+            event: Event::Wait,
+            expect: expect(TimeOut, false, false, true, None, None),
+        },
+        Step {
+            at: 5_200,
+            event: Event::Wait,
+            expect: expect(TimeOut, false, false, true, None, None),
+        },
+        Step {
+            at: 5_300,
+            event: Event::Wait,
+            expect: expect(TimeOut, false, false, false, None, None),
+        },
+        Step {
+            at: 6_800,
+            event: Event::Wait,
+            expect: expect(TimeOut, false, false, false, None, None),
+        },
+        Step {
+            at: 6_900,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ]);
+    run("comms loss", &steps);
+}
+
+// 'c' during a session leaves it alone, and says why.
+#[test]
+fn start_during_session() {
+    let mut session = run("start during session", &into_charge_loop());
+    session.main_loop.serial_input(b'c', session.now);
+    let cd_state = &session.main_loop.cd_state;
+    assert_eq!(cd_state.charge_state, ChargeLoop);
+    assert_eq!(
+        cd_state.activity_list.back().map(|line| line.as_str()),
+        Some("3600 - Session in progress, not starting.")
+    );
+}
+
+// The refusal goes out once in 0x109, incompatible and stopped with d1 off, then the charger is
+// quiet.
+fn refused() -> Expect {
+    expect(
+        ChargeIdle,
+        false,
+        false,
+        false,
+        params108(),
+        status109(0, 0, STOPPED | INCOMPATIBLE, NO_TIME),
+    )
+}
+
+// Asking for more than the charger's rated voltage is refused at once: no lock, no insulation
+// test. The vehicle carries on sending 0x102, enabled, and nothing starts again.
+#[test]
+fn incompatible_vehicle() {
+    let steps = [
+        Step {
+            at: 0,
+            event: Event::Vehicle(|status| status.target_voltage = 450),
+            expect: idle(),
+        },
+        Step {
+            at: 100,
+            event: Event::Key(b'c'),
+            expect: refused(),
+        },
+        Step {
+            at: 200,
+            event: Event::Wait,
+            expect: idle(),
+        },
+        Step {
+            at: 500,
+            event: Event::Vehicle(enable),
+            expect: idle(),
+        },
+        Step {
+            at: 3_000,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ];
+    let session = run("incompatible", &steps);
+    assert!(session.main_loop.cd_state.incompatible);
+}
+
+// The insulation test fails at the end of its measurement, the output is discharged and the
+// session stopped with the error bit set.
+fn insulation_fails(event: Event) -> Vec<Step> {
+    let mut steps = into_charge_loop();
+    steps.truncate(2);
+    steps.extend(vec![
+        Step {
+            at: 600,
+            event,
+            expect: expect(
+                InsulationTest,
+                true,
+                false,
+                true,
+                params108(),
+                status109(410, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 1_500,
+            event: Event::Wait,
+            expect: discharging(410, ERROR),
+        },
+        Step {
+            at: 1_600,
+            event: Event::Wait,
+            expect: discharging(212, ERROR),
+        },
+        Step {
+            at: 1_800,
+            event: Event::Wait,
+            expect: discharging(12, ERROR),
+        },
+        Step {
+            at: 1_900,
+            event: Event::Wait,
+            expect: unlocked(9, ERROR),
+        },
+        Step {
+            at: 2_000,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ]);
+    steps
+}
+
+#[test]
+fn insulation_failure() {
+    // 41 kOhm needed at 410 V.
+    run("insulation failure", &insulation_fails(Event::Insulation(40_000)));
+}
+
+// A monitor that hasn't measured anything reads 0 Ohm, like the board's NoInsulationMonitor, and
+// the test fails without a test setting a resistance.
+#[test]
+fn insulation_not_measured() {
+    let mut session = Session::new();
+    session.io.insulation_monitor = SimulatedInsulation::default();
+    run_on(session, "insulation not measured", &insulation_fails(Event::Wait));
+}
+
+// With the insulation test off, as in the board firmware, d2 goes on as soon as the connector
+// locks and the output is never raised, whatever the monitor reads.
+#[test]
+fn insulation_test_off() {
+    let mut session = Session::new();
+    session.main_loop.cd_state.charger_config.insulation_test = false;
+    session.io.insulation_monitor = SimulatedInsulation::default();
+    let mut steps = into_charge_loop();
+    steps.truncate(1);
+    steps.extend(vec![
+        Step {
+            at: 500,
+            event: Event::Vehicle(enable),
+            expect: expect(
+                WaitVehicleChargeStart,
+                true,
+                true,
+                true,
+                params108(),
+                status109(0, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 3_500,
+            event: Event::Vehicle(start),
+            expect: charging(360, 2, 90),
+        },
+    ]);
+    let session = run_on(session, "insulation test off", &steps);
+    assert!(!session.main_loop.cd_state.insulation_fault);
+}
+
+// A vehicle frame from just before the clock wraps times out 1 s later, after the wrap.
+#[test]
+fn comm_timeout_across_clock_wrap() {
+    let mut cd_state = CDState::new();
+    let mut car_state = CarState::new();
+    cd_state.charge_state = ChargeLoop;
+    cd_state.comm_timeout = false;
+    cd_state.previous_can_ts = u32::MAX - 100;
+    comm_watchdog::init(200, &mut cd_state, &mut car_state);
+    assert_eq!(cd_state.charge_state, ChargeLoop);
+    comm_watchdog::init(1_000, &mut cd_state, &mut car_state);
+    assert_eq!(cd_state.charge_state, TimeOut);
+}
+
+// Once stopped, 0x109 says so without also saying charging, while the current falls off. A comm
+// timeout sets the error bit.
+#[test]
+fn evse_status_once_stopped() {
+    let mut cd_state = CDState::new();
+    let car_state = CarState::new();
+    cd_state.present_current = 10;
+    for state in [WeldCheck, StopCharge, Discharge].iter() {
+        cd_state.charge_state = *state;
+        let status = evse_status(&cd_state, &car_state);
+        assert!(!status.charging, "{:?}", state);
+        assert!(status.stopped, "{:?}", state);
+        assert!(!status.error, "{:?}", state);
+    }
+    cd_state.charge_state = TimeOut;
+    assert!(evse_status(&cd_state, &car_state).error);
+}
+
+// The longest activity line, a weld check given up with the contactors closed, at the highest
+// elapsed and voltage, still fits.
+#[test]
+fn weld_check_activity_fits() {
+    let mut cd_state = CDState::new();
+    let car_state = CarState {
+        contactor_open: false,
+        ..CarState::new()
+    };
+    let mut power_stage = SimulatedSupply::new(u16::MAX, 100);
+    power_stage.battery_connected = true;
+    cd_state.charge_state = WeldCheck;
+    weld_check::init(u32::MAX, &mut cd_state, &car_state, &mut power_stage);
+    assert_eq!(
+        cd_state.activity_list.back().map(|line| line.as_str()),
+        Some("4294967295 - Weld Contactors Closed, 65535 V -> StopCharge")
+    );
+}
+
+// No lock feedback: the session waits in WaitChargeEnable, then gives up with the error bit set.
+#[test]
+fn lock_never_engages() {
+    let steps = [
+        Step {
+            at: 0,
+            event: Event::LockStuck,
+            expect: idle(),
+        },
+        Step {
+            at: 100,
+            event: Event::Key(b'c'),
+            expect: expect(
+                WaitChargeEnable,
+                true,
+                false,
+                false,
+                params108(),
+                status109(0, 0, STOPPED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 500,
+            event: Event::Vehicle(enable),
+            expect: expect(
+                WaitChargeEnable,
+                true,
+                false,
+                true,
+                params108(),
+                status109(0, 0, STOPPED, MAX_TIME),
+            ),
+        },
+        // Never confirmed, so never shown locked.
+        Step {
+            at: 1_500,
+            event: Event::Wait,
+            expect: expect(
+                Discharge,
+                false,
+                false,
+                true,
+                params108(),
+                status109(0, 0, STOPPED | ERROR, NO_TIME),
+            ),
+        },
+        Step {
+            at: 1_600,
+            event: Event::Wait,
+            expect: unlocked(0, ERROR),
+        },
+        Step {
+            at: 1_700,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ];
+    let session = run("lock never engages", &steps);
+    assert!(session.main_loop.cd_state.lock_fault);
+}
+
+// The CAN flag alone doesn't start the session, j has to be on too.
+#[test]
+fn charge_permission_withheld() {
+    let waiting = |latch_enabled, flags| {
+        expect(
+            WaitChargeEnable,
+            true,
+            false,
+            latch_enabled,
+            params108(),
+            status109(0, 0, STOPPED | flags, MAX_TIME),
+        )
+    };
+    let steps = [
+        Step {
+            at: 0,
+            event: Event::Permission(false),
+            expect: idle(),
+        },
+        Step {
+            at: 100,
+            event: Event::Key(b'c'),
+            expect: waiting(false, 0),
+        },
+        Step {
+            at: 500,
+            event: Event::Vehicle(enable),
+            expect: waiting(false, 0),
+        },
+        Step {
+            at: 1_000,
+            event: Event::Permission(true),
+            expect: expect(
+                InsulationTest,
+                true,
+                false,
+                true,
+                params108(),
+                status109(410, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+    ];
+    run("charge permission withheld", &steps);
+}
+
+// j off is the vehicle stopping, through the weld check.
+#[test]
+fn charge_permission_withdrawn() {
+    let mut steps = into_charge_loop();
+    steps.push(Step {
+        at: 4_000,
+        event: Event::Permission(false),
+        expect: weld_check_at(360),
+    });
+    run("charge permission withdrawn", &steps);
+}
+
+#[test]
+fn connector_removed_while_charging() {
+    let mut steps = into_charge_loop();
+    steps.push(Step {
+        at: 4_000,
+        event: Event::Unplug,
+        expect: discharging(360, 0),
+    });
+    run("connector removed", &steps);
+}
+
+fn v2h_discharging(voltage: u16) -> Expect {
+    expect(
+        DischargeLoop,
+        true,
+        true,
+        true,
+        params108(),
+        status109(voltage, 0, CHARGING | LOCKED, NO_TIME),
+    )
+}
+
+// A vehicle offering 15 A of V2H, started with 'd', closes its contactors at 3500.
+fn into_discharge_loop() -> Vec<Step> {
+    vec![
+        Step {
+            at: 0,
+            event: Event::OfferDischarge(15),
+            expect: idle(),
+        },
+        Step {
+            at: 100,
+            event: Event::Key(b'd'),
+            expect: expect(
+                WaitChargeEnable,
+                true,
+                false,
+                false,
+                params108(),
+                status109(0, 0, STOPPED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 500,
+            event: Event::Vehicle(enable),
+            expect: expect(
+                InsulationTest,
+                true,
+                false,
+                true,
+                params108(),
+                status109(410, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 2_000,
+            event: Event::Wait,
+            expect: expect(
+                WaitVehicleChargeStart,
+                true,
+                true,
+                true,
+                params108(),
+                status109(19, 0, STOPPED | LOCKED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 3_500,
+            event: Event::Vehicle(|status| status.status.contactor_open = false),
+            expect: v2h_discharging(359),
+        },
+        Step {
+            at: 5_000,
+            event: Event::Wait,
+            expect: v2h_discharging(358),
+        },
+    ]
+}
+
+// 'd' starts a V2H session. Same set up as a charge, then closed contactors start the discharge,
+// which ramps at 20 A/s to the vehicle's 15 A (the charger allows 20 A) and pulls the battery
+// down through its resistance. The 0x109 current stays at 0, the discharge current is in 0x208.
+#[test]
+fn v2h_discharge() {
+    let steps = into_discharge_loop();
+    let session = run("v2h discharge", &steps);
+    let discharge208 = EvseDischarge208 {
+        present_discharge_current: 15,
+        available_input_voltage: 430,
+        available_input_current: 20,
+        lower_threshold_voltage: 250,
+    };
+    assert_eq!(session.sent(EVSE_DISCHARGE_ID), Some(discharge208.encode()));
+    assert_eq!(session.main_loop.cd_state.present_current, 0);
+
+    // The vehicle's minimum SoC ends it through the weld check, like a charge.
+    let steps = [Step {
+        at: 6_000,
+        event: Event::Vehicle(|status| status.state_of_charge = 30),
+        expect: weld_check_at(360),
+    }];
+    let session = run_on(session, "v2h minimum soc", &steps);
+    assert_eq!(session.main_loop.cd_state.v2h_current, 0);
+    assert_eq!(session.main_loop.cd_state.session_summary.max_current, 15);
+}
+
+// A malfunction and the charge enable dropped in the same frame stop a discharge through
+// StopCharge, skipping the weld check.
+#[test]
+fn v2h_malfunction_and_disable() {
+    let mut steps = into_discharge_loop();
+    steps.push(Step {
+        at: 6_000,
+        event: Event::Vehicle(|status| {
+            malfunction(status);
+            disable(status);
+        }),
+        expect: discharging(259, BATTERY_ERROR),
+    });
+    let session = run("v2h malfunction and disable", &steps);
+    let activity: Vec<&str> = session
+        .main_loop
+        .cd_state
+        .activity_list
+        .iter()
+        .map(|line| line.as_str())
+        .collect();
+    assert!(activity.contains(&"6000 - DisLp -> StopCharge (Malfnctn)"));
+    assert!(!activity.iter().any(|line| line.contains("Chg Disbld")));
+}
+
+// A fault line glitch shorter than the debounce is ignored.
+#[test]
+fn fault_line_glitch() {
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 4_000,
+            event: Event::FaultGlitch(FAULT_DEBOUNCE_MS - 1),
+            expect: charging(361, 10, 90),
+        },
+        Step {
+            at: 4_100,
+            event: Event::Wait,
+            expect: charging(361, 10, 90),
+        },
+    ]);
+    let session = run("fault line glitch", &steps);
+    assert!(!session.main_loop.cd_state.fault_line);
+}
+
+// A held fault stops the charge, with the error bit set and d1 / d2 released.
+#[test]
+fn fault_line_held() {
+    let mut steps = into_charge_loop();
+    steps.push(Step {
+        at: 4_000,
+        event: Event::Fault(true),
+        expect: discharging(267, ERROR),
+    });
+    let session = run("fault line held", &steps);
+    assert!(session.main_loop.cd_state.fault_line);
+    assert!(!session.io.d1.closed);
+    assert!(!session.io.d2.closed);
+}
+
+// A fault during the weld check stops it there, the result stays not run.
+#[test]
+fn fault_line_during_weld_check() {
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 4_000,
+            event: Event::Vehicle(finish),
+            expect: weld_check_at(360),
+        },
+        Step {
+            at: 4_100,
+            event: Event::Fault(true),
+            expect: discharging(266, ERROR),
+        },
+    ]);
+    let session = run("fault line during weld check", &steps);
+    assert!(session
+        .main_loop
+        .cd_state
+        .activity_list
+        .iter()
+        .any(|line| line.as_str() == "4105 - Fault line -> StopCharge"));
+    assert_eq!(
+        session.main_loop.cd_state.session_summary.weld_check,
+        WeldCheckEnum::NotRun
+    );
+    assert!(!session.io.d1.closed);
+    assert!(!session.io.d2.closed);
+}
+
+// A vehicle without 0x200 is refused a V2H session.
+#[test]
+fn v2h_vehicle_not_capable() {
+    let steps = [
+        Step {
+            at: 0,
+            event: Event::Key(b'd'),
+            expect: expect(
+                WaitChargeEnable,
+                true,
+                false,
+                false,
+                params108(),
+                status109(0, 0, STOPPED, MAX_TIME),
+            ),
+        },
+        Step {
+            at: 500,
+            event: Event::Vehicle(enable),
+            expect: refused(),
+        },
+        Step {
+            at: 600,
+            event: Event::Wait,
+            expect: idle(),
+        },
+    ];
+    run("v2h vehicle not capable", &steps);
+}
+
+// The 0x109 frames from another test, at a different protocol number.
+fn at_protocol(mut steps: Vec<Step>, protocol_number: u8) -> Vec<Step> {
+    for step in steps.iter_mut() {
+        if let Some(frame) = step.expect.tx109.as_mut() {
+            frame[0] = protocol_number;
+        }
+    }
+    steps
+}
+
+fn with_available_current(current: u8) -> Option<[u8; FRAME_LENGTH]> {
+    params108().map(|mut frame| {
+        frame[3] = current;
+        frame
+    })
+}
+
+// A 2.0 vehicle with dynamic and high current control. The 0x110 request (20 A) is followed
+// rather than the 10 A in 0x102, and lowering the available current from the console reaches
+// the vehicle in 0x108 / 0x118 and the output at once.
+#[test]
+fn dynamic_control() {
+    let mut session = Session::new();
+    session.vehicle.status.protocol_number = PROTOCOL_2_0;
+    session.vehicle.extended = Some(VehicleExtended110 {
+        functions: ExtendedFunctions {
+            dynamic_control: true,
+            high_current_control: true,
+        },
+        current_request: 20,
+    });
+    let mut steps = at_protocol(into_charge_loop(), PROTOCOL_2_0);
+    let charging_at = |voltage, current, available| {
+        let mut expect = charging(voltage, current, 89);
+        expect.tx108 = with_available_current(available);
+        if let Some(frame) = expect.tx109.as_mut() {
+            frame[0] = PROTOCOL_2_0;
+        }
+        expect
+    };
+    steps.extend(vec![
+        Step {
+            at: 5_000,
+            event: Event::Wait,
+            expect: charging_at(362, 20, 32),
+        },
+        Step {
+            at: 5_100,
+            event: Event::Key(b'a'),
+            expect: charging_at(362, 20, 27),
+        },
+        Step {
+            at: 5_200,
+            event: Event::Key(b'a'),
+            expect: charging_at(362, 20, 22),
+        },
+        Step {
+            at: 5_300,
+            event: Event::Key(b'a'),
+            expect: charging_at(361, 17, 17),
+        },
+    ]);
+    let session = run_on(session, "dynamic control", &steps);
+    assert_eq!(session.main_loop.cd_state.protocol_number, PROTOCOL_2_0);
+    assert!(session.main_loop.cd_state.dynamic_control);
+    assert!(session.main_loop.cd_state.high_current_control);
+    let extended118 = EvseExtended118 {
+        functions: ExtendedFunctions {
+            dynamic_control: true,
+            high_current_control: true,
+        },
+        available_current: 17,
+        present_current: 17,
+    };
+    assert_eq!(session.sent(EVSE_EXTENDED_ID), Some(extended118.encode()));
+}
+
+// A 0.9 vehicle, even one sending 0x110, gets the basic sequence: 0x102's current request, and
+// 0x108 stays as it was when the connector locked.
+#[test]
+fn protocol_fallback() {
+    let mut session = Session::new();
+    session.vehicle.extended = Some(VehicleExtended110 {
+        functions: ExtendedFunctions {
+            dynamic_control: true,
+            high_current_control: true,
+        },
+        current_request: 20,
+    });
+    let mut steps = into_charge_loop();
+    steps.extend(vec![
+        Step {
+            at: 5_000,
+            event: Event::Wait,
+            expect: charging(361, 10, 89),
+        },
+        Step {
+            at: 5_100,
+            event: Event::Key(b'a'),
+            expect: charging(361, 10, 89),
+        },
+    ]);
+    let session = run_on(session, "protocol fallback", &steps);
+    assert_eq!(session.main_loop.cd_state.protocol_number, PROTOCOL_0_9);
+    assert!(!session.main_loop.cd_state.dynamic_control);
+    assert!(!session.main_loop.cd_state.high_current_control);
+    assert_eq!(session.main_loop.cd_state.available_current, 32);
+}
+
+// A vehicle sending protocol number 0 gets 0.9 in 0x109, not 0.
+#[test]
+fn protocol_floor() {
+    let mut session = Session::new();
+    session.vehicle.status.protocol_number = 0;
+    let session = run_on(session, "protocol floor", &into_charge_loop());
+    assert_eq!(session.main_loop.cd_state.protocol_number, PROTOCOL_0_9);
+}
+
+fn gbt_session() -> Session {
+    let mut session = Session::new();
+    session.main_loop.cd_state.charger_config.protocol = ProtocolEnum::Gbt;
+    session.bms = Some(Bms::new());
+    session
+}
+
+fn gbt_expect(
+    state: ChargeStateEnum,
+    switch_one: bool,
+    switch_two: bool,
+    latch_enabled: bool,
+) -> Expect {
+    expect(state, switch_one, switch_two, latch_enabled, None, None)
+}
+
+// GB/T through to charging at the BMS's 20 A request, then the BMS stops. Handshake and the
+// insulation test as for CHAdeMO, recognition, parameters (over the transport protocol) and
+// ready, CCS while charging, then CST / CSD answering the BMS's BST / BSD. No CHAdeMO frames.
+#[test]
+fn gbt_normal_session() {
+    let steps = [
+        Step {
+            at: 0,
+            event: Event::Key(b'c'),
+            expect: gbt_expect(WaitForComms, true, false, false),
+        },
+        Step {
+            at: 400,
+            event: Event::Wait,
+            expect: gbt_expect(InsulationTest, true, false, true),
+        },
+        Step {
+            at: 1_900,
+            event: Event::Wait,
+            expect: gbt_expect(WaitVehicleChargeStart, true, true, true),
+        },
+        Step {
+            at: 2_800,
+            event: Event::Wait,
+            expect: gbt_expect(ChargeLoop, true, true, true),
+        },
+        Step {
+            at: 5_000,
+            event: Event::Wait,
+            expect: gbt_expect(ChargeLoop, true, true, true),
+        },
+    ];
+    let session = run_on(gbt_session(), "gbt normal session", &steps);
+    let bms = session.bms.as_ref().unwrap();
+    let handshake = gbt::ChargerHandshake {
+        protocol_version: gbt::PROTOCOL_VERSION,
+    };
+    assert_eq!(bms.received(gbt::CHM_PGN), Some(handshake.encode()));
+    let recognition = gbt::ChargerRecognition {
+        recognized: true,
+        charger_number: 1,
+    };
+    assert_eq!(bms.received(gbt::CRM_PGN), Some(recognition.encode()));
+    let limits = gbt::ChargerLimits {
+        max_voltage: 4300,
+        min_voltage: 0,
+        max_current: 320,
+        min_current: 0,
+    };
+    assert_eq!(bms.received(gbt::CML_PGN), Some(limits.encode()));
+    assert_eq!(
+        bms.received(gbt::CRO_PGN),
+        Some(gbt::Ready { ready: true }.encode())
+    );
+    // The last transfer, BCS (9 bytes in 2 packets), acknowledged.
+    assert_eq!(
+        bms.received(gbt::TP_CM_PGN),
+        Some([0x13, 0x09, 0x00, 0x02, 0xFF, 0x00, 0x11, 0x00])
+    );
+    let status = gbt::ChargerStatus {
+        output_voltage: 3620,
+        output_current: 200,
+        charge_time: 0,
+        permitted: true,
+    };
+    assert_eq!(bms.received(gbt::CCS_PGN), Some(status.encode()));
+    assert_eq!(session.main_loop.car_state.voltage_target, TARGET_VOLTAGE);
+    assert_eq!(session.main_loop.car_state.extended_current_request, 20);
+    assert_eq!(session.sent(EVSE_STATUS_ID), None);
+
+    let steps = [
+        Step {
+            at: 5_100,
+            event: Event::Bms(|bms| bms.stop = true),
+            expect: gbt_expect(WeldCheck, true, true, true),
+        },
+        Step {
+            at: 5_400,
+            event: Event::Wait,
+            expect: gbt_expect(Discharge, false, false, true),
+        },
+        Step {
+            at: 5_500,
+            event: Event::Wait,
+            expect: gbt_expect(ChargeIdle, false, false, false),
+        },
+    ];
+    let session = run_on(session, "gbt normal session", &steps);
+    let bms = session.bms.as_ref().unwrap();
+    let stop = gbt::Stop {
+        reason: gbt::CST_BMS_STOPPED,
+        faults: 0,
+        errors: 0,
+    };
+    assert_eq!(bms.received(gbt::CST_PGN), Some(stop.encode()));
+    let statistics = gbt::ChargerStatistics {
+        charge_time: 0,
+        energy: 0,
+        charger_number: 1,
+    };
+    assert_eq!(bms.received(gbt::CSD_PGN), Some(statistics.encode()));
+    assert_eq!(
+        session.main_loop.cd_state.session_summary.weld_check,
+        WeldCheckEnum::Passed
+    );
+}
+
+// A BSM fault aborts the session from the protocol side, straight to StopCharge and on to
+// Discharge, without waiting for the BMS to stop.
+#[test]
+fn gbt_bms_fault() {
+    let steps = [
+        Step {
+            at: 0,
+            event: Event::Key(b'c'),
+            expect: gbt_expect(WaitForComms, true, false, false),
+        },
+        Step {
+            at: 4_000,
+            event: Event::Wait,
+            expect: gbt_expect(ChargeLoop, true, true, true),
+        },
+        Step {
+            at: 4_100,
+            event: Event::Bms(|bms| bms.fault = true),
+            expect: gbt_expect(Discharge, false, false, true),
+        },
+    ];
+    let session = run_on(gbt_session(), "gbt bms fault", &steps);
+    let bms = session.bms.as_ref().unwrap();
+    assert_eq!(
+        bms.received(gbt::CCS_PGN).map(|data| data[6] & 0x03),
+        Some(0x01)
+    );
+    assert!(session.main_loop.car_state.malfunction);
+}
+
+// Frames from the receive interrupts keep the time they arrived, and frames the queue had no
+// room for are counted along with FIFO overruns.
+#[test]
+fn queued_can_reception() {
+    let queue = Box::leak(Box::new(RxQueue::new()));
+    let counters = Box::leak(Box::new(RxCounters::new()));
+    let (mut writer, mut reader) = split(queue, counters);
+    let mut fc_can = reader.bus(MockCan::new());
+    let mut cd_state = CDState::new();
+    cd_state.charger_config.protocol = ProtocolEnum::Chademo;
+    let mut car_state = CarState::new();
+
+    let frames = Vehicle::new().frames();
+    for frame in frames.iter() {
+        writer.push(*frame, 1_040);
+    }
+    can_receive_logic(&mut fc_can, 1_050, &mut cd_state, &mut car_state);
+    assert_eq!(cd_state.previous_can_ts, 1_040);
+    assert_eq!(cd_state.can_rx, CanRxCounters::default());
+
+    // Stamped after this pass read the clock.
+    writer.push(frames[0], 1_061);
+    can_receive_logic(&mut fc_can, 1_060, &mut cd_state, &mut car_state);
+    assert_eq!(cd_state.previous_can_ts, 1_060);
+
+    // 32 frames fit in the queue.
+    for _ in 0..40 {
+        writer.push(frames[0], 1_070);
+    }
+    writer.overrun();
+    can_receive_logic(&mut fc_can, 1_080, &mut cd_state, &mut car_state);
+    assert_eq!(
+        cd_state.can_rx,
+        CanRxCounters {
+            overruns: 1,
+            dropped: 8,
+        }
+    );
+    assert_eq!(
+        cd_state.activity_list.back().map(|line| line.as_str()),
+        Some("1080 - CAN rx lost, ovr 1 drop 8")
+    );
+}
+
+// A console write that doesn't fit in the serial transmit buffer is dropped whole and counted,
+// what fit goes out in order.
+#[test]
+fn buffered_console_output() {
+    let queue = Box::leak(Box::new(TxQueue::new()));
+    let (mut writer, mut reader) = serial_tx_queue::split(queue);
+
+    // 29 lines of 70 bytes fit in 2048, the 30th doesn't and none of it is queued.
+    for line in 0..30 {
+        writer
+            .write_str(&format!("{:02} {:66}\n", line, ""))
+            .unwrap();
+    }
+    assert_eq!(writer.dropped, 1);
+
+    // A line formatted in pieces that would fit on their own, but not together, is dropped whole.
+    writeln!(writer, "{}{}", "=".repeat(10), "=".repeat(10)).unwrap();
+    assert_eq!(writer.dropped, 2);
+
+    // The 18 bytes left still take a write that fits.
+    writer.write_str(&"-".repeat(18)).unwrap();
+    assert_eq!(writer.dropped, 2);
+
+    let sent: Vec<u8> = std::iter::from_fn(|| reader.dequeue()).collect();
+    assert_eq!(sent.len(), 2048);
+    assert_eq!(&sent[..3], b"00 ");
+    assert_eq!(&sent[70..73], b"01 ");
+    assert_eq!(&sent[28 * 70..28 * 70 + 3], b"28 ");
+    assert_eq!(sent[29 * 70], b'-');
+
+    // Once sent, there's room again.
+    writer.write_str("Uptime").unwrap();
+    assert_eq!(writer.dropped, 2);
+    assert_eq!(reader.dequeue(), Some(b'U'));
+}
+
+// The verbose console as a terminal would show it: cursor moves (ESC [ row ; col H), clears
+// (ESC [ 2 J, ESC [ K to the end of the row) and newlines, on an 80 x 24 screen. Writing past
+// the last column or a newline on the last row (a scroll) fails the test.
+fn screen(output: &str) -> Vec<String> {
+    let mut rows = vec![vec![' '; 80]; 24];
+    let (mut row, mut col) = (0, 0);
+    let mut chars = output.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1B' => {
+                let mut params = String::new();
+                chars.next();
+                for c in chars.by_ref() {
+                    match c {
+                        'H' => {
+                            let mut position = params.split(';').map(|n| n.parse().unwrap_or(1));
+                            row = position.next().unwrap_or(1) - 1;
+                            col = position.next().unwrap_or(1) - 1;
+                            break;
+                        }
+                        'J' => {
+                            rows = vec![vec![' '; 80]; 24];
+                            break;
+                        }
+                        'K' => {
+                            for c in rows[row][col..].iter_mut() {
+                                *c = ' ';
+                            }
+                            break;
+                        }
+                        _ => params.push(c),
+                    }
+                }
+            }
+            '\n' => {
+                assert!(row < 23, "newline on the last row scrolls the screen");
+                row += 1;
+                col = 0;
+            }
+            _ => {
+                assert!(col < 80, "row {} runs past 80 columns", row + 1);
+                rows[row][col] = c;
+                col += 1;
+            }
+        }
+    }
+    rows.iter().map(|row| row.iter().collect()).collect()
+}
+
+// The full verbose screen, header and status lines, with every field at its widest: nothing
+// overwrites anything else or leaves the screen.
+#[test]
+fn verbose_console_layout() {
+    let mut cd_state = CDState::new();
+    cd_state.verbose_stats = true;
+    for _ in 0..8 {
+        let mut entry: heapless::String<heapless::consts::U60> = heapless::String::new();
+        entry.push_str(&"x".repeat(60)).unwrap();
+        cd_state.activity_list.push_back(entry);
+    }
+    cd_state.can_rx = CanRxCounters {
+        overruns: u32::MAX,
+        dropped: u32::MAX,
+    };
+    cd_state.console_dropped = u32::MAX;
+    cd_state.session_summary.charge_time = u32::MAX;
+    cd_state.session_summary.max_current = u16::MAX;
+    cd_state.session_summary.max_voltage = u16::MAX;
+    cd_state.session_summary.weld_check = WeldCheckEnum::ContactorsClosed;
+    cd_state.current_voltage = u16::MAX;
+    cd_state.present_current = u16::MAX;
+    cd_state.available_current = u16::MAX;
+    cd_state.dynamic_control = true;
+    cd_state.v2h_current = u8::MAX;
+    cd_state.insulation_resistance = u32::MAX;
+    cd_state.protocol_number = u8::MAX;
+    cd_state.charge_state = WaitVehicleChargeStart;
+    cd_state.lock_fault = true;
+    let mut car_state = CarState::new();
+    car_state.voltage_target = u16::MAX;
+    car_state.current_target = u8::MAX;
+    car_state.battery_pack_size = 25.5;
+    car_state.state_of_charge = u8::MAX;
+    car_state.protocol_number = u8::MAX;
+    let mut tx = MockSink::new();
+
+    // The header is drawn on the first 100 ms, along with everything else.
+    serial_console::display(&mut tx, &mut cd_state, &mut car_state, u32::MAX, 0);
+    let rows = screen(&tx.output);
+    assert!(rows[0].starts_with("Commands:"));
+    assert!(rows[3].starts_with("m - Show menu"));
+    assert_eq!(rows[5].trim(), "Activity");
+    for row in [6, 15].iter() {
+        assert_eq!(rows[*row].trim_end(), format!("+{}+", "-".repeat(62)));
+    }
+    for row in rows[7..15].iter() {
+        assert_eq!(row.trim_end(), format!("| {} |", "x".repeat(60)));
+    }
+    assert_eq!(
+        rows[16].trim_end(),
+        "Last session: 4294967295 s, 65535 A max, 65535 V max, Weld: Contactors Closed"
+    );
+    assert_eq!(
+        rows[17].trim_end(),
+        "CAN rx overruns: 4294967295  dropped: 4294967295  Console dropped: 4294967295"
+    );
+    assert_eq!(rows[18].trim_end(), "Charger State:");
+    assert!(rows[19].starts_with("d1: N  d2: N  j: N  k: N"));
+    assert!(rows[19].contains("SoC: 255%"));
+    assert!(rows[20].starts_with("Out V: 65535  Out A: 65535 / 65535 (dyn)"));
+    assert!(rows[20].contains("Iso: 4294967 kOhm"));
+    assert_eq!(rows[21].trim_end(), "Car State:");
+    assert!(rows[22].starts_with("Tgt V: 65535, Tgt A: 255"));
+    assert!(rows[22].trim_end().ends_with("Pack: 25.5"));
+    assert!(rows[23].starts_with("Uptime: 4294967295"));
+    assert!(rows[23].contains("State: Wait for Vehicle Charge Start"));
+    assert!(rows[23].contains("Fault: Y"));
+}
+
+// An RTS whose packet count doesn't match its size is refused, and TP.DT frames after it are
+// ignored instead of being copied past the message.
+#[test]
+fn gbt_transport_packet_count_mismatch() {
+    let mut transport = TransportReceiver::new();
+    transport.receive_cm(&[TP_RTS, 10, 0, 3, 0xFF, 0x00, 0x11, 0x00]);
+    assert_eq!(transport.reply.take().map(|reply| reply[0]), Some(TP_ABORT));
+    for packet in 1..=3 {
+        assert_eq!(transport.receive_dt(&[packet, 1, 2, 3, 4, 5, 6, 7]), None);
+    }
+
+    // The same message with the right count goes through.
+    transport.receive_cm(&[TP_RTS, 10, 0, 2, 0xFF, 0x00, 0x11, 0x00]);
+    assert_eq!(transport.reply.take().map(|reply| reply[0]), Some(TP_CTS));
+    assert_eq!(transport.receive_dt(&[1, 1, 2, 3, 4, 5, 6, 7]), None);
+    assert_eq!(
+        transport.receive_dt(&[2, 8, 9, 10, 0xFF, 0xFF, 0xFF, 0xFF]),
+        Some(0x1100)
+    );
+    assert_eq!(transport.message(), &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+}
+
+// The vehicle module (ev-sim, can-dc-ev) against scripted charger frames: 0x108 and this 0x109
+// every 100 ms from `from` to `to`, each followed by the vehicle's 100 ms loop.
+fn charger_frames(
+    ev: &mut VehicleState,
+    fc_can: &mut MockCan,
+    from: u32,
+    to: u32,
+    tx109: Option<[u8; FRAME_LENGTH]>,
+) {
+    for at in (from..to).step_by(TICK_MS as usize) {
+        fc_can.clear_sent();
+        vehicle::init(at, ev, EVSE_PARAMS_ID, &params108().unwrap());
+        vehicle::init(at, ev, EVSE_STATUS_ID, &tx109.unwrap());
+        vehicle::hundred_ms_loop(at, ev, fc_can);
+    }
+}
+
+fn last_status102(fc_can: &MockCan) -> VehicleStatus102 {
+    VehicleStatus102::decode(fc_can.last_sent(VEHICLE_STATUS_ID).unwrap().data())
+}
+
+// Plugged in and locked, the vehicle waits out the insulation test. d2 comes on 1.4 s after the
+// lock, the vehicle closes its contactors 4 s after the lock and asks for 10 A.
+fn vehicle_locked(config: VehicleConfig) -> (VehicleState, MockCan) {
+    let mut ev = VehicleState::new(config);
+    let mut fc_can = MockCan::new();
+    vehicle::start(&mut ev, 0);
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        100,
+        600,
+        status109(0, 0, STOPPED, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::WaitLock);
+    assert!(ev.charge_permission);
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        600,
+        2_000,
+        status109(19, 0, STOPPED | LOCKED, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::WaitInsulation);
+    (ev, fc_can)
+}
+
+fn vehicle_charging(config: VehicleConfig) -> (VehicleState, MockCan) {
+    let (mut ev, mut fc_can) = vehicle_locked(config);
+    ev.d2 = true;
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        2_000,
+        4_700,
+        status109(19, 0, STOPPED | LOCKED, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Charging);
+    assert!(!ev.contactor_open);
+    assert_eq!(ev.current_request, 10);
+    (ev, fc_can)
+}
+
+fn vehicle_activity(ev: &VehicleState) -> Vec<&str> {
+    ev.activity_list.iter().map(|line| line.as_str()).collect()
+}
+
+// A charger voltage more than 10 V away from the vehicle's own battery voltage for over a second
+// faults the vehicle, which drops j and reports the deviation with the malfunction in 0x102.
+#[test]
+fn vehicle_voltage_deviation() {
+    let config = VehicleConfig {
+        battery_voltage: BATTERY_VOLTAGE,
+        ..VehicleConfig::new()
+    };
+    let (mut ev, mut fc_can) = vehicle_charging(config);
+
+    // 10 V off is still within limits.
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        4_700,
+        6_000,
+        status109(370, 10, CHARGING, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Charging);
+
+    // 0x108 at 6000 runs the checks on the last 0x109 (370 V), the 0x109 after it is the first
+    // one off.
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        6_000,
+        7_100,
+        status109(380, 10, CHARGING, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Charging);
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        7_100,
+        7_200,
+        status109(380, 10, CHARGING, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Fault);
+    assert!(!ev.charge_permission);
+    assert!(ev.contactor_open);
+    assert_eq!(
+        vehicle_activity(&ev).last(),
+        Some(&"7100 - Fault (Volt Dev)")
+    );
+
+    let status = last_status102(&fc_can);
+    assert!(status.faults.voltage_deviation);
+    assert!(status.status.malfunction);
+    assert!(!status.status.charging_enabled);
+    assert_eq!(status.current_request, 0);
+
+    // Without a battery voltage of its own the vehicle doesn't check.
+    let (mut ev, mut fc_can) = vehicle_charging(VehicleConfig::new());
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        4_700,
+        7_100,
+        status109(380, 10, CHARGING, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Charging);
+}
+
+// A charger that flags the vehicle incompatible, or can't reach its target voltage, is refused
+// before the vehicle raises j.
+#[test]
+fn vehicle_refuses_incompatible_charger() {
+    let mut ev = VehicleState::new(VehicleConfig::new());
+    let mut fc_can = MockCan::new();
+    vehicle::start(&mut ev, 0);
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        100,
+        200,
+        status109(0, 0, INCOMPATIBLE, NO_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Fault);
+    assert!(!ev.charge_permission);
+    assert_eq!(
+        vehicle_activity(&ev).last(),
+        Some(&"100 - Fault (Incompatible)")
+    );
+    assert!(last_status102(&fc_can).status.malfunction);
+
+    // 0x108 offers 430 V.
+    let config = VehicleConfig {
+        target_voltage: 440,
+        max_battery_voltage: 450,
+        ..VehicleConfig::new()
+    };
+    let mut ev = VehicleState::new(config);
+    vehicle::start(&mut ev, 0);
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        100,
+        200,
+        status109(0, 0, STOPPED, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Fault);
+    assert!(!ev.charge_permission);
+    assert_eq!(
+        vehicle_activity(&ev).last(),
+        Some(&"100 - Fault (Incompatible)")
+    );
+}
+
+// A vehicle stop drops j and the charge enable at once, the contactors stay closed until the
+// charger is at 0 A, and the vehicle is done once the connector unlocks.
+#[test]
+fn vehicle_stop_drops_permission() {
+    let config = VehicleConfig {
+        charge_time: 2_000,
+        ..VehicleConfig::new()
+    };
+    let (mut ev, mut fc_can) = vehicle_charging(config);
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        4_700,
+        6_700,
+        status109(360, 10, CHARGING | LOCKED, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Stopping);
+    assert!(!ev.charge_permission);
+    assert!(!ev.contactor_open);
+    let status = last_status102(&fc_can);
+    assert!(!status.status.charging_enabled);
+    assert!(!status.status.contactor_open);
+    assert_eq!(status.current_request, 0);
+
+    // Still ramping down.
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        6_700,
+        6_800,
+        status109(360, 4, CHARGING | LOCKED, MAX_TIME),
+    );
+    assert!(!ev.contactor_open);
+
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        6_800,
+        6_900,
+        status109(360, 0, STOPPED | LOCKED, NO_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Stopping);
+    assert!(ev.contactor_open);
+    assert!(last_status102(&fc_can).status.contactor_open);
+
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        6_900,
+        7_000,
+        status109(9, 0, STOPPED, NO_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Stopped);
+    assert!(!ev.malfunction);
+}
+
+// Without d2 the contactors stay open past the contactor delay, they close on the first 100 ms
+// after the charger sets it.
+#[test]
+fn vehicle_waits_for_d2() {
+    let (mut ev, mut fc_can) = vehicle_locked(VehicleConfig::new());
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        2_000,
+        8_000,
+        status109(19, 0, STOPPED | LOCKED, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::WaitInsulation);
+    assert!(ev.contactor_open);
+    assert!(last_status102(&fc_can).status.contactor_open);
+    assert_eq!(ev.current_request, 0);
+
+    ev.d2 = true;
+    charger_frames(
+        &mut ev,
+        &mut fc_can,
+        8_000,
+        8_100,
+        status109(19, 0, STOPPED | LOCKED, MAX_TIME),
+    );
+    assert!(ev.state == VehicleStateEnum::Charging);
+    assert!(!ev.contactor_open);
+    assert!(!last_status102(&fc_can).status.contactor_open);
+    assert_eq!(ev.current_request, 10);
+}